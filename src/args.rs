@@ -1,4 +1,109 @@
+use crate::proxy::ProxyConfig;
+use std::collections::HashMap;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// What to do when a pre-send check finds a likely mistake.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum CheckPolicy {
+    /// Send without complaint.
+    #[default]
+    Allow,
+    /// Print a warning and send anyway.
+    Warn,
+    /// Refuse to send.
+    Error,
+}
+
+/// What to do with an invalid address in a list of BCC addresses.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum InvalidBccPolicy {
+    /// Refuse to send.
+    #[default]
+    Abort,
+    /// Print a warning and send to the valid addresses.
+    Skip,
+}
+
+/// How mail clients are told to show the QR code image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum ImageDisposition {
+    /// Show the image in the body.
+    Inline,
+    /// Offer the image as a file to download.
+    Attachment,
+}
+
+/// Which way the text in the email body reads.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum Direction {
+    /// Left to right, as in English.
+    #[default]
+    Ltr,
+    /// Right to left, as in Arabic or Hebrew.
+    Rtl,
+}
+
+/// A character set the subject can be encoded in.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum Charset {
+    /// UTF-8, which can encode any character.
+    #[default]
+    Utf8,
+    /// ISO-8859-1 (Latin-1), for older mail clients which can't show UTF-8.
+    Latin1,
+}
+
+/// Who an email is being sent to.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RecipientRole {
+    /// The person the eSIM is for.
+    #[default]
+    Primary,
+    /// A parent or guardian receiving their own copy.
+    Guardian,
+}
+
+/// A version of TLS, used to refuse connections with older versions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum TlsVersion {
+    /// TLS 1.0, only for servers which haven't been updated in a long time.
+    Tls10,
+    /// TLS 1.1, only for servers which haven't been updated in a long time.
+    Tls11,
+    /// TLS 1.2.
+    Tls12,
+    /// TLS 1.3. Not every TLS backend can require it.
+    Tls13,
+}
+
+/// The location used in place of an empty one.
+pub const DEFAULT_LOCATION_FALLBACK: &str = "your destination";
+
+/// The note shown to a guardian when no other note is set.
+pub const DEFAULT_GUARDIAN_NOTE: &str = "You're receiving a copy of this eSIM as a parent or \
+guardian. It can only be installed once, so please help set it up on the right device.";
+
+/// Splits a combined `Name <email>` string into the display name and the
+/// address. Anything else is treated as a bare address.
+pub fn split_name_and_address(value: &str) -> (Option<&str>, &str) {
+    let value = value.trim();
+    if let Some((name, address)) = value
+        .strip_suffix('>')
+        .and_then(|rest| rest.rsplit_once('<'))
+    {
+        let address = address.trim();
+        if address.contains('@') && !address.contains('>') && !name.contains(['<', '>']) {
+            let name = name.trim().trim_matches('"').trim();
+            return ((!name.is_empty()).then_some(name), address);
+        }
+    }
+    (None, value)
+}
+
 #[derive(Debug, Default, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
 pub struct Args {
     /// Email address of the sender
     pub email_from: String,
@@ -6,9 +111,28 @@ pub struct Args {
     /// Email address of the recipient
     pub email_to: String,
 
-    /// BCC email address (optional)
+    /// CC email address (optional)
+    pub cc: Option<String>,
+
+    /// Address replies should go to instead of the sender (optional)
+    pub reply_to: Option<String>,
+
+    /// Also CC the reply-to address, unless it's already a recipient
+    pub cc_reply_to: bool,
+
+    /// BCC email addresses, separated by commas (optional)
     pub bcc: Option<String>,
 
+    /// File of addresses to BCC on every email, one per line (optional)
+    pub bcc_group_file: Option<PathBuf>,
+
+    /// Most BCC recipients per send, for providers which limit recipients per message. Longer BCC
+    /// lists are sent the same message in several sends (optional)
+    pub bcc_chunk_size: Option<usize>,
+
+    /// What to do when one of the BCC addresses is invalid
+    pub invalid_bcc: InvalidBccPolicy,
+
     /// Provider name
     pub provider: String,
 
@@ -23,4 +147,353 @@ pub struct Args {
 
     /// Location
     pub location: String,
+
+    /// Text used when the location is empty (optional, defaults to "your destination")
+    pub location_fallback: Option<String>,
+
+    /// MIME type of the QR code image (optional, defaults to image/png)
+    pub image_content_type: Option<String>,
+
+    /// Width of the QR code image in the body, in pixels (optional, defaults to 200)
+    pub qr_width: Option<u32>,
+
+    /// Height of the QR code image in the body, in pixels (optional, defaults to the width)
+    pub qr_height: Option<u32>,
+
+    /// eSIM activation code to generate the QR code from instead of reading an image (optional)
+    pub activation_code: Option<String>,
+
+    /// http(s) URL to download the QR code image from instead of reading a file (optional).
+    /// Only used when downloads are allowed
+    pub image_url: Option<String>,
+
+    /// Allow downloading the QR code image from `image_url`
+    pub allow_image_download: bool,
+
+    /// Reject recipients with known disposable email domains
+    pub reject_disposable_recipients: bool,
+
+    /// SMTP token to use instead of OAuth (optional, never saved)
+    #[serde(skip)]
+    pub token: Option<String>,
+
+    /// File containing the SMTP token to use instead of OAuth (optional)
+    pub token_file: Option<PathBuf>,
+
+    /// Message-ID of the email this one replies to (optional)
+    pub in_reply_to: Option<String>,
+
+    /// Message-IDs of the earlier emails in the thread (optional)
+    pub references: Option<Vec<String>>,
+
+    /// Attach the QR code as a regular file instead of showing it in the body
+    pub attach_qr: bool,
+
+    /// Attach a calendar event for the day the eSIM expires, worked out from the time period.
+    /// Left out when the time period isn't in days or weeks
+    pub attach_expiry_reminder: bool,
+
+    /// Template file, with optional frontmatter, to use instead of the built-in template.
+    /// It's read once and cached for the rest of the process (optional)
+    pub template_file: Option<PathBuf>,
+
+    /// Content-Disposition of the QR code image (optional, defaults to inline unless attaching it)
+    pub image_disposition: Option<ImageDisposition>,
+
+    /// Largest rendered body to send, in bytes (optional, defaults to 1 MiB)
+    pub max_body_bytes: Option<usize>,
+
+    /// Most images embedded in the body through `cid:` references (optional, defaults to 3)
+    pub max_inline_images: Option<usize>,
+
+    /// Include a plain text version of the body alongside the HTML
+    pub plain_text: bool,
+
+    /// Copy the rules in `<style>` blocks onto the elements they match, for mail clients which drop them
+    pub inline_css: bool,
+
+    /// Phone number customers can call for support, shown as a link for {{support_phone}} (optional)
+    pub support_phone: Option<String>,
+
+    /// Preview text shown after the subject in the inbox, with the same placeholders (optional, defaults to mentioning the location)
+    pub preheader: Option<String>,
+
+    /// Which way the body reads, given to the template as {{dir}} (optional, defaults to left to right)
+    pub text_direction: Option<Direction>,
+
+    /// Minutes ahead of UTC of the recipient's timezone, used to pick the {{greeting}} (optional, defaults to UTC)
+    pub utc_offset_minutes: Option<i32>,
+
+    /// HTML body to use instead of the template, with the same placeholders (optional)
+    pub body_override: Option<String>,
+
+    /// Treat the body template, or the body override, as Markdown and convert it to HTML
+    pub markdown: bool,
+
+    /// Send every email to this address instead, e.g. a test inbox in staging (optional)
+    pub redirect_to: Option<String>,
+
+    /// Email address of a parent or guardian who gets their own copy (optional)
+    pub guardian_email: Option<String>,
+
+    /// Note included in the guardian's copy (optional, defaults to a short explanation)
+    pub guardian_note: Option<String>,
+
+    /// Who this email is being sent to, set for each copy when sending
+    #[serde(skip)]
+    pub recipient_role: RecipientRole,
+
+    /// Language code for the subject line, e.g. "de" (optional, defaults to English)
+    pub language: Option<String>,
+
+    /// Truncate the subject to this many characters, ending with "…", instead
+    /// of refusing to send an overly long subject (optional)
+    pub truncate_subject: Option<usize>,
+
+    /// Character set to encode the subject in, falling back to UTF-8 for subjects it can't encode
+    /// (optional, defaults to UTF-8)
+    pub subject_charset: Option<Charset>,
+
+    /// Email address used to authenticate with the SMTP server (optional, defaults to the sender)
+    pub auth_email: Option<String>,
+
+    /// SASL authorization identity to act as after authenticating, for relays which let one
+    /// account send for another (optional, only sent with PLAIN)
+    pub authzid: Option<String>,
+
+    /// What to do when the sender differs from the authenticated account
+    pub from_mismatch: CheckPolicy,
+
+    /// What to do when the recipient is the same as the sender
+    pub self_send: CheckPolicy,
+
+    /// What to do when the body contains an image which could track opens
+    pub tracking_pixels: CheckPolicy,
+
+    /// Ask the server for delivery status notifications when it supports them
+    pub request_dsn: bool,
+
+    /// SOCKS5 proxy to connect to the SMTP server through (optional)
+    pub proxy: Option<ProxyConfig>,
+
+    /// Most emails to send per second across the whole process (optional, defaults to ESIM_MAILER_MAX_RATE or no limit)
+    pub max_sends_per_second: Option<f64>,
+
+    /// Extra attempts at connecting and authenticating before giving up, separate from any retries of the send
+    pub handshake_retries: usize,
+
+    /// Delay between attempts at connecting and authenticating (optional, defaults to 1 second)
+    pub handshake_retry_delay: Option<Duration>,
+
+    /// Extra attempts at looking up the mail server when the lookup fails temporarily
+    pub dns_retries: usize,
+
+    /// Delay before the first retried lookup, doubled after each one (optional, defaults to half a second)
+    pub dns_retry_delay: Option<Duration>,
+
+    /// Organization the sender belongs to, sent in the Organization header (optional)
+    pub organization: Option<String>,
+
+    /// Where recipients can unsubscribe, e.g. a mailto: or https: URL, sent in the List-Unsubscribe header (optional)
+    pub list_unsubscribe: Option<String>,
+
+    /// Order this email belongs to, sent in the order ID header for reconciling sends with orders (optional)
+    pub order_id: Option<String>,
+
+    /// Name of the header carrying the order ID (optional, defaults to X-Order-Id)
+    pub order_id_header: Option<String>,
+
+    /// Leave out the Sender header naming the authenticated account when it differs from the sender
+    pub omit_sender: bool,
+
+    /// Leave out the X-Mailer header naming esim-mailer and its version
+    pub omit_x_mailer: bool,
+
+    /// Oldest TLS version to accept from the server (optional, defaults to the TLS library's)
+    pub min_tls_version: Option<TlsVersion>,
+
+    /// Ports to use instead of each provider's default, keyed by provider name
+    pub port_overrides: HashMap<String, u16>,
+
+    /// Addresses to BCC when sending through each provider, keyed by provider name
+    pub provider_bcc: HashMap<String, String>,
+
+    /// Largest QR code image in bytes to send through each provider before shrinking it, keyed by provider name
+    pub max_image_bytes: HashMap<String, usize>,
+
+    /// Sender addresses allowed to send through each provider, keyed by provider name. A missing
+    /// or empty list allows any sender
+    pub allowed_from: HashMap<String, Vec<String>>,
+
+    /// Domains to show in the From header in place of the sending domain, e.g. mail.company.com to
+    /// company.com. The envelope and authentication still use the real address
+    pub from_domain_rewrites: HashMap<String, String>,
+}
+
+impl Args {
+    /// Returns the email address used to authenticate with the SMTP server,
+    /// without any display name.
+    pub fn auth_identity(&self) -> &str {
+        let identity = self
+            .auth_email
+            .as_deref()
+            .filter(|auth_email| !auth_email.is_empty())
+            .unwrap_or(&self.email_from);
+        split_name_and_address(identity).1
+    }
+
+    /// Returns the arguments for the guardian's copy of the email, if a
+    /// guardian address is set. The copy isn't sent to CC or BCC again.
+    pub fn guardian_copy(&self) -> Option<Args> {
+        let guardian_email = self
+            .guardian_email
+            .as_deref()
+            .filter(|guardian_email| !guardian_email.is_empty())?;
+        Some(Args {
+            email_to: guardian_email.to_string(),
+            cc: None,
+            bcc: None,
+            provider_bcc: HashMap::new(),
+            recipient_role: RecipientRole::Guardian,
+            ..self.clone()
+        })
+    }
+
+    /// Prompts on the terminal for any required fields which are empty.
+    ///
+    /// Does nothing when stdin isn't a terminal, returning whether prompting
+    /// took place.
+    pub fn prompt_missing_from_stdin(&mut self) -> io::Result<bool> {
+        let stdin = io::stdin();
+        if !stdin.is_terminal() {
+            return Ok(false);
+        }
+        self.prompt_missing(stdin.lock(), io::stdout())?;
+        Ok(true)
+    }
+
+    /// Reads each required field which is empty from `input`, writing a prompt
+    /// to `output` and asking again until a valid value is given.
+    pub fn prompt_missing<R: BufRead, W: Write>(
+        &mut self,
+        mut input: R,
+        mut output: W,
+    ) -> io::Result<()> {
+        let fields = [
+            ("From", &mut self.email_from, true),
+            ("To", &mut self.email_to, true),
+            ("Provider", &mut self.provider, false),
+            ("Name", &mut self.name, false),
+            ("Data Amount", &mut self.data_amount, false),
+            ("Time Period", &mut self.time_period, false),
+            ("Location", &mut self.location, false),
+        ];
+
+        for (label, value, is_address) in fields {
+            while value.trim().is_empty() {
+                write!(output, "{}: ", label)?;
+                output.flush()?;
+
+                let mut line = String::new();
+                if input.read_line(&mut line)? == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("No value provided for {}", label),
+                    ));
+                }
+
+                let line = line.trim();
+                if is_address && line.parse::<lettre::Address>().is_err() {
+                    writeln!(output, "Invalid email address: {}", line)?;
+                    continue;
+                }
+                *value = line.to_string();
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_name_and_address() {
+        assert_eq!(
+            split_name_and_address("Jane Doe <jane@gmail.com>"),
+            (Some("Jane Doe"), "jane@gmail.com")
+        );
+        assert_eq!(
+            split_name_and_address("\"Doe, Jane\" <jane@gmail.com>"),
+            (Some("Doe, Jane"), "jane@gmail.com")
+        );
+        assert_eq!(
+            split_name_and_address("<jane@gmail.com>"),
+            (None, "jane@gmail.com")
+        );
+    }
+
+    #[test]
+    fn test_split_name_and_address_bare_address() {
+        assert_eq!(
+            split_name_and_address(" jane@gmail.com "),
+            (None, "jane@gmail.com")
+        );
+    }
+
+    #[test]
+    fn test_split_name_and_address_malformed() {
+        for value in [
+            "Jane Doe <jane@gmail.com",
+            "Jane Doe <>",
+            "Jane <<jane@gmail.com>",
+        ] {
+            assert_eq!(split_name_and_address(value), (None, value));
+        }
+    }
+
+    #[test]
+    fn test_auth_identity_drops_display_name() {
+        let mut args = Args {
+            email_from: "Jane Doe <jane@gmail.com>".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(args.auth_identity(), "jane@gmail.com");
+
+        args.auth_email = Some("Support <support@gmail.com>".to_string());
+        assert_eq!(args.auth_identity(), "support@gmail.com");
+    }
+
+    #[test]
+    fn test_prompt_missing_fills_empty_fields() {
+        let mut args = Args {
+            provider: "TestProvider".to_string(),
+            ..Default::default()
+        };
+        let input = "from@gmail.com\nnot an address\n\nto@example.com\nJohn\n5GB\n30 days\nEgypt\n";
+        let mut output = Vec::new();
+
+        args.prompt_missing(input.as_bytes(), &mut output).unwrap();
+
+        assert_eq!(args.email_from, "from@gmail.com");
+        assert_eq!(args.email_to, "to@example.com");
+        assert_eq!(args.provider, "TestProvider");
+        assert_eq!(args.name, "John");
+        assert_eq!(args.data_amount, "5GB");
+        assert_eq!(args.time_period, "30 days");
+        assert_eq!(args.location, "Egypt");
+
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("Invalid email address: not an address"));
+        assert!(!output.contains("Provider:"));
+    }
+
+    #[test]
+    fn test_prompt_missing_errors_on_end_of_input() {
+        let mut args = Args::default();
+        let result = args.prompt_missing("from@gmail.com\n".as_bytes(), Vec::new());
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
 }
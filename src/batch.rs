@@ -0,0 +1,1021 @@
+use crate::args::CheckPolicy;
+use crate::email::{EmailTemplate, Provider};
+use crate::retry::RetryPolicy;
+use crate::{Args, EsimMailerError};
+#[cfg(feature = "async")]
+use futures_util::stream::{self, StreamExt};
+#[cfg(feature = "async")]
+use lettre::transport::smtp::PoolConfig;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::str::FromStr;
+
+/// A single eSIM email to send as part of a batch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmailJob {
+    pub args: Args,
+    pub image_path: PathBuf,
+    pub count: usize,
+}
+
+/// Returns a stable key for sending the email for `args`, `image_path` and
+/// `count`, made by hashing the recipient, the subject and the image's
+/// content. Identical sends get the same key across runs, so it can be used
+/// with an [`IdempotencyStore`] or by other systems to find duplicates.
+///
+/// Images made from an activation code or downloaded from a URL are hashed
+/// by the code or the URL, without generating or downloading them. Fails if
+/// the template or the image file can't be read.
+pub fn idempotency_key(
+    args: &Args,
+    image_path: &Path,
+    count: usize,
+) -> Result<String, EsimMailerError> {
+    let subject = EmailTemplate::load(args)?.subject(args, count);
+    let mut hasher = Sha256::new();
+    for part in [args.email_to.as_bytes(), subject.as_bytes()] {
+        hasher.update(part);
+        hasher.update(b"\n");
+    }
+    match (&args.activation_code, &args.image_url) {
+        (Some(activation_code), _) => hasher.update(activation_code.as_bytes()),
+        (None, Some(image_url)) => hasher.update(image_url.as_bytes()),
+        (None, None) => hasher.update(fs::read(image_path).map_err(|e| {
+            EsimMailerError::MessageError(format!(
+                "Could not read image '{}': {}",
+                image_path.display(),
+                e
+            ))
+        })?),
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// Trait for remembering which emails have already been sent
+pub trait IdempotencyStore {
+    fn contains(&self, key: &str) -> bool;
+    fn insert(&mut self, key: String);
+}
+
+// In-memory implementation of IdempotencyStore
+#[derive(Default)]
+pub struct MemoryIdempotencyStore {
+    keys: HashSet<String>,
+}
+
+impl IdempotencyStore for MemoryIdempotencyStore {
+    fn contains(&self, key: &str) -> bool {
+        self.keys.contains(key)
+    }
+
+    fn insert(&mut self, key: String) {
+        self.keys.insert(key);
+    }
+}
+
+/// Options controlling how a batch of emails is sent.
+#[derive(Debug, Default, Clone)]
+pub struct BatchConfig {
+    /// Stop the batch once this many sends have failed in a row (optional)
+    pub abort_after_consecutive_failures: Option<usize>,
+
+    /// How failed sends are retried before counting as a failure (optional)
+    pub retry_policy: Option<RetryPolicy>,
+
+    /// Refuse to send batches with more emails than this (optional)
+    pub max_recipients: Option<usize>,
+
+    /// Warn, but still send, when a batch has more emails than this (optional)
+    pub warn_recipients: Option<usize>,
+
+    /// Stop the batch at the first failed send instead of attempting every job
+    pub fail_fast: bool,
+
+    /// Senders used in turn for each email sent, each in proportion to its
+    /// weight. When empty, each job's own sender is used
+    pub senders: Vec<Sender>,
+
+    /// Send a test email to the sender's own address first, and abort the
+    /// batch if it fails
+    pub smoke_test: bool,
+}
+
+/// How many connections an async batch keeps open to the server by default.
+#[cfg(feature = "async")]
+pub const DEFAULT_POOL_SIZE: u32 = 4;
+
+/// Options controlling how an async batch of emails is sent.
+#[cfg(feature = "async")]
+#[derive(Debug, Default, Clone)]
+pub struct AsyncBatchConfig {
+    /// Most connections to keep open to the server, and so most emails sent
+    /// at once (optional, defaults to [`DEFAULT_POOL_SIZE`])
+    pub pool_size: Option<u32>,
+}
+
+#[cfg(feature = "async")]
+impl AsyncBatchConfig {
+    /// Returns the number of connections to keep open, which is at least one.
+    pub fn pool_size(&self) -> u32 {
+        self.pool_size.unwrap_or(DEFAULT_POOL_SIZE).max(1)
+    }
+
+    /// Returns the connection pool settings for the batch's transport, for
+    /// [`crate::email::configure_async_mailer`].
+    pub fn pool_config(&self) -> PoolConfig {
+        PoolConfig::new().max_size(self.pool_size())
+    }
+}
+
+/// An account which can send a batch's emails.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sender {
+    pub email_from: String,
+    pub token: String,
+
+    /// How many emails this sender sends for each one sent by a sender with
+    /// a weight of 1, e.g. to match its quota. Senders with a weight of 0
+    /// aren't used
+    pub weight: usize,
+}
+
+impl Sender {
+    /// Returns a sender with a weight of 1.
+    pub fn new(email_from: impl Into<String>, token: impl Into<String>) -> Self {
+        Self {
+            email_from: email_from.into(),
+            token: token.into(),
+            weight: 1,
+        }
+    }
+}
+
+/// Picks senders by smooth weighted round-robin, which spreads each sender's
+/// turns evenly through the batch rather than sending all of them at once.
+struct SenderPool<'a> {
+    senders: &'a [Sender],
+    current: Vec<i64>,
+}
+
+impl<'a> SenderPool<'a> {
+    fn new(senders: &'a [Sender]) -> Self {
+        Self {
+            senders,
+            current: vec![0; senders.len()],
+        }
+    }
+
+    /// Returns the sender for the next email, or `None` if no sender has a
+    /// weight above 0.
+    fn next(&mut self) -> Option<&'a Sender> {
+        let total: i64 = self.senders.iter().map(|sender| sender.weight as i64).sum();
+        if total == 0 {
+            return None;
+        }
+        for (current, sender) in self.current.iter_mut().zip(self.senders) {
+            *current += sender.weight as i64;
+        }
+        let (index, _) = self
+            .current
+            .iter()
+            .enumerate()
+            .max_by_key(|(index, current)| (**current, std::cmp::Reverse(*index)))?;
+        self.current[index] -= total;
+        Some(&self.senders[index])
+    }
+}
+
+/// When a batch counts as failed, e.g. for a process's exit code.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ExitPolicy {
+    /// Fail if any email couldn't be sent.
+    #[default]
+    AnyFailure,
+    /// Fail only if no email was sent.
+    AllFailures,
+}
+
+impl FromStr for ExitPolicy {
+    type Err = EsimMailerError;
+
+    /// Parses `any` or `all`, the failures which fail the batch.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "any" => Ok(Self::AnyFailure),
+            "all" => Ok(Self::AllFailures),
+            _ => Err(EsimMailerError::ConfigError(format!(
+                "Unknown exit policy '{}', expected 'any' or 'all'",
+                s
+            ))),
+        }
+    }
+}
+
+/// The outcome of sending a batch of emails.
+#[derive(Debug, Default)]
+pub struct BatchReport {
+    /// Indices of the jobs which were sent successfully
+    pub sent: Vec<usize>,
+
+    /// Indices of the jobs which failed, along with their errors
+    pub failed: Vec<(usize, EsimMailerError)>,
+
+    /// Indices of the jobs skipped because they had already been sent
+    pub skipped_duplicates: Vec<usize>,
+
+    /// Indices of the jobs skipped because an identical job came earlier in
+    /// the same batch
+    pub skipped_in_batch: Vec<usize>,
+
+    /// Whether the batch stopped before attempting every job
+    pub aborted: bool,
+
+    /// Warnings about the batch as a whole, which are also printed
+    pub warnings: Vec<String>,
+}
+
+impl BatchReport {
+    /// Returns whether any email failed to send.
+    pub fn has_failures(&self) -> bool {
+        !self.failed.is_empty()
+    }
+
+    /// Returns whether emails were attempted and every one of them failed.
+    pub fn all_failed(&self) -> bool {
+        self.has_failures() && self.sent.is_empty()
+    }
+
+    /// Returns the exit code for a process which sent this batch. A batch
+    /// which was aborted before sending everything counts as a failure.
+    pub fn exit_code(&self, policy: ExitPolicy) -> ExitCode {
+        let failed = match policy {
+            ExitPolicy::AnyFailure => self.has_failures() || self.aborted,
+            ExitPolicy::AllFailures => {
+                self.sent.is_empty() && (self.has_failures() || self.aborted)
+            }
+        };
+        if failed {
+            ExitCode::FAILURE
+        } else {
+            ExitCode::SUCCESS
+        }
+    }
+}
+
+/// Checks that every job's sender address belongs to a provider which can
+/// send email, listing every unsupported address in one error.
+pub fn validate_senders(jobs: &[EmailJob]) -> Result<(), EsimMailerError> {
+    let mut unsupported: Vec<&str> = Vec::new();
+    for job in jobs {
+        let sender = job.args.auth_identity();
+        if sender.parse::<Provider>().is_err() && !unsupported.contains(&sender) {
+            unsupported.push(sender);
+        }
+    }
+    if unsupported.is_empty() {
+        return Ok(());
+    }
+    Err(EsimMailerError::ConfigError(format!(
+        "No supported email provider for {}",
+        unsupported.join(", ")
+    )))
+}
+
+/// Sends each job in order using `send`, collecting the results into a report.
+pub fn send_batch<F>(jobs: &[EmailJob], config: &BatchConfig, send: F) -> BatchReport
+where
+    F: FnMut(&EmailJob) -> Result<(), EsimMailerError>,
+{
+    run_batch(jobs, config, None, send)
+}
+
+/// Like [`send_batch`], but skips jobs which `store` records as already sent
+/// and records each job which is sent successfully.
+pub fn send_batch_with_store<F>(
+    jobs: &[EmailJob],
+    config: &BatchConfig,
+    store: &mut dyn IdempotencyStore,
+    send: F,
+) -> BatchReport
+where
+    F: FnMut(&EmailJob) -> Result<(), EsimMailerError>,
+{
+    run_batch(jobs, config, Some(store), send)
+}
+
+/// Sends the jobs using `send`, with as many sending at once as the
+/// config's pool size, collecting the results into a report.
+#[cfg(feature = "async")]
+pub async fn send_batch_async<F, Fut>(
+    jobs: &[EmailJob],
+    config: &AsyncBatchConfig,
+    send: F,
+) -> BatchReport
+where
+    F: Fn(EmailJob) -> Fut,
+    Fut: Future<Output = Result<(), EsimMailerError>>,
+{
+    let mut results: Vec<_> = stream::iter(jobs.iter().cloned().enumerate())
+        .map(|(index, job)| {
+            let sending = send(job);
+            async move { (index, sending.await) }
+        })
+        .buffer_unordered(config.pool_size() as usize)
+        .collect()
+        .await;
+    results.sort_by_key(|(index, _)| *index);
+
+    let mut report = BatchReport::default();
+    for (index, result) in results {
+        match result {
+            Ok(()) => report.sent.push(index),
+            Err(e) => report.failed.push((index, e)),
+        }
+    }
+    report
+}
+
+fn run_batch<F>(
+    jobs: &[EmailJob],
+    config: &BatchConfig,
+    mut store: Option<&mut dyn IdempotencyStore>,
+    mut send: F,
+) -> BatchReport
+where
+    F: FnMut(&EmailJob) -> Result<(), EsimMailerError>,
+{
+    let mut report = BatchReport::default();
+    let mut consecutive_failures = 0;
+    let mut attempted = HashSet::new();
+    let mut senders = SenderPool::new(&config.senders);
+
+    if let Some(max) = config.max_recipients.filter(|max| jobs.len() > *max) {
+        let problem = format!(
+            "Refusing to send {} emails, the limit is {}",
+            jobs.len(),
+            max
+        );
+        eprintln!("Error: {}", problem);
+        report.warnings.push(problem);
+        report.aborted = true;
+        return report;
+    }
+    if let Some(threshold) = config
+        .warn_recipients
+        .filter(|threshold| jobs.len() > *threshold)
+    {
+        let warning = format!(
+            "About to send {} emails, more than the usual {}. Make sure this is intended",
+            jobs.len(),
+            threshold
+        );
+        eprintln!("Warning: {}", warning);
+        report.warnings.push(warning);
+    }
+    // Read every template file before sending anything, so a missing one
+    // doesn't stop the batch part way through.
+    for path in jobs
+        .iter()
+        .filter_map(|job| job.args.template_file.as_ref())
+    {
+        if let Err(e) = EmailTemplate::cached(path) {
+            let problem = format!("Could not load template {}: {}", path.display(), e);
+            eprintln!("Error: {}", problem);
+            report.warnings.push(problem);
+            report.aborted = true;
+            return report;
+        }
+    }
+    if config.smoke_test
+        && let Some(job) = jobs.first()
+    {
+        let job = smoke_test_job(job, config);
+        if let Err(e) = send(&job) {
+            let problem = format!(
+                "Smoke test to {} failed, not sending the batch: {}",
+                job.args.email_to, e
+            );
+            eprintln!("Error: {}", problem);
+            report.warnings.push(problem);
+            report.aborted = true;
+            return report;
+        }
+    }
+
+    for (index, job) in jobs.iter().enumerate() {
+        if config
+            .abort_after_consecutive_failures
+            .is_some_and(|limit| consecutive_failures >= limit)
+        {
+            eprintln!(
+                "Aborting batch after {} consecutive failures",
+                consecutive_failures
+            );
+            report.aborted = true;
+            break;
+        }
+
+        let key = match idempotency_key(&job.args, &job.image_path, job.count) {
+            Ok(key) => key,
+            // The send would fail too, so count it as a failed send
+            Err(e) => {
+                consecutive_failures += 1;
+                report.failed.push((index, e));
+                if config.fail_fast {
+                    report.aborted = true;
+                    break;
+                }
+                continue;
+            }
+        };
+        if store.as_ref().is_some_and(|store| store.contains(&key)) {
+            report.skipped_duplicates.push(index);
+            continue;
+        }
+        if !attempted.insert(key.clone()) {
+            report.skipped_in_batch.push(index);
+            continue;
+        }
+
+        let sender_job;
+        let job = match senders.next() {
+            Some(sender) => {
+                // The sender's account authenticates, not the job's
+                sender_job = EmailJob {
+                    args: Args {
+                        email_from: sender.email_from.clone(),
+                        token: Some(sender.token.clone()),
+                        auth_email: None,
+                        ..job.args.clone()
+                    },
+                    ..job.clone()
+                };
+                &sender_job
+            }
+            None => job,
+        };
+
+        let result = match &config.retry_policy {
+            Some(policy) => policy.run(|| send(job)),
+            None => send(job),
+        };
+
+        match result {
+            Ok(()) => {
+                consecutive_failures = 0;
+                report.sent.push(index);
+                if let Some(store) = store.as_mut() {
+                    store.insert(key);
+                }
+            }
+            Err(e) => {
+                consecutive_failures += 1;
+                report.failed.push((index, e));
+                if config.fail_fast {
+                    report.aborted = true;
+                    break;
+                }
+            }
+        }
+    }
+
+    report
+}
+
+/// Returns a copy of `job` addressed only to its own sender, using the first
+/// of the batch's senders if it has any.
+fn smoke_test_job(job: &EmailJob, config: &BatchConfig) -> EmailJob {
+    let sender = config.senders.iter().find(|sender| sender.weight > 0);
+    let (email_from, token, auth_email) = match sender {
+        Some(sender) => (sender.email_from.clone(), Some(sender.token.clone()), None),
+        None => (
+            job.args.email_from.clone(),
+            job.args.token.clone(),
+            job.args.auth_email.clone(),
+        ),
+    };
+    EmailJob {
+        args: Args {
+            email_to: email_from.clone(),
+            email_from,
+            token,
+            auth_email,
+            cc: None,
+            bcc: None,
+            bcc_group_file: None,
+            provider_bcc: Default::default(),
+            guardian_email: None,
+            cc_reply_to: false,
+            self_send: CheckPolicy::Allow,
+            ..job.args.clone()
+        },
+        ..job.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn jobs(n: usize) -> Vec<EmailJob> {
+        (1..=n)
+            .map(|count| EmailJob {
+                args: Args {
+                    activation_code: Some(format!("LPA:1$smdp.example.com$CODE-{}", count)),
+                    ..Default::default()
+                },
+                image_path: PathBuf::from(format!("qr{}.png", count)),
+                count,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_idempotency_key() {
+        let image_path = std::env::temp_dir().join("test_image_idempotency_key.png");
+        fs::write(&image_path, b"fake image data").unwrap();
+        let args = Args {
+            email_to: "recipient@example.com".to_string(),
+            ..Default::default()
+        };
+
+        let key = idempotency_key(&args, &image_path, 1).unwrap();
+        let same = idempotency_key(&args, &image_path, 1).unwrap();
+        fs::write(&image_path, b"other image data").unwrap();
+        let changed_image = idempotency_key(&args, &image_path, 1).unwrap();
+
+        fs::remove_file(&image_path).unwrap();
+        let missing_image = idempotency_key(&args, &image_path, 1);
+
+        assert_eq!(key, same);
+        assert_eq!(key.len(), 64);
+        assert_ne!(key, changed_image);
+        assert!(matches!(
+            missing_image,
+            Err(EsimMailerError::MessageError(_))
+        ));
+    }
+
+    #[test]
+    fn test_validate_senders() {
+        let mut jobs = jobs(4);
+        jobs[0].args.email_from = "one@gmail.com".to_string();
+        jobs[1].args.email_from = "Two <two@unknown.example>".to_string();
+        jobs[2].args.email_from = "three@example.org".to_string();
+        jobs[3].args.email_from = "two@unknown.example".to_string();
+
+        let result = validate_senders(&jobs);
+
+        let Err(EsimMailerError::ConfigError(message)) = result else {
+            panic!("Expected a ConfigError, got {:?}", result);
+        };
+        assert_eq!(
+            message,
+            "No supported email provider for two@unknown.example, three@example.org"
+        );
+
+        jobs[1].args.email_from = "two@fastmail.com".to_string();
+        jobs[2].args.email_from = "three@outlook.com".to_string();
+        jobs[3].args.email_from = "four@zoho.com".to_string();
+        assert!(validate_senders(&jobs).is_ok());
+    }
+
+    #[test]
+    fn test_send_batch_all_succeed() {
+        let report = send_batch(&jobs(3), &BatchConfig::default(), |_| Ok(()));
+        assert_eq!(report.sent, vec![0, 1, 2]);
+        assert!(report.failed.is_empty());
+        assert!(!report.aborted);
+    }
+
+    #[test]
+    fn test_send_batch_aborts_after_consecutive_failures() {
+        let config = BatchConfig {
+            abort_after_consecutive_failures: Some(3),
+            ..Default::default()
+        };
+        let mut attempted = Vec::new();
+
+        let report = send_batch(&jobs(5), &config, |job| {
+            attempted.push(job.count);
+            Err(EsimMailerError::SmtpError("connection refused".to_string()))
+        });
+
+        assert_eq!(attempted, vec![1, 2, 3]);
+        assert!(report.sent.is_empty());
+        assert_eq!(report.failed.len(), 3);
+        assert!(report.aborted);
+    }
+
+    #[test]
+    fn test_send_batch_fail_fast() {
+        let mut jobs = jobs(4);
+        jobs[1].args.email_to = "not an address".to_string();
+        let send = |job: &EmailJob| {
+            if job.args.email_to.contains(' ') {
+                Err(EsimMailerError::MessageError("invalid address".to_string()))
+            } else {
+                Ok(())
+            }
+        };
+
+        let config = BatchConfig {
+            fail_fast: true,
+            ..Default::default()
+        };
+        let report = send_batch(&jobs, &config, send);
+        assert_eq!(report.sent, vec![0]);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, 1);
+        assert!(report.aborted);
+
+        let report = send_batch(&jobs, &BatchConfig::default(), send);
+        assert_eq!(report.sent, vec![0, 2, 3]);
+        assert_eq!(report.failed.len(), 1);
+        assert!(!report.aborted);
+    }
+
+    #[test]
+    fn test_send_batch_success_resets_failure_streak() {
+        let config = BatchConfig {
+            abort_after_consecutive_failures: Some(2),
+            ..Default::default()
+        };
+
+        let report = send_batch(&jobs(5), &config, |job| {
+            if job.count % 2 == 0 {
+                Ok(())
+            } else {
+                Err(EsimMailerError::SmtpError("temporary failure".to_string()))
+            }
+        });
+
+        assert_eq!(report.sent, vec![1, 3]);
+        assert_eq!(report.failed.len(), 3);
+        assert!(!report.aborted);
+    }
+
+    #[test]
+    fn test_send_batch_round_robins_senders() {
+        let config = BatchConfig {
+            senders: vec![
+                Sender::new("first@example.com", "first-token"),
+                Sender::new("second@example.com", "second-token"),
+            ],
+            ..Default::default()
+        };
+        let mut used = Vec::new();
+
+        let report = send_batch(&jobs(3), &config, |job| {
+            used.push((job.args.email_from.clone(), job.args.token.clone().unwrap()));
+            Ok(())
+        });
+
+        assert_eq!(report.sent, vec![0, 1, 2]);
+        assert_eq!(
+            used,
+            vec![
+                ("first@example.com".to_string(), "first-token".to_string()),
+                ("second@example.com".to_string(), "second-token".to_string()),
+                ("first@example.com".to_string(), "first-token".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_send_batch_senders_replace_auth_email() {
+        let config = BatchConfig {
+            senders: vec![Sender::new("first@gmail.com", "first-token")],
+            smoke_test: true,
+            ..Default::default()
+        };
+        let mut jobs = jobs(1);
+        jobs[0].args.email_from = "shared@example.com".to_string();
+        jobs[0].args.auth_email = Some("job@outlook.com".to_string());
+        let mut identities = Vec::new();
+
+        let report = send_batch(&jobs, &config, |job| {
+            identities.push(job.args.auth_identity().to_string());
+            Ok(())
+        });
+
+        assert_eq!(report.sent, vec![0]);
+        assert_eq!(identities, vec!["first@gmail.com", "first@gmail.com"]);
+    }
+
+    #[test]
+    fn test_send_batch_weights_senders() {
+        let config = BatchConfig {
+            senders: vec![
+                Sender {
+                    weight: 5,
+                    ..Sender::new("big@example.com", "big-token")
+                },
+                Sender {
+                    weight: 3,
+                    ..Sender::new("medium@example.com", "medium-token")
+                },
+                Sender::new("small@example.com", "small-token"),
+                Sender {
+                    weight: 0,
+                    ..Sender::new("paused@example.com", "paused-token")
+                },
+            ],
+            ..Default::default()
+        };
+        let mut counts: HashMap<String, usize> = HashMap::new();
+
+        let report = send_batch(&jobs(900), &config, |job| {
+            *counts.entry(job.args.email_from.clone()).or_default() += 1;
+            Ok(())
+        });
+
+        assert_eq!(report.sent.len(), 900);
+        assert!(counts["big@example.com"].abs_diff(500) <= 5);
+        assert!(counts["medium@example.com"].abs_diff(300) <= 5);
+        assert!(counts["small@example.com"].abs_diff(100) <= 5);
+        assert!(!counts.contains_key("paused@example.com"));
+    }
+
+    #[test]
+    fn test_send_batch_missing_template_aborts_before_sending() {
+        let mut jobs = jobs(2);
+        jobs[1].args.template_file =
+            Some(std::env::temp_dir().join("test_template_batch_missing.html"));
+        let mut attempts = 0;
+
+        let report = send_batch(&jobs, &BatchConfig::default(), |_| {
+            attempts += 1;
+            Ok(())
+        });
+
+        assert_eq!(attempts, 0);
+        assert!(report.aborted);
+        assert_eq!(report.warnings.len(), 1);
+    }
+
+    #[test]
+    fn test_send_batch_smoke_test_failure_aborts() {
+        let config = BatchConfig {
+            smoke_test: true,
+            ..Default::default()
+        };
+        let mut jobs = jobs(2);
+        for job in &mut jobs {
+            job.args.email_from = "sender@example.com".to_string();
+            job.args.email_to = "recipient@example.com".to_string();
+        }
+        let mut recipients = Vec::new();
+
+        let report = send_batch(&jobs, &config, |job| {
+            recipients.push(job.args.email_to.clone());
+            Err(EsimMailerError::AuthError("bad token".to_string()))
+        });
+
+        assert_eq!(recipients, vec!["sender@example.com"]);
+        assert!(report.aborted);
+        assert!(report.sent.is_empty());
+        assert!(report.failed.is_empty());
+        assert_eq!(report.warnings.len(), 1);
+    }
+
+    #[test]
+    fn test_send_batch_smoke_test_success_proceeds() {
+        let config = BatchConfig {
+            smoke_test: true,
+            ..Default::default()
+        };
+        let mut jobs = jobs(2);
+        for job in &mut jobs {
+            job.args.email_from = "sender@example.com".to_string();
+            job.args.email_to = "recipient@example.com".to_string();
+            job.args.bcc = Some("audit@example.com".to_string());
+        }
+        let mut sent = Vec::new();
+
+        let report = send_batch(&jobs, &config, |job| {
+            sent.push((job.args.email_to.clone(), job.args.bcc.clone()));
+            Ok(())
+        });
+
+        assert_eq!(
+            sent,
+            vec![
+                ("sender@example.com".to_string(), None),
+                (
+                    "recipient@example.com".to_string(),
+                    Some("audit@example.com".to_string())
+                ),
+                (
+                    "recipient@example.com".to_string(),
+                    Some("audit@example.com".to_string())
+                ),
+            ]
+        );
+        assert_eq!(report.sent, vec![0, 1]);
+        assert!(!report.aborted);
+    }
+
+    #[test]
+    fn test_send_batch_without_threshold_attempts_every_job() {
+        let report = send_batch(&jobs(4), &BatchConfig::default(), |_| {
+            Err(EsimMailerError::SmtpError("connection refused".to_string()))
+        });
+
+        assert_eq!(report.failed.len(), 4);
+        assert!(!report.aborted);
+    }
+
+    #[test]
+    fn test_batch_report_failure_helpers() {
+        let mixed = send_batch(&jobs(3), &BatchConfig::default(), |job| {
+            if job.count == 2 {
+                Err(EsimMailerError::SmtpError("rejected".to_string()))
+            } else {
+                Ok(())
+            }
+        });
+        assert!(mixed.has_failures());
+        assert!(!mixed.all_failed());
+        assert_eq!(mixed.exit_code(ExitPolicy::AnyFailure), ExitCode::FAILURE);
+        assert_eq!(mixed.exit_code(ExitPolicy::AllFailures), ExitCode::SUCCESS);
+
+        let all_failed = send_batch(&jobs(2), &BatchConfig::default(), |_| {
+            Err(EsimMailerError::SmtpError("rejected".to_string()))
+        });
+        assert!(all_failed.has_failures());
+        assert!(all_failed.all_failed());
+        assert_eq!(
+            all_failed.exit_code(ExitPolicy::AllFailures),
+            ExitCode::FAILURE
+        );
+
+        let all_sent = send_batch(&jobs(2), &BatchConfig::default(), |_| Ok(()));
+        assert!(!all_sent.has_failures());
+        assert!(!all_sent.all_failed());
+        assert_eq!(
+            all_sent.exit_code(ExitPolicy::AnyFailure),
+            ExitCode::SUCCESS
+        );
+    }
+
+    #[test]
+    fn test_send_batch_retries_with_policy() {
+        let config = BatchConfig {
+            retry_policy: Some(RetryPolicy {
+                max_attempts: 2,
+                delay: std::time::Duration::ZERO,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut attempts = 0;
+
+        let report = send_batch(&jobs(1), &config, |_| {
+            attempts += 1;
+            if attempts == 1 {
+                Err(EsimMailerError::NetworkError(
+                    "connection reset".to_string(),
+                ))
+            } else {
+                Ok(())
+            }
+        });
+
+        assert_eq!(attempts, 2);
+        assert_eq!(report.sent, vec![0]);
+    }
+
+    #[test]
+    fn test_send_batch_warns_over_soft_threshold() {
+        let config = BatchConfig {
+            max_recipients: Some(5),
+            warn_recipients: Some(3),
+            ..Default::default()
+        };
+
+        let report = send_batch(&jobs(4), &config, |_| Ok(()));
+
+        assert_eq!(report.sent, vec![0, 1, 2, 3]);
+        assert_eq!(report.warnings.len(), 1);
+        assert!(report.warnings[0].contains("4 emails"));
+        assert!(!report.aborted);
+
+        let report = send_batch(&jobs(3), &config, |_| Ok(()));
+        assert!(report.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_send_batch_refuses_over_hard_limit() {
+        let config = BatchConfig {
+            max_recipients: Some(2),
+            warn_recipients: Some(1),
+            ..Default::default()
+        };
+        let mut attempted = 0;
+
+        let report = send_batch(&jobs(3), &config, |_| {
+            attempted += 1;
+            Ok(())
+        });
+
+        assert_eq!(attempted, 0);
+        assert!(report.sent.is_empty());
+        assert!(report.aborted);
+        assert!(report.warnings[0].contains("limit is 2"));
+    }
+
+    #[test]
+    fn test_send_batch_skips_duplicates_in_batch() {
+        let mut jobs = jobs(2);
+        jobs.push(jobs[0].clone());
+        let mut sent = Vec::new();
+
+        let report = send_batch(&jobs, &BatchConfig::default(), |job| {
+            sent.push(job.count);
+            Ok(())
+        });
+
+        assert_eq!(sent, vec![1, 2]);
+        assert_eq!(report.sent, vec![0, 1]);
+        assert_eq!(report.skipped_in_batch, vec![2]);
+        assert!(report.skipped_duplicates.is_empty());
+    }
+
+    #[test]
+    fn test_send_batch_with_store_skips_duplicates() {
+        let mut jobs = jobs(3);
+        jobs.insert(2, jobs[1].clone());
+        let mut store = MemoryIdempotencyStore::default();
+        let mut sent = Vec::new();
+
+        let report = send_batch_with_store(&jobs, &BatchConfig::default(), &mut store, |job| {
+            sent.push(job.count);
+            Ok(())
+        });
+
+        assert_eq!(sent, vec![1, 2, 3]);
+        assert_eq!(report.sent, vec![0, 1, 3]);
+        assert_eq!(report.skipped_duplicates, vec![2]);
+        assert!(report.failed.is_empty());
+    }
+
+    #[test]
+    fn test_send_batch_with_store_retries_failed_jobs() {
+        let jobs = jobs(1);
+        let mut store = MemoryIdempotencyStore::default();
+
+        let first = send_batch_with_store(&jobs, &BatchConfig::default(), &mut store, |_| {
+            Err(EsimMailerError::NetworkError(
+                "connection reset".to_string(),
+            ))
+        });
+        let second = send_batch_with_store(&jobs, &BatchConfig::default(), &mut store, |_| Ok(()));
+        let third = send_batch_with_store(&jobs, &BatchConfig::default(), &mut store, |_| Ok(()));
+
+        assert_eq!(first.failed.len(), 1);
+        assert_eq!(second.sent, vec![0]);
+        assert_eq!(third.skipped_duplicates, vec![0]);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_send_batch_async_caps_concurrency_at_pool_size() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let jobs = jobs(10);
+        let config = AsyncBatchConfig { pool_size: Some(3) };
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let most_in_flight = Arc::new(AtomicUsize::new(0));
+
+        let report = send_batch_async(&jobs, &config, |job| {
+            let in_flight = Arc::clone(&in_flight);
+            let most_in_flight = Arc::clone(&most_in_flight);
+            async move {
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                most_in_flight.fetch_max(now, Ordering::SeqCst);
+                for _ in 0..5 {
+                    tokio::task::yield_now().await;
+                }
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                if job.count == 4 {
+                    return Err(EsimMailerError::SmtpError("rejected".to_string()));
+                }
+                Ok(())
+            }
+        })
+        .await;
+
+        assert_eq!(most_in_flight.load(Ordering::SeqCst), 3);
+        assert_eq!(report.sent, vec![0, 1, 2, 4, 5, 6, 7, 8, 9]);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, 3);
+        assert_eq!(config.pool_size(), 3);
+        assert_eq!(AsyncBatchConfig::default().pool_size(), DEFAULT_POOL_SIZE);
+    }
+}
@@ -0,0 +1,183 @@
+use crate::batch::{BatchConfig, BatchReport, EmailJob, ExitPolicy, send_batch};
+use crate::email::{Provider, TransportCache, send_email_cached};
+use crate::oauth::OAuthClient;
+use crate::token::{TOKEN_ENV_VAR, resolve_token};
+use crate::{Args, EsimMailerError};
+use std::path::PathBuf;
+
+/// Options given on the command line.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CliOptions {
+    /// Prompt on the terminal for any missing fields and send from there,
+    /// without opening the window
+    pub to_stdin: bool,
+
+    /// QR code images to send, one email each
+    pub image_paths: Vec<PathBuf>,
+
+    /// Send a test email to the sender's own address first, and stop if it
+    /// fails
+    pub smoke_test: bool,
+
+    /// Which failed sends make the process exit with a failure
+    pub exit_policy: ExitPolicy,
+}
+
+impl CliOptions {
+    /// Parses the command line arguments, without the program name.
+    pub fn parse<I>(args: I) -> Result<Self, EsimMailerError>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut options = Self::default();
+        for arg in args {
+            match arg.as_str() {
+                "--to-stdin" => options.to_stdin = true,
+                "--smoke-test" => options.smoke_test = true,
+                flag if flag.starts_with("--exit-on=") => {
+                    options.exit_policy = flag["--exit-on=".len()..].parse()?;
+                }
+                flag if flag.starts_with("--") => {
+                    return Err(EsimMailerError::ConfigError(format!(
+                        "Unknown option '{}'",
+                        flag
+                    )));
+                }
+                _ => options.image_paths.push(PathBuf::from(arg)),
+            }
+        }
+        if !options.to_stdin && !options.image_paths.is_empty() {
+            return Err(EsimMailerError::ConfigError(
+                "QR code images can only be given with --to-stdin".to_string(),
+            ));
+        }
+        if !options.to_stdin && options.smoke_test {
+            return Err(EsimMailerError::ConfigError(
+                "--smoke-test can only be given with --to-stdin, the window has a checkbox for it"
+                    .to_string(),
+            ));
+        }
+        if !options.to_stdin && options.exit_policy != ExitPolicy::default() {
+            return Err(EsimMailerError::ConfigError(
+                "--exit-on can only be given with --to-stdin".to_string(),
+            ));
+        }
+        Ok(options)
+    }
+}
+
+/// Prompts on the terminal for the fields needed to send, then sends an
+/// email for each of the QR code images given on the command line.
+pub fn run_interactive(options: &CliOptions) -> Result<BatchReport, EsimMailerError> {
+    if options.image_paths.is_empty() {
+        return Err(EsimMailerError::ConfigError(
+            "Give the QR code images to send after --to-stdin".to_string(),
+        ));
+    }
+
+    let mut args = Args::default();
+    if !args.prompt_missing_from_stdin()? {
+        return Err(EsimMailerError::ConfigError(
+            "--to-stdin needs a terminal to prompt on".to_string(),
+        ));
+    }
+
+    let provider: Provider = args.auth_identity().parse()?;
+    let token = match resolve_token(&args)? {
+        Some(token) => token,
+        None if provider.requires_oauth() => OAuthClient::default()
+            .get_or_refresh_token(&provider, args.auth_identity())
+            .map_err(|e| EsimMailerError::AuthError(e.to_string()))?,
+        None => {
+            return Err(EsimMailerError::ConfigError(format!(
+                "{} needs an app password, set it in {}. {}",
+                provider,
+                TOKEN_ENV_VAR,
+                provider.setup_instructions()
+            )));
+        }
+    };
+
+    let jobs: Vec<EmailJob> = options
+        .image_paths
+        .iter()
+        .enumerate()
+        .map(|(index, image_path)| EmailJob {
+            args: args.clone(),
+            image_path: image_path.clone(),
+            count: index + 1,
+        })
+        .collect();
+    let config = BatchConfig {
+        fail_fast: true,
+        smoke_test: options.smoke_test,
+        ..Default::default()
+    };
+    // Reuse the connection to the server across the batch
+    let mut cache = TransportCache::default();
+    Ok(send_batch(&jobs, &config, |job| {
+        send_email_cached(
+            &job.args,
+            token.clone(),
+            &job.image_path,
+            job.count,
+            None,
+            &mut cache,
+        )
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<CliOptions, EsimMailerError> {
+        CliOptions::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse(&[]).unwrap(), CliOptions::default());
+
+        let options = parse(&["--to-stdin", "qr1.png", "qr2.png"]).unwrap();
+        assert!(options.to_stdin);
+        assert!(!options.smoke_test);
+        assert_eq!(
+            options.image_paths,
+            [PathBuf::from("qr1.png"), PathBuf::from("qr2.png")]
+        );
+
+        let options = parse(&["--to-stdin", "--smoke-test", "qr1.png"]).unwrap();
+        assert!(options.smoke_test);
+        assert_eq!(options.exit_policy, ExitPolicy::AnyFailure);
+
+        let options = parse(&["--to-stdin", "--exit-on=all", "qr1.png"]).unwrap();
+        assert_eq!(options.exit_policy, ExitPolicy::AllFailures);
+        let options = parse(&["--to-stdin", "--exit-on=any", "qr1.png"]).unwrap();
+        assert_eq!(options.exit_policy, ExitPolicy::AnyFailure);
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(matches!(
+            parse(&["--to-stdout"]),
+            Err(EsimMailerError::ConfigError(_))
+        ));
+        assert!(matches!(
+            parse(&["qr1.png"]),
+            Err(EsimMailerError::ConfigError(_))
+        ));
+        assert!(matches!(
+            parse(&["--smoke-test"]),
+            Err(EsimMailerError::ConfigError(_))
+        ));
+        assert!(matches!(
+            parse(&["--to-stdin", "--exit-on=some", "qr1.png"]),
+            Err(EsimMailerError::ConfigError(_))
+        ));
+        assert!(matches!(
+            parse(&["--exit-on=all"]),
+            Err(EsimMailerError::ConfigError(_))
+        ));
+    }
+}
@@ -0,0 +1,40 @@
+use std::time::SystemTime;
+
+// Trait for getting the current time, so time-dependent code can be tested
+pub trait Clock {
+    fn now(&self) -> SystemTime;
+}
+
+// Clock implementation which reads the system time
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+// Clock implementation which always returns the same time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixedClock(pub SystemTime);
+
+impl Clock for FixedClock {
+    fn now(&self) -> SystemTime {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_fixed_clock_does_not_advance() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let clock = FixedClock(time);
+        assert_eq!(clock.now(), time);
+        assert_eq!(clock.now(), time);
+    }
+}
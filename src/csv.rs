@@ -0,0 +1,409 @@
+use crate::batch::EmailJob;
+use crate::email::parse_mailbox;
+use crate::{Args, EsimMailerError};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A problem with one row of a CSV file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsvRowError {
+    /// Line of the file the row starts on, counting from 1
+    pub line: usize,
+
+    /// What's wrong with the row
+    pub message: String,
+}
+
+/// The outcome of checking a CSV file without sending anything.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CsvValidationReport {
+    /// Number of rows checked, not counting the header
+    pub rows: usize,
+
+    /// Problems found, in the order of the rows they're in
+    pub errors: Vec<CsvRowError>,
+}
+
+impl CsvValidationReport {
+    /// Returns whether every row can be sent.
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// Checks every row of a CSV file of recipients, filled in over `args` as
+/// [`load_jobs_csv`] does, reporting each row which couldn't be sent along
+/// with its line number. Nothing is sent.
+///
+/// Only problems with the file as a whole, such as a missing `email_to`
+/// column, are returned as errors.
+pub fn validate_csv(path: &Path, args: &Args) -> Result<CsvValidationReport, EsimMailerError> {
+    let (header, records) = read_csv(path)?;
+    let mut report = CsvValidationReport::default();
+    for (line, fields) in &records {
+        report.rows += 1;
+        if let Err(message) = row_to_job(path, &header, fields, args) {
+            report.errors.push(CsvRowError {
+                line: *line,
+                message,
+            });
+        }
+    }
+    Ok(report)
+}
+
+/// Reads a CSV file of recipients into jobs, filling in each row's columns
+/// over `args`. Fails on the first row which couldn't be sent.
+///
+/// The header names the columns: `email_to` and one of `image_path` or
+/// `activation_code` are required, and `name`, `cc`, `bcc`, `data_amount`,
+/// `time_period`, `location` and `count` are optional. The count is shown in
+/// the subject, and is 1 when missing or invalid. Empty cells other than
+/// `email_to` keep the value from `args`. Image paths are relative to the CSV
+/// file.
+pub fn load_jobs_csv(path: &Path, args: &Args) -> Result<Vec<EmailJob>, EsimMailerError> {
+    let (header, records) = read_csv(path)?;
+    records
+        .iter()
+        .map(|(line, fields)| {
+            row_to_job(path, &header, fields, args).map_err(|message| {
+                EsimMailerError::ConfigError(format!(
+                    "{} line {}: {}",
+                    path.display(),
+                    line,
+                    message
+                ))
+            })
+        })
+        .collect()
+}
+
+/// The columns a CSV file of recipients may have.
+const COLUMNS: &[&str] = &[
+    "email_to",
+    "image_path",
+    "activation_code",
+    "name",
+    "cc",
+    "bcc",
+    "data_amount",
+    "time_period",
+    "location",
+    "count",
+];
+
+type Record = (usize, Vec<String>);
+
+/// Reads the header and the rows of a CSV file, checking the header.
+fn read_csv(path: &Path) -> Result<(Vec<String>, Vec<Record>), EsimMailerError> {
+    let contents = fs::read_to_string(path).map_err(|e| {
+        EsimMailerError::ConfigError(format!(
+            "Could not read CSV file '{}': {}",
+            path.display(),
+            e
+        ))
+    })?;
+    let mut records = parse_records(&contents)
+        .map_err(|e| EsimMailerError::ConfigError(format!("{}: {}", path.display(), e)))?
+        .into_iter();
+
+    let header_error =
+        |message: String| EsimMailerError::ConfigError(format!("{}: {}", path.display(), message));
+    let (_, header) = records
+        .next()
+        .ok_or_else(|| header_error("The file is empty".to_string()))?;
+    let header: Vec<String> = header
+        .iter()
+        .map(|column| column.trim().to_ascii_lowercase())
+        .collect();
+    if let Some(column) = header
+        .iter()
+        .find(|column| !COLUMNS.contains(&column.as_str()))
+    {
+        return Err(header_error(format!("Unknown column '{}'", column)));
+    }
+    if !header.iter().any(|column| column == "email_to") {
+        return Err(header_error("Missing the email_to column".to_string()));
+    }
+
+    Ok((header, records.collect()))
+}
+
+/// Turns one row into a job, describing what's wrong with it if it couldn't
+/// be sent.
+fn row_to_job(
+    path: &Path,
+    header: &[String],
+    fields: &[String],
+    args: &Args,
+) -> Result<EmailJob, String> {
+    if fields.len() != header.len() {
+        return Err(format!(
+            "Expected {} columns but found {}",
+            header.len(),
+            fields.len()
+        ));
+    }
+
+    let mut args = args.clone();
+    let mut image_path = None;
+    let mut count = 1;
+    for (column, value) in header.iter().zip(fields) {
+        let value = value.trim().to_string();
+        if column == "email_to" {
+            args.email_to = value;
+            continue;
+        }
+        // Empty cells keep the value from `args`
+        if value.is_empty() {
+            continue;
+        }
+        match column.as_str() {
+            "image_path" => image_path = Some(value),
+            "activation_code" => args.activation_code = Some(value),
+            "name" => args.name = value,
+            "cc" => args.cc = Some(value),
+            "bcc" => args.bcc = Some(value),
+            "data_amount" => args.data_amount = value,
+            "time_period" => args.time_period = value,
+            "location" => args.location = value,
+            "count" => count = value.parse().ok().filter(|count| *count > 0).unwrap_or(1),
+            _ => {}
+        }
+    }
+
+    let directory = path.parent().unwrap_or(Path::new(""));
+    checked_job(directory, args, image_path.map(PathBuf::from), count)
+}
+
+/// Checks that a job read from a file could be sent, describing what's wrong
+/// with it if not. Image paths are relative to `directory`.
+pub(crate) fn checked_job(
+    directory: &Path,
+    args: Args,
+    image_path: Option<PathBuf>,
+    count: usize,
+) -> Result<EmailJob, String> {
+    if args.email_to.is_empty() {
+        return Err("Missing the recipient's email address".to_string());
+    }
+    for address in [Some(&args.email_to), args.cc.as_ref(), args.bcc.as_ref()]
+        .into_iter()
+        .flatten()
+    {
+        parse_mailbox(address).map_err(|e| e.to_string())?;
+    }
+
+    let image_path = match image_path {
+        Some(image_path) => {
+            let image_path = directory.join(image_path);
+            if args.activation_code.is_none() && !image_path.is_file() {
+                return Err(format!("No image at '{}'", image_path.display()));
+            }
+            image_path
+        }
+        None if args.activation_code.is_some() => Default::default(),
+        None => return Err("Missing an image path or activation code".to_string()),
+    };
+
+    Ok(EmailJob {
+        args,
+        image_path,
+        count,
+    })
+}
+
+/// Splits CSV text into records of fields, each with the line it starts on.
+/// Fields may be quoted, with `""` standing for a quote. Blank lines are
+/// skipped.
+fn parse_records(text: &str) -> Result<Vec<Record>, String> {
+    let mut records = Vec::new();
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut line = 1;
+    let mut record_line = 1;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if in_quotes => in_quotes = false,
+            '"' if field.is_empty() => in_quotes = true,
+            '\n' if in_quotes => {
+                line += 1;
+                field.push(c);
+            }
+            ',' if !in_quotes => fields.push(std::mem::take(&mut field)),
+            '\r' if !in_quotes && chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                fields.push(std::mem::take(&mut field));
+                if fields.iter().any(|field| !field.is_empty()) {
+                    records.push((record_line, std::mem::take(&mut fields)));
+                }
+                fields.clear();
+                line += 1;
+                record_line = line;
+            }
+            _ => field.push(c),
+        }
+    }
+    if in_quotes {
+        return Err(format!("Unterminated quote on line {}", record_line));
+    }
+    fields.push(field);
+    if fields.iter().any(|field| !field.is_empty()) {
+        records.push((record_line, fields));
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::email::EmailTemplate;
+
+    #[test]
+    fn test_parse_records() {
+        let records = parse_records("a,b\r\n\n\"x, \"\"y\"\"\",\"multi\nline\"\nlast,").unwrap();
+        assert_eq!(
+            records,
+            vec![
+                (1, vec!["a".to_string(), "b".to_string()]),
+                (3, vec!["x, \"y\"".to_string(), "multi\nline".to_string()]),
+                (5, vec!["last".to_string(), "".to_string()]),
+            ]
+        );
+
+        assert!(parse_records("a,\"b\n").is_err());
+    }
+
+    #[test]
+    fn test_validate_csv_reports_bad_row() {
+        let dir = std::env::temp_dir();
+        let csv_path = dir.join("test_validate_csv.csv");
+        let image_path = dir.join("test_validate_csv.png");
+        fs::write(&image_path, b"fake image data").unwrap();
+        fs::write(
+            &csv_path,
+            "email_to,name,image_path\n\
+             jane@example.com,Jane,test_validate_csv.png\n\
+             not an address,Bob,test_validate_csv.png\n\
+             sam@example.com,Sam,test_validate_csv.png\n",
+        )
+        .unwrap();
+
+        let report = validate_csv(&csv_path, &Args::default());
+        let jobs = load_jobs_csv(&csv_path, &Args::default());
+
+        fs::remove_file(csv_path).unwrap();
+        fs::remove_file(image_path).unwrap();
+
+        let report = report.unwrap();
+        assert_eq!(report.rows, 3);
+        assert!(!report.is_valid());
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].line, 3);
+        assert!(report.errors[0].message.contains("not an address"));
+        assert!(matches!(jobs, Err(EsimMailerError::ConfigError(e)) if e.contains("line 3")));
+    }
+
+    #[test]
+    fn test_load_jobs_csv() {
+        let csv_path = std::env::temp_dir().join("test_load_jobs_csv.csv");
+        fs::write(
+            &csv_path,
+            "Email_To,Name,Activation_Code,Location\n\
+             jane@example.com,Jane,LPA:1$example.com$ABC,Japan\n\
+             sam@example.com,Sam,LPA:1$example.com$DEF,\n",
+        )
+        .unwrap();
+
+        let args = Args {
+            email_from: "test@gmail.com".to_string(),
+            location: "Egypt".to_string(),
+            ..Default::default()
+        };
+        let jobs = load_jobs_csv(&csv_path, &args);
+
+        fs::remove_file(csv_path).unwrap();
+
+        let jobs = jobs.unwrap();
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs[0].args.email_to, "jane@example.com");
+        assert_eq!(jobs[0].args.email_from, "test@gmail.com");
+        assert_eq!(jobs[0].args.location, "Japan");
+        assert_eq!(jobs[1].args.location, "Egypt");
+        assert_eq!(jobs[1].count, 1);
+    }
+
+    #[test]
+    fn test_load_jobs_csv_counts() {
+        let csv_path = std::env::temp_dir().join("test_load_jobs_csv_counts.csv");
+        fs::write(
+            &csv_path,
+            "email_to,activation_code,count\n\
+             jane@example.com,LPA:1$example.com$ABC,3\n\
+             sam@example.com,LPA:1$example.com$DEF,\n\
+             kim@example.com,LPA:1$example.com$GHI,lots\n",
+        )
+        .unwrap();
+
+        let args = Args {
+            provider: "Gmail".to_string(),
+            location: "Japan".to_string(),
+            ..Default::default()
+        };
+        let jobs = load_jobs_csv(&csv_path, &args);
+
+        fs::remove_file(csv_path).unwrap();
+
+        let template = EmailTemplate::new();
+        let subjects: Vec<String> = jobs
+            .unwrap()
+            .iter()
+            .map(|job| template.subject(&job.args, job.count))
+            .collect();
+        assert_eq!(
+            subjects,
+            vec![
+                "[Gmail] Japan eSIM - 3",
+                "[Gmail] Japan eSIM - 1",
+                "[Gmail] Japan eSIM - 1"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_csv_missing_column() {
+        let csv_path = std::env::temp_dir().join("test_validate_csv_header.csv");
+        fs::write(&csv_path, "name,image_path\nJane,qr.png\n").unwrap();
+
+        let result = validate_csv(&csv_path, &Args::default());
+
+        fs::remove_file(csv_path).unwrap();
+
+        assert!(matches!(result, Err(EsimMailerError::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_validate_csv_uses_args() {
+        let csv_path = std::env::temp_dir().join("test_validate_csv_args.csv");
+        fs::write(&csv_path, "email_to,name\njane@example.com,Jane\n").unwrap();
+
+        let without_code = validate_csv(&csv_path, &Args::default());
+        let args = Args {
+            activation_code: Some("LPA:1$smdp.example.com$ABC123".to_string()),
+            ..Default::default()
+        };
+        let with_code = validate_csv(&csv_path, &args);
+
+        fs::remove_file(csv_path).unwrap();
+
+        assert!(!without_code.unwrap().is_valid());
+        assert!(with_code.unwrap().is_valid());
+    }
+}
@@ -0,0 +1,138 @@
+use crate::EsimMailerError;
+use crate::error::ErrorCategory;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::thread;
+use std::time::Duration;
+
+/// The delay before the first retry of a failed lookup, doubled after each
+/// further failure, unless another is configured.
+pub const DEFAULT_DNS_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+// Trait for looking up a mail server's addresses
+pub trait Resolver {
+    fn resolve(&self, host: &str, port: u16) -> Result<(), EsimMailerError>;
+}
+
+/// Looks up addresses with the operating system's resolver.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> Result<(), EsimMailerError> {
+        (host, port)
+            .to_socket_addrs()
+            .map(|_| ())
+            .map_err(|e| lookup_error(host, e))
+    }
+}
+
+/// Turns a failed lookup into an error, telling hosts which don't exist
+/// apart from lookups which may succeed later. The operating system only
+/// reports the difference in its message.
+fn lookup_error(host: &str, e: io::Error) -> EsimMailerError {
+    let message = e.to_string();
+    let not_found = [
+        // glibc and musl
+        "Name or service not known",
+        "No address associated with hostname",
+        // macOS and the BSDs
+        "nodename nor servname provided",
+        // Windows
+        "No such host is known",
+    ];
+    if not_found.iter().any(|marker| message.contains(marker)) {
+        EsimMailerError::ConfigError(format!("Mail server '{}' doesn't exist: {}", host, message))
+    } else {
+        EsimMailerError::NetworkError(format!("Could not look up '{}': {}", host, message))
+    }
+}
+
+/// Looks up `host`, retrying up to `retries` times if the lookup fails in a
+/// way which may succeed later. The delay starts at `delay` and doubles
+/// after each attempt. Hosts which don't exist aren't retried.
+pub fn resolve_with_retry(
+    resolver: &dyn Resolver,
+    host: &str,
+    port: u16,
+    retries: usize,
+    mut delay: Duration,
+) -> Result<(), EsimMailerError> {
+    let mut attempt = 0;
+    loop {
+        match resolver.resolve(host, port) {
+            Err(e) if attempt < retries && e.category() == ErrorCategory::Network => {
+                eprintln!("Looking up {} failed, retrying: {}", host, e);
+                attempt += 1;
+                thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    /// Fails each lookup with the next of `failures`, then succeeds.
+    struct MockResolver {
+        failures: Vec<&'static str>,
+        calls: Cell<usize>,
+    }
+
+    impl Resolver for MockResolver {
+        fn resolve(&self, host: &str, _port: u16) -> Result<(), EsimMailerError> {
+            let call = self.calls.get();
+            self.calls.set(call + 1);
+            match self.failures.get(call) {
+                Some(message) => Err(lookup_error(host, io::Error::other(*message))),
+                None => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_transient_failure_is_retried() {
+        let resolver = MockResolver {
+            failures: vec![
+                "failed to lookup address information: Temporary failure in name resolution",
+                "failed to lookup address information: Try again",
+            ],
+            calls: Cell::new(0),
+        };
+
+        let result = resolve_with_retry(&resolver, "smtp.gmail.com", 587, 3, Duration::ZERO);
+
+        assert!(result.is_ok());
+        assert_eq!(resolver.calls.get(), 3);
+    }
+
+    #[test]
+    fn test_missing_host_is_not_retried() {
+        let resolver = MockResolver {
+            failures: vec!["failed to lookup address information: Name or service not known"],
+            calls: Cell::new(0),
+        };
+
+        let result = resolve_with_retry(&resolver, "smtp.typo.invalid", 587, 3, Duration::ZERO);
+
+        assert!(matches!(result, Err(EsimMailerError::ConfigError(_))));
+        assert_eq!(resolver.calls.get(), 1);
+    }
+
+    #[test]
+    fn test_retries_run_out() {
+        let resolver = MockResolver {
+            failures: vec!["Temporary failure in name resolution"; 5],
+            calls: Cell::new(0),
+        };
+
+        let result = resolve_with_retry(&resolver, "smtp.gmail.com", 587, 2, Duration::ZERO);
+
+        assert!(matches!(result, Err(EsimMailerError::NetworkError(_))));
+        assert_eq!(resolver.calls.get(), 3);
+    }
+}
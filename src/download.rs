@@ -0,0 +1,194 @@
+use crate::{Args, EsimMailerError};
+use oauth2::reqwest::blocking::Client;
+use std::io::Read;
+use std::time::Duration;
+use url::Url;
+
+/// The largest image which will be downloaded, in bytes.
+pub const MAX_DOWNLOAD_BYTES: u64 = 5 * 1024 * 1024;
+
+/// How long a download may take before giving up.
+pub const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Downloads the QR code image from `args.image_url`, which must be an http
+/// or https URL. Downloads must be allowed with `args.allow_image_download`,
+/// since they make sending depend on another server.
+pub fn download_image(args: &Args) -> Result<Vec<u8>, EsimMailerError> {
+    let url = args.image_url.as_deref().unwrap_or_default();
+    if !args.allow_image_download {
+        return Err(EsimMailerError::ConfigError(format!(
+            "Downloading the image from {} isn't allowed",
+            url
+        )));
+    }
+    let parsed = Url::parse(url)
+        .ok()
+        .filter(|parsed| matches!(parsed.scheme(), "http" | "https"))
+        .ok_or_else(|| {
+            EsimMailerError::ConfigError(format!("Image URL '{}' must use http or https", url))
+        })?;
+
+    let network_error =
+        |problem: String| EsimMailerError::NetworkError(format!("{}: {}", url, problem));
+    let client = Client::builder()
+        .timeout(DOWNLOAD_TIMEOUT)
+        .build()
+        .map_err(|e| network_error(e.to_string()))?;
+    let response = client
+        .get(parsed)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| network_error(e.to_string()))?;
+    if response
+        .content_length()
+        .is_some_and(|length| length > MAX_DOWNLOAD_BYTES)
+    {
+        return Err(too_large(url));
+    }
+
+    let mut data = Vec::new();
+    response
+        .take(MAX_DOWNLOAD_BYTES + 1)
+        .read_to_end(&mut data)
+        .map_err(|e| network_error(e.to_string()))?;
+    if data.len() as u64 > MAX_DOWNLOAD_BYTES {
+        return Err(too_large(url));
+    }
+    Ok(data)
+}
+
+fn too_large(url: &str) -> EsimMailerError {
+    EsimMailerError::MessageError(format!(
+        "The image at {} is larger than {} bytes",
+        url, MAX_DOWNLOAD_BYTES
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorCategory;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Starts a server which answers one request with `status` and `body`.
+    /// Returns the URL of the image it serves.
+    fn image_server(status: &'static str, body: &'static [u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap_or(0) > 2 {
+                line.clear();
+            }
+            let headers = format!(
+                "HTTP/1.1 {}\r\nContent-Type: image/png\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                status,
+                body.len()
+            );
+            stream.write_all(headers.as_bytes()).unwrap();
+            // The client stops reading bodies which are too large
+            let _ = stream.write_all(body);
+        });
+        format!("http://127.0.0.1:{}/qr.png", port)
+    }
+
+    #[test]
+    fn test_download_image() {
+        let args = Args {
+            image_url: Some(image_server("200 OK", b"\x89PNG\r\n\x1a\nfake")),
+            allow_image_download: true,
+            ..Default::default()
+        };
+        assert_eq!(download_image(&args).unwrap(), b"\x89PNG\r\n\x1a\nfake");
+
+        let args = Args {
+            image_url: Some(image_server("404 Not Found", b"")),
+            allow_image_download: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            download_image(&args),
+            Err(EsimMailerError::NetworkError(_))
+        ));
+    }
+
+    #[test]
+    fn test_download_image_too_large() {
+        let body = vec![0; MAX_DOWNLOAD_BYTES as usize + 1].leak();
+        let args = Args {
+            image_url: Some(image_server("200 OK", body)),
+            allow_image_download: true,
+            ..Default::default()
+        };
+        let error = download_image(&args).unwrap_err();
+        assert!(matches!(error, EsimMailerError::MessageError(_)));
+        assert_eq!(error.category(), ErrorCategory::Permanent);
+    }
+
+    #[test]
+    fn test_build_email_embeds_downloaded_image() -> Result<(), EsimMailerError> {
+        let args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            image_url: Some(image_server("200 OK", b"\x89PNG\r\n\x1a\ndownloaded")),
+            allow_image_download: true,
+            ..Default::default()
+        };
+        let image_path = std::env::temp_dir().join("test_image_not_downloaded.png");
+        let message = crate::email::build_email(&args, &image_path, 1)?;
+        let message = String::from_utf8(message.formatted()).unwrap();
+
+        assert!(message.contains("Content-Type: image/png"));
+        // Base64 of the PNG signature followed by "downloaded"
+        assert!(message.contains("iVBORw0KGgpkb3dubG9hZGVk"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_guardian_copy_downloads_image_once() -> Result<(), EsimMailerError> {
+        // The server only answers one request, so a second download would fail
+        let args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            guardian_email: Some("guardian@example.com".to_string()),
+            image_url: Some(image_server("200 OK", b"\x89PNG\r\n\x1a\ndownloaded")),
+            allow_image_download: true,
+            ..Default::default()
+        };
+        let image_path = std::env::temp_dir().join("test_image_not_downloaded.png");
+        let emails = crate::email::build_emails(&args, &image_path, 1)?;
+
+        assert_eq!(emails.len(), 2);
+        for (_, message) in emails {
+            let message = String::from_utf8(message.formatted()).unwrap();
+            assert!(message.contains("iVBORw0KGgpkb3dubG9hZGVk"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_download_image_must_be_allowed() {
+        let args = Args {
+            image_url: Some("https://cdn.example.com/qr.png".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            download_image(&args),
+            Err(EsimMailerError::ConfigError(_))
+        ));
+
+        let args = Args {
+            image_url: Some("file:///etc/passwd".to_string()),
+            allow_image_download: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            download_image(&args),
+            Err(EsimMailerError::ConfigError(_))
+        ));
+    }
+}
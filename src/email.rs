@@ -1,13 +1,51 @@
-use crate::Args;
-use lettre::message::header;
+use crate::args::{Charset, Direction, ImageDisposition, InvalidBccPolicy};
+use crate::args::{DEFAULT_GUARDIAN_NOTE, DEFAULT_LOCATION_FALLBACK, split_name_and_address};
+use crate::clock::{Clock, FixedClock, SystemClock};
+use crate::dns::{self, SystemResolver};
+use crate::download::download_image;
+use crate::error::ErrorCategory;
+use crate::lpa::validate_lpa;
+use crate::markdown;
+#[cfg(feature = "async")]
+use crate::proxy;
+use crate::proxy::ProxyConfig;
+use crate::rate_limit;
+use crate::registry::{CustomProvider, ProviderRegistry};
+use crate::retry::RetryPolicy;
+use crate::token::{TokenKind, classify_token, resolve_token};
+use crate::{Args, CheckPolicy, EsimMailerError, RecipientRole, TlsVersion};
+use base64::prelude::{BASE64_STANDARD, Engine as _};
+use lettre::Address;
+use lettre::address::Envelope;
+use lettre::message::header::{self, Header, HeaderName, HeaderValue, Headers};
+use lettre::message::{Mailbox, Mailboxes};
 use lettre::transport::smtp::authentication::{Credentials, Mechanism};
-use lettre::{Message, SmtpTransport, Transport};
+use lettre::transport::smtp::client::{self, SmtpConnection, Tls, TlsParameters};
+#[cfg(feature = "async")]
+use lettre::transport::smtp::client::{AsyncSmtpConnection, AsyncTokioStream};
+use lettre::transport::smtp::commands::{Data, Ehlo, Mail, Rcpt};
+use lettre::transport::smtp::extension::{ClientId, MailParameter, RcptParameter};
+use lettre::transport::smtp::response::Response;
+use lettre::transport::smtp::{self, SmtpTransport};
+#[cfg(feature = "async")]
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor, transport::smtp::PoolConfig};
+use lettre::{Message, Transport};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
 use std::fmt::Display;
 use std::fs;
 use std::io;
-use std::path::Path;
+#[cfg(feature = "async")]
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "async")]
+use std::pin::Pin;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "async")]
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
 use uuid;
 
 /// An error which can be returned when parsing a provider from an email address.
@@ -20,16 +58,225 @@ pub struct ParseProviderError(String);
 pub enum Provider {
     Gmail,
     Outlook,
+    Fastmail,
+    Zoho,
+    Gmx,
+    /// A provider added through the [`ProviderRegistry`].
+    Custom(Arc<CustomProvider>),
+}
+
+/// How the connection to an SMTP server is secured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsMode {
+    /// Connect in plaintext and upgrade with STARTTLS.
+    StartTls,
+    /// Connect over TLS from the start.
+    Implicit,
+}
+
+/// The SMTP connection settings used for a provider.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub tls: TlsMode,
+    pub mechanism: Mechanism,
+}
+
+impl Provider {
+    /// Returns the SMTP connection settings for this provider.
+    pub fn smtp_config(&self) -> SmtpConfig {
+        match self {
+            Self::Gmail => SmtpConfig {
+                host: "smtp.gmail.com".to_string(),
+                port: 587,
+                tls: TlsMode::StartTls,
+                mechanism: Mechanism::Xoauth2,
+            },
+            Self::Outlook => SmtpConfig {
+                host: "smtp-mail.outlook.com".to_string(),
+                port: 587,
+                tls: TlsMode::StartTls,
+                mechanism: Mechanism::Xoauth2,
+            },
+            // Fastmail only supports app passwords for SMTP.
+            Self::Fastmail => SmtpConfig {
+                host: "smtp.fastmail.com".to_string(),
+                port: 465,
+                tls: TlsMode::Implicit,
+                mechanism: Mechanism::Plain,
+            },
+            // Zoho also only supports app passwords for SMTP.
+            Self::Zoho => SmtpConfig {
+                host: "smtp.zoho.com".to_string(),
+                port: 587,
+                tls: TlsMode::StartTls,
+                mechanism: Mechanism::Login,
+            },
+            // GMX and Web.de share servers and need SMTP access turned on in
+            // the account settings.
+            Self::Gmx => SmtpConfig {
+                host: "mail.gmx.net".to_string(),
+                port: 587,
+                tls: TlsMode::StartTls,
+                mechanism: Mechanism::Login,
+            },
+            Self::Custom(custom) => SmtpConfig {
+                host: custom.host.clone(),
+                port: custom.port,
+                tls: custom.tls,
+                mechanism: custom.mechanism,
+            },
+        }
+    }
+
+    /// Returns whether this provider needs an OAuth token to send through
+    /// SMTP. The others need an app password instead.
+    pub fn requires_oauth(&self) -> bool {
+        match self {
+            Self::Gmail | Self::Outlook => true,
+            Self::Fastmail | Self::Zoho | Self::Gmx | Self::Custom(_) => false,
+        }
+    }
+
+    /// Returns guidance on getting the credentials this provider needs to
+    /// send through SMTP.
+    pub fn setup_instructions(&self) -> &'static str {
+        match self {
+            Self::Gmail => {
+                "Sign in with Google when asked to allow sending with OAuth. If your account \
+                 has 2-Step Verification, you can instead create an app password at \
+                 https://myaccount.google.com/apppasswords and enter it in \
+                 the App Password field or the ESIM_MAILER_TOKEN environment variable."
+            }
+            Self::Outlook => {
+                "Sign in with Microsoft when asked to allow sending with OAuth. SMTP AUTH must \
+                 be enabled for the mailbox, which an administrator may need to turn on."
+            }
+            Self::Fastmail => {
+                "Create an app password with SMTP access under Settings > Privacy & Security > \
+                 Manage app passwords, and enter it in the App Password field or the \
+                 ESIM_MAILER_TOKEN environment variable."
+            }
+            Self::Zoho => {
+                "Create an app password under My Account > Security > App Passwords, and \
+                 enter it in the App Password field or the ESIM_MAILER_TOKEN environment \
+                 variable."
+            }
+            Self::Gmx => {
+                "Enable POP3/IMAP and SMTP access in the mail settings, then enter your account \
+                 password, or an app password if you use two-factor authentication, in \
+                 the App Password field or the ESIM_MAILER_TOKEN environment variable."
+            }
+            Self::Custom(_) => {
+                "Enter the password or app password for your account with this provider in \
+                 the App Password field or the ESIM_MAILER_TOKEN environment variable."
+            }
+        }
+    }
+
+    /// Returns the largest QR code image to send through this provider before
+    /// shrinking it. Outlook is stricter about large messages than the others.
+    pub fn max_image_bytes(&self) -> usize {
+        match self {
+            Self::Outlook => 256 * 1024,
+            Self::Gmail | Self::Fastmail | Self::Zoho | Self::Gmx | Self::Custom(_) => 1024 * 1024,
+        }
+    }
+
+    /// Returns the SMTP connection settings for this provider, using the port
+    /// from `port_overrides` if one is set for it. Provider names are matched
+    /// case-insensitively.
+    pub fn smtp_config_with_overrides(&self, port_overrides: &HashMap<String, u16>) -> SmtpConfig {
+        let config = self.smtp_config();
+        match self.setting(port_overrides) {
+            Some(&port) => config.with_port(port),
+            None => config,
+        }
+    }
+
+    /// Returns the settings a transport for this provider is built with, once
+    /// `port_overrides` and the kind of token are taken into account.
+    pub fn transport_config(
+        &self,
+        port_overrides: &HashMap<String, u16>,
+        token: &str,
+    ) -> SmtpConfig {
+        self.smtp_config_with_overrides(port_overrides)
+            .for_token(token)
+    }
+
+    /// Returns this provider's entry in a map keyed by provider name, matching
+    /// names case-insensitively.
+    pub fn setting<'a, T>(&self, settings: &'a HashMap<String, T>) -> Option<&'a T> {
+        let name = self.to_string();
+        settings
+            .iter()
+            .find(|(provider, _)| provider.eq_ignore_ascii_case(&name))
+            .map(|(_, setting)| setting)
+    }
+}
+
+impl SmtpConfig {
+    /// Uses LOGIN instead of XOAUTH2 when the token looks like an app
+    /// password, which Gmail and Outlook also accept.
+    pub fn for_token(self, token: &str) -> Self {
+        match (self.mechanism, classify_token(token)) {
+            (Mechanism::Xoauth2, TokenKind::AppPassword) => Self {
+                mechanism: Mechanism::Login,
+                ..self
+            },
+            _ => self,
+        }
+    }
+
+    /// Uses a different port, with implicit TLS on port 465 and STARTTLS on
+    /// any other port.
+    pub fn with_port(self, port: u16) -> Self {
+        let tls = if port == 465 {
+            TlsMode::Implicit
+        } else {
+            TlsMode::StartTls
+        };
+        Self { port, tls, ..self }
+    }
+}
+
+/// Returns the email address with its domain lowercased, since domains are
+/// case-insensitive. The local part is left as it is.
+pub fn canonical_email(email: &str) -> String {
+    match email.rsplit_once('@') {
+        Some((local, domain)) => format!("{}@{}", local, domain.to_ascii_lowercase()),
+        None => email.to_string(),
+    }
 }
 
 impl FromStr for Provider {
     type Err = ParseProviderError;
 
+    /// Finds the provider for an email address's domain, checking the
+    /// built-in providers before those in the [`ProviderRegistry`]. A single
+    /// trailing dot, which makes a domain fully qualified, is ignored.
     fn from_str(email: &str) -> Result<Self, Self::Err> {
-        match email.rsplit_once('@') {
-            Some((_, "gmail.com")) => Ok(Self::Gmail),
-            Some((_, "outlook.com" | "hotmail.com")) => Ok(Self::Outlook),
-            _ => Err(ParseProviderError(email.to_string())),
+        let canonical = canonical_email(email);
+        canonical
+            .rsplit_once('@')
+            .map(|(_, domain)| domain.strip_suffix('.').unwrap_or(domain))
+            .and_then(|domain| ProviderRegistry::global().lookup(domain))
+            .ok_or_else(|| ParseProviderError(email.to_string()))
+    }
+}
+
+impl Provider {
+    /// Returns the built-in provider for a lowercase domain, if there is one.
+    pub(crate) fn builtin(domain: &str) -> Option<Self> {
+        match domain {
+            "gmail.com" => Some(Self::Gmail),
+            "outlook.com" | "hotmail.com" => Some(Self::Outlook),
+            "fastmail.com" | "fastmail.fm" | "messagingengine.com" => Some(Self::Fastmail),
+            "zoho.com" | "zohomail.com" => Some(Self::Zoho),
+            "gmx.com" | "gmx.net" | "web.de" => Some(Self::Gmx),
+            _ => None,
         }
     }
 }
@@ -39,15 +286,48 @@ impl Display for Provider {
         match self {
             Self::Gmail => write!(f, "Gmail"),
             Self::Outlook => write!(f, "Outlook"),
+            Self::Fastmail => write!(f, "Fastmail"),
+            Self::Zoho => write!(f, "Zoho"),
+            Self::Gmx => write!(f, "GMX"),
+            Self::Custom(custom) => write!(f, "{}", custom.name),
         }
     }
 }
 
+/// Known disposable email domains, one per line.
+const DISPOSABLE_DOMAINS: &str = include_str!("../data/disposable_domains.txt");
+
+/// Returns whether the domain belongs to a known disposable email service.
+pub fn is_disposable_domain(domain: &str) -> bool {
+    DISPOSABLE_DOMAINS
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .any(|disposable| disposable.eq_ignore_ascii_case(domain))
+}
+
+/// The width and height, in pixels, of the QR code image in the body.
+pub const DEFAULT_QR_SIZE: u32 = 200;
+
+/// Subject templates for languages other than English, keyed by language code.
+const LOCALIZED_SUBJECT_TEMPLATES: &[(&str, &str)] = &[
+    ("de", "[{{provider}}] eSIM für {{location}}"),
+    ("es", "[{{provider}}] eSIM para {{location}}"),
+    ("fr", "[{{provider}}] eSIM pour {{location}}"),
+];
+
+#[derive(Clone)]
 pub struct EmailTemplate {
-    subject_template: &'static str,
-    body_template: &'static str,
+    subject_template: String,
+    localized_subject_templates: &'static [(&'static str, &'static str)],
+    body_template: String,
 }
 
+/// Templates loaded from files, keyed by the path they were loaded from,
+/// along with when the file was last modified.
+static TEMPLATE_CACHE: Mutex<BTreeMap<PathBuf, (Option<SystemTime>, EmailTemplate)>> =
+    Mutex::new(BTreeMap::new());
+
 impl Default for EmailTemplate {
     fn default() -> Self {
         Self::new()
@@ -57,255 +337,5878 @@ impl Default for EmailTemplate {
 impl EmailTemplate {
     pub fn new() -> Self {
         Self {
-            subject_template: "[{{provider}}] {{location}} eSIM",
-            body_template: include_str!("../templates/email_template.html"),
+            subject_template: "[{{provider}}] {{location}} eSIM".to_string(),
+            localized_subject_templates: LOCALIZED_SUBJECT_TEMPLATES,
+            body_template: include_str!("../templates/email_template.html").to_string(),
+        }
+    }
+
+    /// Returns the template for emails with the QR code as a regular
+    /// attachment, whose body doesn't show the image.
+    pub fn attachment() -> Self {
+        Self {
+            body_template: include_str!("../templates/email_template_attachment.html").to_string(),
+            ..Self::new()
+        }
+    }
+
+    /// Loads a template from a file whose body may be preceded by frontmatter
+    /// setting the subject:
+    ///
+    /// ```text
+    /// ---
+    /// subject: Your {{location}} eSIM from {{provider}}
+    /// ---
+    /// <html>...
+    /// ```
+    ///
+    /// A subject set this way isn't translated.
+    pub fn from_file(path: &Path) -> Result<Self, EsimMailerError> {
+        let contents = fs::read_to_string(path)?;
+        let (frontmatter, body) = split_frontmatter(&contents)?;
+        if body.trim().is_empty() {
+            return Err(EsimMailerError::TemplateError(format!(
+                "The body in {} is empty",
+                path.display()
+            )));
+        }
+
+        let mut template = Self {
+            body_template: body.to_string(),
+            ..Self::new()
+        };
+        for (key, value) in frontmatter {
+            match key {
+                "subject" => {
+                    template.subject_template = value.to_string();
+                    template.localized_subject_templates = &[];
+                }
+                _ => {
+                    return Err(EsimMailerError::TemplateError(format!(
+                        "Unknown frontmatter key '{}' in {}",
+                        key,
+                        path.display()
+                    )));
+                }
+            }
         }
+        Ok(template)
     }
 
+    /// Loads a template with [`EmailTemplate::from_file`] the first time it's
+    /// asked for, then returns the same template until the file is modified.
+    /// If the file disappears the last template read from it is kept, so a
+    /// long batch doesn't fail part way through.
+    pub fn cached(path: &Path) -> Result<Self, EsimMailerError> {
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+        let mut cache = TEMPLATE_CACHE
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some((cached_modified, template)) = cache.get(path)
+            && (modified.is_none() || modified == *cached_modified)
+        {
+            return Ok(template.clone());
+        }
+        let template = Self::from_file(path)?;
+        cache.insert(path.to_path_buf(), (modified, template.clone()));
+        Ok(template)
+    }
+
+    /// Returns the template file set in `args`, or the built-in template
+    /// matching how `args` sends the QR code.
+    pub fn load(args: &Args) -> Result<Self, EsimMailerError> {
+        match &args.template_file {
+            Some(path) => Self::cached(path),
+            None => Ok(Self::for_args(args)),
+        }
+    }
+
+    /// Returns the template matching how `args` sends the QR code.
+    pub fn for_args(args: &Args) -> Self {
+        if args.attach_qr {
+            Self::attachment()
+        } else {
+            Self::new()
+        }
+    }
+
+    /// Renders the subject, ending with ` - {count}` unless `count` is 0.
+    /// Subjects longer than `args.truncate_subject` are cut short with `…`.
     pub fn subject(&self, args: &Args, count: usize) -> String {
-        let subject = self
-            .subject_template
-            .replace("{{provider}}", &args.provider)
-            .replace("{{location}}", &args.location);
-        format!("{} - {}", subject, count)
+        let mut subject = render(self.subject_template_for(args), &self.variables(args));
+        if count != 0 {
+            subject = format!("{} - {}", subject, count);
+        }
+        match args.truncate_subject {
+            Some(max_chars) => truncate_with_ellipsis(subject, max_chars),
+            None => subject,
+        }
     }
 
-    pub fn body(&self, args: &Args) -> String {
-        self.body_template
-            .replace("{{provider}}", &args.provider)
-            .replace("{{name}}", &args.name)
-            .replace("{{data_amount}}", &args.data_amount)
-            .replace("{{time_period}}", &args.time_period)
-            .replace("{{location}}", &args.location)
+    /// Returns the subject template for the requested language, falling back
+    /// to English. Regional variants such as "de-AT" use their base language.
+    fn subject_template_for(&self, args: &Args) -> &str {
+        args.language
+            .as_deref()
+            .and_then(|language| self.localized_subject_template(language))
+            .unwrap_or(&self.subject_template)
     }
-}
 
-pub fn send_email(args: &Args, token: String, image_path: &Path, count: usize) -> io::Result<()> {
-    let email_from = &args.email_from;
-    let email_to = &args.email_to;
+    fn localized_subject_template(&self, language: &str) -> Option<&'static str> {
+        let base_language = language.split(['-', '_']).next().unwrap_or(language);
+        self.localized_subject_templates
+            .iter()
+            .find(|(code, _)| code.eq_ignore_ascii_case(base_language))
+            .map(|(_, template)| *template)
+    }
 
-    // Get template content
-    let template = EmailTemplate::new();
+    /// Returns the codes of the languages with their own templates, starting
+    /// with English.
+    pub fn languages(&self) -> Vec<&'static str> {
+        let localized = self
+            .localized_subject_templates
+            .iter()
+            .map(|(code, _)| *code);
+        std::iter::once("en").chain(localized).collect()
+    }
 
-    // Read image file
-    let image_data = fs::read(image_path)?;
+    /// Renders the subject, without a count, and the body in the given
+    /// language, for reviewing translations.
+    ///
+    /// Languages without their own templates fall back to English unless
+    /// `strict` is set, in which case they're an error.
+    pub fn render_language(
+        &self,
+        language: &str,
+        args: &Args,
+        strict: bool,
+    ) -> Result<(String, String), EsimMailerError> {
+        let is_english = language
+            .split(['-', '_'])
+            .next()
+            .is_some_and(|base| base.eq_ignore_ascii_case("en"));
+        if strict && !is_english && self.localized_subject_template(language).is_none() {
+            return Err(EsimMailerError::TemplateError(format!(
+                "No templates for language '{}'",
+                language
+            )));
+        }
 
-    // Get subject and body content
-    let subject = template.subject(args, count);
-    // Generate a unique Content-ID for the image
-    let content_id = format!("qr_image_cid@{}", uuid::Uuid::new_v4());
+        let args = Args {
+            language: Some(language.to_string()),
+            ..args.clone()
+        };
+        Ok((self.subject(&args, 0), self.body(&args)))
+    }
 
-    // Get the body content and replace the QR_CID placeholder with the actual Content-ID
-    let body_content = template.body(args);
-    let body = body_content.replace("{{QR_CID}}", &content_id);
+    /// Renders the body, starting with a hidden preheader which inboxes show
+    /// as preview text after the subject. An empty preheader is left out.
+    pub fn body(&self, args: &Args) -> String {
+        self.body_at(args, &SystemClock)
+    }
 
-    // Create multipart email with HTML body and image attachment
-    let mut email_builder = Message::builder()
-        .from(
-            email_from
-                .parse()
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
-        )
-        .to(email_to
-            .parse()
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?)
-        .subject(subject);
-
-    // Add BCC if provided and not empty
-    if let Some(bcc) = &args.bcc {
-        if !bcc.is_empty() {
-            email_builder = email_builder.bcc(
-                bcc.parse()
-                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
-            );
+    /// Renders the body as [`EmailTemplate::body`] does, with the time-based
+    /// `{{greeting}}` taken from `clock`.
+    pub fn body_at(&self, args: &Args, clock: &dyn Clock) -> String {
+        let variables = self.variables_at(args, clock);
+        let body = render(&self.body_template, &variables);
+        let preheader = match (&args.preheader, args.location.trim()) {
+            (Some(preheader), _) => render(preheader, &variables),
+            (None, "") => "Your eSIM is ready".to_string(),
+            (None, location) => format!("Your {} eSIM is ready", location),
+        };
+        if preheader.is_empty() {
+            return body;
         }
+        with_preheader(&body, &preheader)
     }
 
-    // Build the email with multipart/related content
-    let email = email_builder
-        .multipart(
-            lettre::message::MultiPart::related()
-                .singlepart(
-                    lettre::message::SinglePart::builder()
-                        .header(header::ContentType::TEXT_HTML)
-                        .body(body),
-                )
-                .singlepart(
-                    lettre::message::Attachment::new_inline(content_id)
-                        .body(image_data, header::ContentType::parse("image/png").unwrap()),
-                ),
-        )
-        .unwrap();
+    /// Expands `{{> name}}` includes in the body with the matching partials.
+    pub fn with_partials(
+        mut self,
+        partials: &HashMap<String, String>,
+    ) -> Result<Self, EsimMailerError> {
+        self.body_template = expand_partials(&self.body_template, partials, 0)?;
+        Ok(self)
+    }
 
-    // Configure SMTP client with TLS
-    let provider: Provider = email_from
-        .parse()
-        // TODO: Ideally this wouldn't get mapped to an io::Error, but right now
-        // the function signature requires it.
-        .map_err(|_| io::Error::other("Unsupported email provider"))?;
-    let mailer = configure_mailer(&provider, email_from, token)?;
-
-    // Send the email
-    match mailer.send(&email) {
-        Ok(_) => {
-            println!("Email sent successfully!");
-            Ok(())
+    /// Checks that the body is well-formed HTML, with every tag closed and the
+    /// `{{QR_CID}}` placeholder only used in an `<img>` tag's `src`.
+    ///
+    /// This is a lightweight check for common mistakes, not a full HTML parser.
+    pub fn validate_html(&self) -> Result<(), EsimMailerError> {
+        check_html(&self.body_template)
+    }
+
+    /// Checks that the body has something other than whitespace in it, so a
+    /// blank email isn't sent.
+    pub fn validate_not_empty(&self) -> Result<(), EsimMailerError> {
+        check_body_not_empty(&self.body_template)
+    }
+
+    /// Checks that the body uses the `{{QR_CID}}` placeholder, without which
+    /// the inline QR code image wouldn't be shown.
+    pub fn validate_qr_reference(&self) -> Result<(), EsimMailerError> {
+        check_qr_referenced(&self.body_template)
+    }
+
+    /// Returns every placeholder name along with the value it will be
+    /// replaced with when rendering.
+    pub fn variables(&self, args: &Args) -> BTreeMap<String, String> {
+        self.variables_at(args, &SystemClock)
+    }
+
+    /// Returns the placeholder values as [`EmailTemplate::variables`] does,
+    /// with the time-based `{{greeting}}` taken from `clock`.
+    pub fn variables_at(&self, args: &Args, clock: &dyn Clock) -> BTreeMap<String, String> {
+        let guardian_note = match args.recipient_role {
+            RecipientRole::Primary => String::new(),
+            RecipientRole::Guardian => args
+                .guardian_note
+                .clone()
+                .unwrap_or_else(|| DEFAULT_GUARDIAN_NOTE.to_string()),
+        };
+        let qr_width = args.qr_width.unwrap_or(DEFAULT_QR_SIZE);
+        let qr_height = args.qr_height.unwrap_or(qr_width);
+        let location = match args.location.trim() {
+            "" => args
+                .location_fallback
+                .clone()
+                .unwrap_or_else(|| DEFAULT_LOCATION_FALLBACK.to_string()),
+            location => location.to_string(),
+        };
+        let smdp_address = args
+            .activation_code
+            .as_deref()
+            .and_then(|activation_code| validate_lpa(activation_code).ok())
+            .map(|components| components.smdp_address)
+            .unwrap_or_default();
+        BTreeMap::from([
+            ("provider".to_string(), args.provider.clone()),
+            ("name".to_string(), args.name.clone()),
+            ("data_amount".to_string(), args.data_amount.clone()),
+            ("time_period".to_string(), args.time_period.clone()),
+            ("location".to_string(), location),
+            ("guardian_note".to_string(), guardian_note),
+            ("qr_width".to_string(), qr_width.to_string()),
+            ("qr_height".to_string(), qr_height.to_string()),
+            ("support_phone".to_string(), support_phone_link(args)),
+            ("smdp_address".to_string(), smdp_address),
+            (
+                "dir".to_string(),
+                direction_attribute(args.text_direction.unwrap_or_default()).to_string(),
+            ),
+            ("greeting".to_string(), greeting(args, clock).to_string()),
+        ])
+    }
+}
+
+/// Returns "Good morning", "Good afternoon" or "Good evening" for the time of
+/// day in the recipient's timezone, which is UTC unless
+/// `utc_offset_minutes` is given.
+fn greeting(args: &Args, clock: &dyn Clock) -> &'static str {
+    let now = clock
+        .now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs() as i64);
+    let local = now + i64::from(args.utc_offset_minutes.unwrap_or(0)) * 60;
+    match local.rem_euclid(86_400) / 3_600 {
+        0..12 => "Good morning",
+        12..18 => "Good afternoon",
+        _ => "Good evening",
+    }
+}
+
+/// Returns the support phone number as a `tel:` link, or an empty string if
+/// there isn't one. The link keeps only the digits and a leading `+`.
+fn support_phone_link(args: &Args) -> String {
+    let Some(phone) = args
+        .support_phone
+        .as_deref()
+        .map(str::trim)
+        .filter(|phone| !phone.is_empty())
+    else {
+        return String::new();
+    };
+    let number: String = phone
+        .char_indices()
+        .filter(|(index, c)| c.is_ascii_digit() || (*index == 0 && *c == '+'))
+        .map(|(_, c)| c)
+        .collect();
+    format!(r#"<a href="tel:{}">{}</a>"#, number, phone)
+}
+
+/// The `key: value` pairs from a template's frontmatter, in order.
+type Frontmatter<'a> = Vec<(&'a str, &'a str)>;
+
+/// Splits `key: value` frontmatter between `---` lines from the start of a
+/// template, returning it along with the rest of the template.
+fn split_frontmatter(contents: &str) -> Result<(Frontmatter<'_>, &str), EsimMailerError> {
+    let Some(rest) = contents
+        .strip_prefix("---\n")
+        .or_else(|| contents.strip_prefix("---\r\n"))
+    else {
+        return Ok((Vec::new(), contents));
+    };
+
+    let mut frontmatter = Vec::new();
+    let mut remaining = rest;
+    loop {
+        let (line, after) = remaining.split_once('\n').unwrap_or((remaining, ""));
+        let line = line.trim_end_matches('\r');
+        if line == "---" {
+            return Ok((frontmatter, after));
         }
-        Err(e) => {
-            eprintln!("Could not send email: {:?}", e);
-            if let Some(source) = e.source() {
-                eprintln!("Error source: {:?}", source);
-            }
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("Could not send email: {}", e),
-            ))
+        if after.is_empty() && remaining.is_empty() {
+            return Err(EsimMailerError::TemplateError(
+                "Frontmatter is missing its closing ---".to_string(),
+            ));
+        }
+        if !line.trim().is_empty() {
+            let (key, value) = line.split_once(':').ok_or_else(|| {
+                EsimMailerError::TemplateError(format!("Invalid frontmatter line '{}'", line))
+            })?;
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value);
+            frontmatter.push((key.trim(), value));
         }
+        remaining = after;
     }
 }
 
-fn configure_mailer(
-    provider: &Provider,
-    email_address: &str,
-    token: String,
-) -> io::Result<SmtpTransport> {
-    match provider {
-        Provider::Gmail => Ok(SmtpTransport::relay("smtp.gmail.com")
-            .unwrap()
-            .credentials(Credentials::new(email_address.to_string(), token))
-            .authentication(vec![Mechanism::Xoauth2])
-            .port(587)
-            .tls(lettre::transport::smtp::client::Tls::Required(
-                lettre::transport::smtp::client::TlsParameters::new("smtp.gmail.com".to_string())
-                    .unwrap(),
-            ))
-            .build()),
-        Provider::Outlook => Ok(SmtpTransport::relay("smtp-mail.outlook.com")
-            .unwrap()
-            .credentials(Credentials::new(email_address.to_string(), token))
-            .authentication(vec![Mechanism::Xoauth2])
-            .port(587)
-            .tls(lettre::transport::smtp::client::Tls::Required(
-                lettre::transport::smtp::client::TlsParameters::new(
-                    "smtp-mail.outlook.com".to_string(),
-                )
-                .unwrap(),
-            ))
-            .build()),
+/// How deeply partials may include other partials before giving up.
+const MAX_PARTIAL_DEPTH: usize = 8;
+
+/// Loads each `.html` file in the directory as a partial named after the file.
+pub fn load_partials(dir: &Path) -> Result<HashMap<String, String>, EsimMailerError> {
+    let mut partials = HashMap::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "html")
+            && let Some(name) = path.file_stem().and_then(|name| name.to_str())
+        {
+            partials.insert(name.to_string(), fs::read_to_string(&path)?);
+        }
     }
+    Ok(partials)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+fn expand_partials(
+    template: &str,
+    partials: &HashMap<String, String>,
+    depth: usize,
+) -> Result<String, EsimMailerError> {
+    let mut expanded = String::with_capacity(template.len());
+    let mut rest = template;
 
-    #[test]
-    fn test_email_template_subject() {
-        let template = EmailTemplate::new();
-        let args = Args {
-            email_from: "sender@example.com".to_string(),
-            email_to: "recipient@example.com".to_string(),
-            bcc: None,
-            provider: "TestProvider".to_string(),
-            name: "John".to_string(),
-            data_amount: "5GB".to_string(),
-            time_period: "30 days".to_string(),
-            location: "Egypt".to_string(),
-        };
-        let result = template.subject(&args, 1);
-        assert_eq!(result, "[TestProvider] Egypt eSIM - 1");
+    while let Some(start) = rest.find("{{>") {
+        let end = rest[start..]
+            .find("}}")
+            .map(|end| start + end)
+            .ok_or_else(|| {
+                EsimMailerError::TemplateError("Unclosed partial include".to_string())
+            })?;
+        let name = rest[start + 3..end].trim();
+        let partial = partials
+            .get(name)
+            .ok_or_else(|| EsimMailerError::TemplateError(format!("Unknown partial '{}'", name)))?;
+        if depth >= MAX_PARTIAL_DEPTH {
+            return Err(EsimMailerError::TemplateError(format!(
+                "Partial '{}' is nested more than {} levels deep, does it include itself?",
+                name, MAX_PARTIAL_DEPTH
+            )));
+        }
+
+        expanded.push_str(&rest[..start]);
+        expanded.push_str(&expand_partials(partial, partials, depth + 1)?);
+        rest = &rest[end + 2..];
     }
 
-    #[test]
-    fn test_email_template_body() {
-        let template = EmailTemplate::new();
-        let args = Args {
-            email_from: "sender@example.com".to_string(),
-            email_to: "recipient@example.com".to_string(),
-            bcc: None,
-            provider: "TestProvider".to_string(),
-            name: "John".to_string(),
-            data_amount: "5GB".to_string(),
-            time_period: "30 days".to_string(),
-            location: "Egypt".to_string(),
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Elements which never have a closing tag.
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+
+/// Elements whose contents aren't parsed as HTML.
+const RAW_TEXT_ELEMENTS: &[&str] = &["script", "style"];
+
+fn check_html(html: &str) -> Result<(), EsimMailerError> {
+    let template_error = |problem: String| Err(EsimMailerError::TemplateError(problem));
+    let misplaced_qr_cid = || {
+        template_error("{{QR_CID}} must only be used in an <img> tag's src attribute".to_string())
+    };
+    let mut open_tags: Vec<String> = Vec::new();
+    let mut rest = html;
+
+    while let Some(start) = rest.find('<') {
+        if rest[..start].contains("{{QR_CID}}") {
+            return misplaced_qr_cid();
+        }
+        rest = &rest[start..];
+
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            let Some(end) = comment.find("-->") else {
+                return template_error("Unclosed comment".to_string());
+            };
+            rest = &comment[end + 3..];
+            continue;
+        }
+
+        let Some(end) = rest.find('>') else {
+            return template_error("Unclosed '<' at the end of the template".to_string());
         };
-        let result = template.body(&args);
-        assert!(result.contains("John"));
-        assert!(result.contains("TestProvider"));
-        assert!(result.contains("5GB"));
-        assert!(result.contains("30 days"));
-        assert!(result.contains("Egypt"));
-    }
+        let tag = &rest[1..end];
+        rest = &rest[end + 1..];
 
-    #[test]
-    fn parse_valid_provider() {
-        let gmail = "foobar@gmail.com".parse::<Provider>();
-        assert_eq!(gmail, Ok(Provider::Gmail));
+        // Doctypes and processing instructions don't need closing.
+        if tag.starts_with(['!', '?']) {
+            continue;
+        }
 
-        let outlook = "foobar@outlook.com".parse::<Provider>();
-        assert_eq!(outlook, Ok(Provider::Outlook));
+        if let Some(name) = tag.strip_prefix('/') {
+            let name = name.trim().to_ascii_lowercase();
+            match open_tags.pop() {
+                Some(open) if open == name => {}
+                Some(open) => {
+                    return template_error(format!("Expected </{}> but found </{}>", open, name));
+                }
+                None => return template_error(format!("Unexpected closing tag </{}>", name)),
+            }
+            continue;
+        }
 
-        let hotmail = "foobar@hotmail.com".parse::<Provider>();
-        assert_eq!(hotmail, Ok(Provider::Outlook));
-    }
+        let name = tag
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        if name.is_empty() {
+            return template_error(format!("Invalid tag <{}>", tag));
+        }
+        if tag.contains("{{QR_CID}}")
+            && !(name == "img"
+                && attribute(tag, "src").is_some_and(|src| src.contains("{{QR_CID}}")))
+        {
+            return misplaced_qr_cid();
+        }
+        if tag.ends_with('/') || VOID_ELEMENTS.contains(&name.as_str()) {
+            continue;
+        }
 
-    #[test]
-    fn parse_invalid_provider() {
-        let result = "foobar@yahoo.com".parse::<Provider>();
-        assert_eq!(result, Err(ParseProviderError("foobar@yahoo.com".into())));
+        if RAW_TEXT_ELEMENTS.contains(&name.as_str()) {
+            let closing = format!("</{}", name);
+            let Some(end) = rest.to_ascii_lowercase().find(&closing) else {
+                return template_error(format!("Unclosed tag <{}>", name));
+            };
+            rest = &rest[end..];
+        }
+        open_tags.push(name);
     }
 
-    #[test]
-    fn test_configure_mailer_gmail() {
-        let result = configure_mailer(&Provider::Gmail, "test@gmail.com", "token".to_string());
-        assert!(result.is_ok());
+    if rest.contains("{{QR_CID}}") {
+        return misplaced_qr_cid();
     }
+    match open_tags.pop() {
+        Some(open) => template_error(format!("Unclosed tag <{}>", open)),
+        None => Ok(()),
+    }
+}
 
-    #[test]
-    fn test_configure_mailer_outlook() {
-        let result = configure_mailer(&Provider::Outlook, "test@outlook.com", "token".to_string());
-        assert!(result.is_ok());
+fn check_body_not_empty(body: &str) -> Result<(), EsimMailerError> {
+    if body.trim().is_empty() {
+        return Err(EsimMailerError::TemplateError(
+            "The body is empty".to_string(),
+        ));
     }
+    Ok(())
+}
 
-    #[test]
-    fn test_provider_display() {
-        assert_eq!(Provider::Gmail.to_string(), "Gmail");
-        assert_eq!(Provider::Outlook.to_string(), "Outlook");
+fn check_qr_referenced(body: &str) -> Result<(), EsimMailerError> {
+    if !body.contains("{{QR_CID}}") {
+        return Err(EsimMailerError::TemplateError(
+            "The body never uses {{QR_CID}}, so the QR code image wouldn't be shown".to_string(),
+        ));
     }
+    Ok(())
+}
 
-    #[test]
-    fn test_send_email() -> io::Result<()> {
-        // Create a temporary test image
-        let temp_dir = std::env::temp_dir();
-        let image_path = temp_dir.join("test_image.png");
-        fs::write(&image_path, b"fake image data")?;
+/// Returns the quoted value of the named attribute in the contents of a tag.
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let lowercase = tag.to_ascii_lowercase();
+    lowercase.match_indices(name).find_map(|(index, _)| {
+        if !tag[..index].ends_with(char::is_whitespace) {
+            return None;
+        }
+        let value = tag[index + name.len()..].trim_start().strip_prefix('=')?;
+        let value = value.trim_start();
+        let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        let value = &value[1..];
+        value.find(quote).map(|end| &value[..end])
+    })
+}
 
-        let args = Args {
-            email_from: "test@gmail.com".to_string(),
-            email_to: "recipient@example.com".to_string(),
-            bcc: Some("bcc@example.com".to_string()),
-            provider: "TestProvider".to_string(),
-            name: "Test User".to_string(),
-            data_amount: "1GB".to_string(),
-            time_period: "7 days".to_string(),
-            location: "TestLocation".to_string(),
-        };
+/// Elements which are set apart from the surrounding text by blank lines
+/// when converting HTML to plain text.
+const PARAGRAPH_ELEMENTS: &[&str] = &[
+    "blockquote",
+    "div",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "ol",
+    "p",
+    "table",
+    "ul",
+];
 
-        // Test the function - it should fail when trying to send
-        let result = send_email(&args, "fake_token".to_string(), &image_path, 1);
+/// Elements which start a new line when converting HTML to plain text.
+const LINE_BREAK_ELEMENTS: &[&str] = &["br", "tr"];
 
-        // Clean up the temporary file
-        fs::remove_file(image_path)?;
+/// Converts an HTML body to plain text by dropping the markup. Paragraphs are
+/// separated by blank lines, list items start with `- ` and headings are
+/// uppercased.
+pub fn html_to_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut rest = html;
+    let mut in_heading = false;
 
-        // We expect an error from the SMTP client
-        assert!(result.is_err());
-        let err = result.unwrap_err();
-        assert!(err.to_string().contains("Could not send email"));
-        assert!(
-            err.to_string()
-                .contains("mechanism does not expect a challenge")
-        );
+    while let Some(start) = rest.find('<') {
+        push_collapsed(&mut text, &rest[..start], in_heading);
+        let Some(end) = rest[start..].find('>').map(|end| start + end) else {
+            rest = "";
+            break;
+        };
+        let tag = &rest[start + 1..end];
+        rest = &rest[end + 1..];
+
+        let is_closing = tag.starts_with('/');
+        let name = tag
+            .trim_start_matches('/')
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        if !is_closing && RAW_TEXT_ELEMENTS.contains(&name.as_str()) {
+            let closing = format!("</{}", name);
+            let end = rest
+                .to_ascii_lowercase()
+                .find(&closing)
+                .unwrap_or(rest.len());
+            rest = &rest[end..];
+            continue;
+        }
 
+        if name.len() == 2 && name.starts_with('h') && name.ends_with(|c: char| c.is_ascii_digit())
+        {
+            in_heading = !is_closing;
+        }
+        if PARAGRAPH_ELEMENTS.contains(&name.as_str()) {
+            text.push_str("\n\n");
+        } else if name == "li" && !is_closing {
+            text.push_str("\n- ");
+        } else if LINE_BREAK_ELEMENTS.contains(&name.as_str()) {
+            text.push('\n');
+        }
+    }
+    push_collapsed(&mut text, rest, in_heading);
+
+    // Trim each line and keep at most one blank line between blocks
+    let mut lines: Vec<&str> = Vec::new();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() && lines.last().is_none_or(|last| last.is_empty()) {
+            continue;
+        }
+        lines.push(line);
+    }
+    while lines.last() == Some(&"") {
+        lines.pop();
+    }
+    lines.join("\n")
+}
+
+/// Appends text from HTML with entities decoded and whitespace collapsed, as
+/// a browser would show it.
+fn push_collapsed(text: &mut String, html: &str, uppercase: bool) {
+    let mut decoded = html
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&");
+    if uppercase {
+        decoded = decoded.to_uppercase();
+    }
+    for (i, word) in decoded.split_whitespace().enumerate() {
+        if (i > 0 || decoded.starts_with(char::is_whitespace))
+            && !text.ends_with([' ', '\n'])
+            && !text.is_empty()
+        {
+            text.push(' ');
+        }
+        text.push_str(word);
+    }
+    if decoded.ends_with(char::is_whitespace) && !decoded.trim().is_empty() {
+        text.push(' ');
+    }
+}
+
+/// The subject and body of an email as they'll be sent, for previewing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmailPreview {
+    pub subject: String,
+    pub body_html: String,
+
+    /// The plain text body, if one is sent
+    pub body_text: Option<String>,
+}
+
+/// Renders the subject and body for previewing, without the QR code image.
+/// A template file which can't be read is previewed as the built-in template.
+pub fn preview(args: &Args, count: usize) -> EmailPreview {
+    let template = EmailTemplate::load(args).unwrap_or_else(|_| EmailTemplate::for_args(args));
+    let body_html = html_body(&template, args, &SystemClock);
+    let body_text = args
+        .plain_text
+        .then(|| text_body(&template, args, &body_html, &SystemClock));
+    EmailPreview {
+        subject: template.subject(args, count),
+        body_html,
+        body_text,
+    }
+}
+
+/// Returns a line-based diff of the bodies the two templates render for
+/// `args`, for reviewing template changes. Removed lines start with `- `,
+/// added lines with `+ ` and unchanged lines with two spaces.
+pub fn diff_rendered(
+    old_template: &EmailTemplate,
+    new_template: &EmailTemplate,
+    args: &Args,
+) -> String {
+    let old_body = old_template.body(args);
+    let new_body = new_template.body(args);
+    let old_lines: Vec<&str> = old_body.lines().collect();
+    let new_lines: Vec<&str> = new_body.lines().collect();
+
+    // Longest common subsequence of lines, counted from the end
+    let mut common = vec![vec![0usize; new_lines.len() + 1]; old_lines.len() + 1];
+    for i in (0..old_lines.len()).rev() {
+        for j in (0..new_lines.len()).rev() {
+            common[i][j] = if old_lines[i] == new_lines[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old_lines.len() || j < new_lines.len() {
+        if i < old_lines.len() && j < new_lines.len() && old_lines[i] == new_lines[j] {
+            diff.push_str(&format!("  {}\n", old_lines[i]));
+            i += 1;
+            j += 1;
+        } else if i < old_lines.len()
+            && (j == new_lines.len() || common[i + 1][j] >= common[i][j + 1])
+        {
+            diff.push_str(&format!("- {}\n", old_lines[i]));
+            i += 1;
+        } else {
+            diff.push_str(&format!("+ {}\n", new_lines[j]));
+            j += 1;
+        }
+    }
+    diff
+}
+
+fn html_body(template: &EmailTemplate, args: &Args, clock: &dyn Clock) -> String {
+    let body = match (&args.body_override, args.markdown) {
+        (Some(body_override), false) => render(body_override, &template.variables_at(args, clock)),
+        (Some(body_override), true) => render(
+            &markdown::to_html(body_override),
+            &template.variables_at(args, clock),
+        ),
+        (None, false) => template.body_at(args, clock),
+        (None, true) => EmailTemplate {
+            body_template: markdown::to_html(&template.body_template),
+            ..template.clone()
+        }
+        .body_at(args, clock),
+    };
+    let body = match args.text_direction {
+        Some(Direction::Rtl) => with_direction(&body, Direction::Rtl),
+        _ => body,
+    };
+    if args.inline_css {
+        inline_css(&body)
+    } else {
+        body
+    }
+}
+
+/// Returns the plain text version of the body. Markdown bodies are converted
+/// from their source, and others from the rendered HTML.
+fn text_body(template: &EmailTemplate, args: &Args, html: &str, clock: &dyn Clock) -> String {
+    if !args.markdown {
+        return html_to_text(html);
+    }
+    let source = args
+        .body_override
+        .as_deref()
+        .unwrap_or(&template.body_template);
+    // Values such as the support phone link are HTML, so convert them too
+    let variables = template
+        .variables_at(args, clock)
+        .into_iter()
+        .map(|(name, value)| (name, html_to_text(&value)))
+        .collect();
+    render(&markdown::to_text(source), &variables)
+}
+
+/// Returns the value of the HTML `dir` attribute for a direction.
+fn direction_attribute(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Ltr => "ltr",
+        Direction::Rtl => "rtl",
+    }
+}
+
+/// Adds a `dir` attribute to the `<html>` or `<body>` tag, or wraps the body
+/// in a `<div>` with one if it has neither. Bodies which already set a `dir`
+/// attribute, e.g. through `{{dir}}`, are left alone.
+fn with_direction(html: &str, direction: Direction) -> String {
+    let lowercase = html.to_ascii_lowercase();
+    let attribute = format!(r#" dir="{}""#, direction_attribute(direction));
+    if lowercase.contains("dir=") {
+        return html.to_string();
+    }
+    let tag_end = ["<html", "<body"].iter().find_map(|tag| {
+        lowercase
+            .find(tag)
+            .map(|start| start + tag.len())
+            .filter(|end| {
+                lowercase[*end..].starts_with(|c: char| c == '>' || c.is_ascii_whitespace())
+            })
+    });
+    match tag_end {
+        Some(end) => format!("{}{}{}", &html[..end], attribute, &html[end..]),
+        None => format!("<div{}>{}</div>", attribute, html),
+    }
+}
+
+/// Copies the rules in the `<style>` blocks of an HTML body onto the `style`
+/// attributes of the elements they match, since many mail clients drop
+/// `<style>` blocks. Only simple selectors such as `p`, `.note`, `#footer` and
+/// `td.code` are inlined, and the `<style>` blocks are kept for the clients
+/// which do support them.
+fn inline_css(html: &str) -> String {
+    let rules = style_rules(html);
+    if rules.is_empty() {
+        return html.to_string();
+    }
+
+    let mut inlined = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        let Some(end) = rest[start..].find('>').map(|end| start + end) else {
+            break;
+        };
+        let tag = &rest[start + 1..end];
+        inlined.push_str(&rest[..=start]);
+        rest = &rest[end..];
+
+        let name = tag
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            inlined.push_str(tag);
+            continue;
+        }
+        if RAW_TEXT_ELEMENTS.contains(&name.as_str()) {
+            let closing = format!("</{}", name);
+            let close = rest
+                .to_ascii_lowercase()
+                .find(&closing)
+                .unwrap_or(rest.len());
+            inlined.push_str(tag);
+            inlined.push_str(&rest[..close]);
+            rest = &rest[close..];
+            continue;
+        }
+
+        let declarations: Vec<&str> = rules
+            .iter()
+            .filter(|(selector, _)| selector_matches(selector, &name, tag))
+            .map(|(_, declarations)| declarations.as_str())
+            .collect();
+        inlined.push_str(&with_style(tag, &declarations));
+    }
+    inlined.push_str(rest);
+    inlined
+}
+
+/// Returns the selectors and declarations of the rules in an HTML body's
+/// `<style>` blocks, in order. At-rules such as `@media` are skipped.
+fn style_rules(html: &str) -> Vec<(String, String)> {
+    let lowercase = html.to_ascii_lowercase();
+    let mut rules = Vec::new();
+    let mut from = 0;
+    while let Some(start) = lowercase[from..].find("<style").map(|start| from + start) {
+        let Some(open) = lowercase[start..].find('>').map(|end| start + end + 1) else {
+            break;
+        };
+        let close = lowercase[open..]
+            .find("</style")
+            .map_or(html.len(), |close| open + close);
+        parse_css_rules(&html[open..close], &mut rules);
+        from = close;
+    }
+    rules
+}
+
+fn parse_css_rules(css: &str, rules: &mut Vec<(String, String)>) {
+    let mut css = css.replace("<!--", "").replace("-->", "");
+    while let Some(start) = css.find("/*") {
+        let end = css[start..]
+            .find("*/")
+            .map_or(css.len(), |end| start + end + 2);
+        css.replace_range(start..end, "");
+    }
+
+    let mut rest = css.as_str();
+    while let Some(open) = rest.find('{') {
+        let prelude = rest[..open].trim();
+        let mut depth = 0;
+        let mut close = rest.len();
+        for (index, c) in rest[open..].char_indices() {
+            match c {
+                '{' => depth += 1,
+                '}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        close = open + index;
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+        let body = &rest[open + 1..close];
+        rest = rest.get(close + 1..).unwrap_or_default();
+
+        if prelude.starts_with('@') {
+            continue;
+        }
+        // Attributes are written with double quotes, so use single quotes
+        // for values such as font names.
+        let declarations = body
+            .split(';')
+            .map(str::trim)
+            .filter(|declaration| !declaration.is_empty())
+            .collect::<Vec<_>>()
+            .join("; ")
+            .replace('"', "'");
+        if declarations.is_empty() {
+            continue;
+        }
+        for selector in prelude.split(',') {
+            rules.push((selector.trim().to_string(), declarations.clone()));
+        }
+    }
+}
+
+/// Returns whether a simple selector, made of an optional element name
+/// followed by classes and ids, matches the element with the given tag.
+fn selector_matches(selector: &str, name: &str, tag: &str) -> bool {
+    let is_simple = !selector.is_empty()
+        && !selector.contains(|c: char| c.is_whitespace() || ">+~:[*".contains(c));
+    if !is_simple {
+        return false;
+    }
+
+    let (element, mut rest) =
+        selector.split_at(selector.find(['.', '#']).unwrap_or(selector.len()));
+    if !element.is_empty() && !element.eq_ignore_ascii_case(name) {
+        return false;
+    }
+    while let Some(kind) = rest.chars().next() {
+        let remaining = &rest[1..];
+        let len = remaining.find(['.', '#']).unwrap_or(remaining.len());
+        let value = &remaining[..len];
+        rest = &remaining[len..];
+        let is_match = match kind {
+            '.' => attribute(tag, "class")
+                .unwrap_or_default()
+                .split_whitespace()
+                .any(|class| class == value),
+            _ => attribute(tag, "id") == Some(value),
+        };
+        if value.is_empty() || !is_match {
+            return false;
+        }
+    }
+    true
+}
+
+/// Returns the contents of a tag with the declarations added to its `style`
+/// attribute. Declarations already on the element come last so they win.
+fn with_style(tag: &str, declarations: &[&str]) -> String {
+    if declarations.is_empty() {
+        return tag.to_string();
+    }
+    let mut style = declarations.join("; ");
+    match attribute(tag, "style") {
+        Some(existing) => {
+            let offset = existing.as_ptr() as usize - tag.as_ptr() as usize;
+            let own = existing.trim().trim_end_matches(';');
+            if !own.is_empty() {
+                style.push_str("; ");
+                style.push_str(own);
+            }
+            format!(
+                "{}{}{}",
+                &tag[..offset],
+                style,
+                &tag[offset + existing.len()..]
+            )
+        }
+        None => {
+            let end = tag.trim_end().trim_end_matches('/').trim_end().len();
+            format!("{} style=\"{}\"{}", &tag[..end], style, &tag[end..])
+        }
+    }
+}
+
+/// Returns the date, as YYYY-MM-DD in UTC, on which an eSIM valid for
+/// `time_period` from now expires. Only periods in days or weeks, such as
+/// "30 days", are understood.
+pub fn expiry_date(time_period: &str, clock: &dyn Clock) -> Option<String> {
+    let (year, month, day) = civil_from_days(expiry_day(time_period, clock)?);
+    Some(format!("{:04}-{:02}-{:02}", year, month, day))
+}
+
+/// Returns the day, counted from 1970-01-01, on which an eSIM valid for
+/// `time_period` from now expires.
+fn expiry_day(time_period: &str, clock: &dyn Clock) -> Option<u64> {
+    let (amount, unit) = time_period.trim().split_once(char::is_whitespace)?;
+    let amount: u64 = amount.parse().ok()?;
+    let days = match unit.trim().to_ascii_lowercase().as_str() {
+        "day" | "days" => amount,
+        "week" | "weeks" => amount.checked_mul(7)?,
+        _ => return None,
+    };
+
+    let now = clock.now().duration_since(SystemTime::UNIX_EPOCH).ok()?;
+    (now.as_secs() / 86_400).checked_add(days)
+}
+
+/// Returns an iCalendar file with an all-day event on the day the eSIM
+/// expires, or `None` if the time period isn't one [`expiry_date`]
+/// understands.
+fn expiry_reminder_ics(args: &Args, clock: &dyn Clock) -> Option<String> {
+    let expiry_day = expiry_day(&args.time_period, clock)?;
+    let date = |days: u64| {
+        let (year, month, day) = civil_from_days(days);
+        format!("{:04}{:02}{:02}", year, month, day)
+    };
+    let now = clock
+        .now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .ok()?
+        .as_secs();
+    let timestamp = format!(
+        "{}T{:02}{:02}{:02}Z",
+        date(now / 86_400),
+        now % 86_400 / 3_600,
+        now % 3_600 / 60,
+        now % 60
+    );
+    let summary = match args.location.trim() {
+        "" => "eSIM expires".to_string(),
+        location => format!("{} eSIM expires", location),
+    };
+    // Escape the characters RFC 5545 section 3.3.11 gives a meaning in text
+    let summary = summary
+        .replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,");
+
+    let lines = [
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        format!("PRODID:-//{}//EN", X_MAILER),
+        "METHOD:PUBLISH".to_string(),
+        "BEGIN:VEVENT".to_string(),
+        format!(
+            "UID:esim-expiry-{}-{}",
+            date(expiry_day),
+            args.email_to.trim()
+        ),
+        format!("DTSTAMP:{}", timestamp),
+        format!("DTSTART;VALUE=DATE:{}", date(expiry_day)),
+        format!("DTEND;VALUE=DATE:{}", date(expiry_day + 1)),
+        format!("SUMMARY:{}", summary),
+        "TRANSP:TRANSPARENT".to_string(),
+        "END:VEVENT".to_string(),
+        "END:VCALENDAR".to_string(),
+    ];
+    Some(lines.join("\r\n") + "\r\n")
+}
+
+/// Converts a number of days since 1970-01-01 to a year, month and day in the
+/// proleptic Gregorian calendar.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    // Count from 0000-03-01 so the leap day falls at the end of each year.
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Styles which hide the preheader in the body while leaving it for inboxes to
+/// use as preview text.
+const PREHEADER_STYLE: &str = "display: none; max-height: 0; overflow: hidden; \
+    mso-hide: all; opacity: 0; font-size: 1px; line-height: 1px; color: transparent";
+
+/// Inserts the preheader in a hidden `<span>` at the start of the HTML body.
+fn with_preheader(html: &str, preheader: &str) -> String {
+    let span = format!(r#"<span style="{}">{}</span>"#, PREHEADER_STYLE, preheader);
+    let body_start = html
+        .to_ascii_lowercase()
+        .find("<body")
+        .and_then(|start| html[start..].find('>').map(|end| start + end + 1))
+        .unwrap_or(0);
+    format!("{}{}{}", &html[..body_start], span, &html[body_start..])
+}
+
+/// Replaces each `{{name}}` placeholder in the template with its value.
+fn render(template: &str, variables: &BTreeMap<String, String>) -> String {
+    variables
+        .iter()
+        .fold(template.to_string(), |text, (name, value)| {
+            text.replace(&format!("{{{{{}}}}}", name), value)
+        })
+}
+
+/// The value of the X-Mailer header, e.g. `esim-mailer/0.1.0`.
+pub const X_MAILER: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// The name of the header carrying the order ID, unless another is configured.
+pub const DEFAULT_ORDER_ID_HEADER: &str = "X-Order-Id";
+
+/// Defines a header with a plain text value, like lettre's own text headers.
+macro_rules! text_header {
+    ($(#[$attr:meta])* $type_name:ident, $header_name:expr) => {
+        $(#[$attr])*
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct $type_name(String);
+
+        impl Header for $type_name {
+            fn name() -> HeaderName {
+                HeaderName::new_from_ascii_str($header_name)
+            }
+
+            fn parse(s: &str) -> Result<Self, Box<dyn Error + Send + Sync>> {
+                Ok(Self(s.to_string()))
+            }
+
+            fn display(&self) -> HeaderValue {
+                HeaderValue::new(Self::name(), self.0.clone())
+            }
+        }
+    };
+}
+
+text_header!(
+    /// The X-Mailer header, naming the program which sent the email.
+    XMailer,
+    "X-Mailer"
+);
+
+text_header!(
+    /// The X-Original-To header, recording who an email was meant for before
+    /// it was redirected.
+    XOriginalTo,
+    "X-Original-To"
+);
+
+text_header!(
+    /// The Organization header, naming the organization the sender belongs to.
+    Organization,
+    "Organization"
+);
+
+text_header!(
+    /// The List-Unsubscribe header, telling mail clients where recipients can
+    /// unsubscribe, per RFC 2369.
+    ListUnsubscribe,
+    "List-Unsubscribe"
+);
+
+/// Values which are normally random or time-based, fixed so that rendered
+/// messages are reproducible in snapshot tests.
+#[derive(Debug, Clone)]
+pub struct FixedParts {
+    pub boundary: String,
+    pub content_id: String,
+    pub date: SystemTime,
+}
+
+/// Builds the eSIM email for a single QR code image without sending it.
+pub fn build_email(
+    args: &Args,
+    image_path: &Path,
+    count: usize,
+) -> Result<Message, EsimMailerError> {
+    build_message(args, image_path, count, None)
+}
+
+/// Builds the eSIM email for each recipient, along with the arguments used
+/// for it. This is the primary recipient and, if set, the guardian, whose
+/// copy includes the guardian note. The image is only read once for both.
+pub fn build_emails(
+    args: &Args,
+    image_path: &Path,
+    count: usize,
+) -> Result<Vec<(Args, Message)>, EsimMailerError> {
+    let image_data = load_image(args, image_path)?;
+    std::iter::once(args.clone())
+        .chain(args.guardian_copy())
+        .map(|args| {
+            let email =
+                build_message_with_image(&args, image_path, image_data.clone(), count, None)?;
+            Ok((args, email))
+        })
+        .collect()
+}
+
+/// Renders the eSIM email exactly as it would be sent over the wire.
+///
+/// Passing `fixed` replaces the random MIME boundary, Content-ID and date so
+/// the output is byte-for-byte reproducible. Real sends always use random values.
+pub fn render_raw(
+    args: &Args,
+    image_path: &Path,
+    count: usize,
+    fixed: Option<&FixedParts>,
+) -> Result<Vec<u8>, EsimMailerError> {
+    Ok(build_message(args, image_path, count, fixed)?.formatted())
+}
+
+fn build_message(
+    args: &Args,
+    image_path: &Path,
+    count: usize,
+    fixed: Option<&FixedParts>,
+) -> Result<Message, EsimMailerError> {
+    let image_data = load_image(args, image_path)?;
+    build_message_with_image(args, image_path, image_data, count, fixed)
+}
+
+/// Reads the image file, or generates it from the activation code, or
+/// downloads it.
+fn load_image(args: &Args, image_path: &Path) -> Result<Vec<u8>, EsimMailerError> {
+    match args.activation_code.as_deref() {
+        Some(activation_code) => {
+            validate_lpa(activation_code)?;
+            activation_code_png(activation_code)
+        }
+        None if args.image_url.is_some() => download_image(args),
+        None => Ok(fs::read(image_path)?),
+    }
+}
+
+fn build_message_with_image(
+    args: &Args,
+    image_path: &Path,
+    image_data: Vec<u8>,
+    count: usize,
+    fixed: Option<&FixedParts>,
+) -> Result<Message, EsimMailerError> {
+    // Get template content
+    let template = EmailTemplate::load(args)?;
+
+    let image_data = fit_image_to_provider(args, image_data);
+    let image_content_type = image_content_type(args)?;
+
+    // Get subject and body content
+    let subject = template.subject(args, count);
+    // Truncating to a limit past what servers accept still can't be sent.
+    check_subject_length(&subject)?;
+    // Generate a unique Content-ID for the image
+    let content_id = match fixed {
+        Some(fixed) => fixed.content_id.clone(),
+        None => format!("qr_image_cid@{}", uuid::Uuid::new_v4()),
+    };
+    // Time-based parts of the email follow the fixed date when there is one
+    let fixed_clock = fixed.map(|fixed| FixedClock(fixed.date));
+    let clock: &dyn Clock = match &fixed_clock {
+        Some(fixed_clock) => fixed_clock,
+        None => &SystemClock,
+    };
+
+    // Get the body content and replace the QR_CID placeholder with the actual Content-ID
+    let body_content = html_body(&template, args, clock);
+    check_body_not_empty(&body_content)?;
+    check_body_length(args, &body_content)?;
+    check_inline_image_count(args, &body_content)?;
+    if !args.attach_qr {
+        check_qr_referenced(&body_content)?;
+    }
+    let body = body_content.replace("{{QR_CID}}", &content_id);
+
+    // Create multipart email with HTML body and image attachment
+    let from = parse_mailbox(&args.email_from)?;
+    let mut email_builder = Message::builder()
+        .from(from.clone())
+        .subject(subject.clone());
+
+    // Name the authenticated account as the Sender when sending on behalf of
+    // another address, as RFC 5322 section 3.6.2 recommends
+    let auth = parse_mailbox(args.auth_identity())?;
+    if !args.omit_sender
+        && !auth
+            .email
+            .to_string()
+            .eq_ignore_ascii_case(from.email.as_ref())
+    {
+        email_builder = email_builder.sender(auth);
+    }
+
+    // Send to the test inbox instead when redirecting, dropping CC and BCC so
+    // nothing reaches the real recipients.
+    let redirect_to = args
+        .redirect_to
+        .as_deref()
+        .filter(|redirect_to| !redirect_to.is_empty());
+    match redirect_to {
+        Some(redirect_to) => {
+            let original_to = parse_recipient(args, &args.email_to)?;
+            email_builder = email_builder
+                .to(parse_mailbox(redirect_to)?)
+                .header(XOriginalTo(original_to.email.to_string()));
+        }
+        None => email_builder = email_builder.to(parse_recipient(args, &args.email_to)?),
+    }
+
+    if let Some(fixed) = fixed {
+        email_builder = email_builder.date(fixed.date);
+    }
+
+    if !args.omit_x_mailer {
+        email_builder = email_builder.header(XMailer(X_MAILER.to_string()));
+    }
+
+    if let Some(organization) = args
+        .organization
+        .as_deref()
+        .filter(|organization| !organization.is_empty())
+    {
+        email_builder = email_builder.header(Organization(organization.to_string()));
+    }
+
+    if let Some(list_unsubscribe) = args
+        .list_unsubscribe
+        .as_deref()
+        .map(str::trim)
+        .filter(|list_unsubscribe| !list_unsubscribe.is_empty())
+    {
+        let list_unsubscribe = match list_unsubscribe.starts_with('<') {
+            true => list_unsubscribe.to_string(),
+            false => format!("<{}>", list_unsubscribe),
+        };
+        email_builder = email_builder.header(ListUnsubscribe(list_unsubscribe));
+    }
+
+    // Thread follow-up emails with the earlier ones
+    if let Some(in_reply_to) = &args.in_reply_to {
+        email_builder = email_builder.in_reply_to(check_msg_id(in_reply_to)?.to_string());
+    }
+    if let Some(references) = args.references.as_ref().filter(|ids| !ids.is_empty()) {
+        for id in references {
+            check_msg_id(id)?;
+        }
+        email_builder = email_builder.references(references.join(" "));
+    }
+
+    // Add CC if provided and not empty
+    if let Some(cc) = args
+        .cc
+        .as_deref()
+        .filter(|cc| !cc.is_empty() && redirect_to.is_none())
+    {
+        email_builder = email_builder.cc(parse_recipient(args, cc)?);
+    }
+
+    // Direct replies elsewhere, copying that address in when asked so it
+    // sees the whole conversation
+    if let Some(reply_to) = args
+        .reply_to
+        .as_deref()
+        .filter(|reply_to| !reply_to.is_empty())
+    {
+        let reply_to = parse_mailbox(reply_to)?;
+        let is_recipient = [Some(args.email_to.as_str()), args.cc.as_deref()]
+            .into_iter()
+            .flatten()
+            .chain(split_addresses(args.bcc.as_deref().unwrap_or_default()))
+            .filter_map(|address| parse_mailbox(address).ok())
+            .any(|mailbox| {
+                mailbox
+                    .email
+                    .to_string()
+                    .eq_ignore_ascii_case(reply_to.email.as_ref())
+            });
+        if args.cc_reply_to && !is_recipient && redirect_to.is_none() {
+            email_builder = email_builder.cc(reply_to.clone());
+        }
+        email_builder = email_builder.reply_to(reply_to);
+    }
+
+    // Add BCC if provided, which may be a comma-separated list, along with
+    // the addresses in the group file
+    if redirect_to.is_none() {
+        for mailbox in bcc_mailboxes(args)? {
+            email_builder = email_builder.bcc(mailbox);
+        }
+    }
+
+    // Add the sending provider's BCC, e.g. an archive address
+    if let Some(bcc) = provider_bcc(args).filter(|_| redirect_to.is_none()) {
+        email_builder = email_builder.bcc(parse_mailbox(bcc)?);
+    }
+
+    let text = args
+        .plain_text
+        .then(|| text_body(&template, args, &body, clock));
+    let html = lettre::message::SinglePart::builder()
+        .header(header::ContentType::TEXT_HTML)
+        .body(body);
+
+    // Offer the plain text version as an alternative to the HTML
+    let alternative = |text: String| {
+        let mut alternative = lettre::message::MultiPart::alternative();
+        if let Some(fixed) = fixed {
+            alternative = alternative.boundary(format!("{}-alt", fixed.boundary));
+        }
+        alternative.singlepart(lettre::message::SinglePart::plain(text))
+    };
+
+    let filename = match args.activation_code {
+        Some(_) => None,
+        None => image_path.file_name().and_then(|name| name.to_str()),
+    }
+    .unwrap_or("qr.png");
+    // Remind the recipient when the eSIM expires, if the time period says when
+    let expiry_reminder = expiry_reminder_ics(args, clock)
+        .filter(|_| args.attach_expiry_reminder)
+        .map(|ics| {
+            lettre::message::Attachment::new("esim-expiry.ics".to_string()).body(
+                ics,
+                header::ContentType::parse("text/calendar; charset=utf-8; method=PUBLISH")
+                    .expect("valid content type"),
+            )
+        });
+
+    let content = if args.attach_qr {
+        // Attach the image as a regular file, with nothing shown inline
+        let attachment = image_part(
+            args.image_disposition
+                .unwrap_or(ImageDisposition::Attachment),
+            None,
+            filename,
+            image_data,
+            image_content_type,
+        );
+
+        let mut mixed = lettre::message::MultiPart::mixed();
+        if let Some(fixed) = fixed {
+            mixed = mixed.boundary(fixed.boundary.clone());
+        }
+        let mixed = match text {
+            Some(text) => mixed.multipart(alternative(text).singlepart(html)),
+            None => mixed.singlepart(html),
+        };
+        let mixed = mixed.singlepart(attachment);
+        match expiry_reminder {
+            Some(expiry_reminder) => mixed.singlepart(expiry_reminder),
+            None => mixed,
+        }
+    } else {
+        let mut related = lettre::message::MultiPart::related();
+        if let Some(fixed) = fixed {
+            related = related.boundary(fixed.boundary.clone());
+        }
+
+        // Build the email with multipart/related content
+        let related = related.singlepart(html).singlepart(image_part(
+            args.image_disposition.unwrap_or(ImageDisposition::Inline),
+            Some(&content_id),
+            filename,
+            image_data,
+            image_content_type,
+        ));
+        let content = match text {
+            Some(text) => alternative(text).multipart(related),
+            None => related,
+        };
+        match expiry_reminder {
+            Some(expiry_reminder) => {
+                let mut mixed = lettre::message::MultiPart::mixed();
+                if let Some(fixed) = fixed {
+                    mixed = mixed.boundary(format!("{}-mixed", fixed.boundary));
+                }
+                mixed.multipart(content).singlepart(expiry_reminder)
+            }
+            None => content,
+        }
+    };
+
+    let mut message = email_builder
+        .multipart(content)
+        .map_err(|e| EsimMailerError::MessageError(e.to_string()))?;
+    // Rewrite the visible From last, so the envelope keeps the real sender
+    if let Some(from) = rewritten_from(args, &from)? {
+        message
+            .headers_mut()
+            .insert_raw(header::From::from(Mailboxes::from(from)).display());
+    }
+    if let Some(subject) = encoded_subject(args, &subject) {
+        message.headers_mut().insert_raw(subject);
+    }
+    if let Some(order_id) = order_id_header(args)? {
+        message.headers_mut().insert_raw(order_id);
+    }
+    Ok(message)
+}
+
+/// Returns the sender with its domain rewritten for the From header, or
+/// `None` if its domain isn't rewritten.
+fn rewritten_from(args: &Args, from: &Mailbox) -> Result<Option<Mailbox>, EsimMailerError> {
+    let Some((_, domain)) = args
+        .from_domain_rewrites
+        .iter()
+        .find(|(domain, _)| domain.eq_ignore_ascii_case(from.email.domain()))
+    else {
+        return Ok(None);
+    };
+    let email = Address::new(from.email.user(), domain.trim()).map_err(|e| {
+        EsimMailerError::ConfigError(format!(
+            "Could not rewrite the From domain to '{}': {}",
+            domain, e
+        ))
+    })?;
+    Ok(Some(Mailbox::new(from.name.clone(), email)))
+}
+
+/// The longest an RFC 2047 encoded word may be, including its delimiters.
+const MAX_ENCODED_WORD_LEN: usize = 75;
+
+/// Returns the Subject header encoded in the charset set in `args`, or `None`
+/// if lettre's own UTF-8 encoding should be used. That's the case for UTF-8,
+/// for plain ASCII subjects which need no encoding and for subjects with
+/// characters outside the charset.
+fn encoded_subject(args: &Args, subject: &str) -> Option<HeaderValue> {
+    if args.subject_charset.unwrap_or_default() != Charset::Latin1
+        || subject.is_ascii()
+        || subject.chars().any(|c| u32::from(c) > 0xFF)
+    {
+        return None;
+    }
+
+    let prefix = "=?ISO-8859-1?Q?";
+    let suffix = "?=";
+    let mut words = Vec::new();
+    let mut word = String::new();
+    for c in subject.chars() {
+        // Q encoding, per RFC 2047 section 4.2, keeping only the characters
+        // which are safe anywhere in a header.
+        let encoded = match c {
+            ' ' => "_".to_string(),
+            c if c.is_ascii_alphanumeric() || "!*+-/".contains(c) => c.to_string(),
+            c => format!("={:02X}", u32::from(c)),
+        };
+        if prefix.len() + word.len() + encoded.len() + suffix.len() > MAX_ENCODED_WORD_LEN {
+            words.push(format!("{}{}{}", prefix, word, suffix));
+            word.clear();
+        }
+        word.push_str(&encoded);
+    }
+    words.push(format!("{}{}{}", prefix, word, suffix));
+
+    Some(HeaderValue::dangerous_new_pre_encoded(
+        HeaderName::new_from_ascii_str("Subject"),
+        subject.to_string(),
+        words.join("\r\n "),
+    ))
+}
+
+/// Returns the header carrying the order ID, if there is one. Both the name
+/// and the value must be printable ASCII so the header can't be used to
+/// inject others.
+fn order_id_header(args: &Args) -> Result<Option<HeaderValue>, EsimMailerError> {
+    let Some(order_id) = args.order_id.as_deref().filter(|id| !id.is_empty()) else {
+        return Ok(None);
+    };
+    let name = args
+        .order_id_header
+        .as_deref()
+        .unwrap_or(DEFAULT_ORDER_ID_HEADER);
+    let is_printable = |s: &str| s.chars().all(|c| c.is_ascii_graphic() || c == ' ');
+    if !is_printable(order_id) || order_id.len() > 256 {
+        return Err(EsimMailerError::MessageError(format!(
+            "Order ID '{}' must be at most 256 printable ASCII characters",
+            order_id.escape_default()
+        )));
+    }
+    let name = HeaderName::new_from_ascii(name.to_string())
+        .ok()
+        .filter(|name| is_printable(name))
+        .ok_or_else(|| {
+            EsimMailerError::MessageError(format!(
+                "'{}' isn't a valid header name for the order ID",
+                name.escape_default()
+            ))
+        })?;
+    Ok(Some(HeaderValue::new(name, order_id.to_string())))
+}
+
+/// Returns the MIME part holding the QR code image, with the headers in the
+/// same order as lettre's own attachments.
+fn image_part(
+    disposition: ImageDisposition,
+    content_id: Option<&str>,
+    filename: &str,
+    image_data: Vec<u8>,
+    content_type: header::ContentType,
+) -> lettre::message::SinglePart {
+    let mut builder = lettre::message::SinglePart::builder();
+    if let Some(content_id) = content_id {
+        builder = builder.header(header::ContentId::from(format!("<{}>", content_id)));
+    }
+    let disposition = match (disposition, content_id) {
+        (ImageDisposition::Inline, Some(_)) => header::ContentDisposition::inline(),
+        (ImageDisposition::Inline, None) => header::ContentDisposition::inline_with_name(filename),
+        (ImageDisposition::Attachment, _) => header::ContentDisposition::attachment(filename),
+    };
+    builder
+        .header(disposition)
+        .header(content_type)
+        .body(image_data)
+}
+
+/// Returns the BCC address configured for the provider the email is sent
+/// through, if there is one.
+fn provider_bcc(args: &Args) -> Option<&str> {
+    let provider: Provider = args.auth_identity().parse().ok()?;
+    provider
+        .setting(&args.provider_bcc)
+        .map(|bcc| bcc.trim())
+        .filter(|bcc| !bcc.is_empty())
+}
+
+pub fn send_email(
+    args: &Args,
+    token: String,
+    image_path: &Path,
+    count: usize,
+) -> Result<(), EsimMailerError> {
+    send_email_with_hook(args, token, image_path, count, None)
+}
+
+/// Sends an eSIM email, calling `on_success` with the arguments once the
+/// server has accepted it.
+pub fn send_email_with_hook(
+    args: &Args,
+    token: String,
+    image_path: &Path,
+    count: usize,
+    on_success: Option<&dyn Fn(&Args)>,
+) -> Result<(), EsimMailerError> {
+    send_email_through(args, token, image_path, count, on_success, None)
+}
+
+/// Like [`send_email_with_hook`], but reuses the transport from `cache` for
+/// the account, creating it on the first send. The token is only used when
+/// the transport is created.
+pub fn send_email_cached(
+    args: &Args,
+    token: String,
+    image_path: &Path,
+    count: usize,
+    on_success: Option<&dyn Fn(&Args)>,
+    cache: &mut TransportCache,
+) -> Result<(), EsimMailerError> {
+    send_email_through(args, token, image_path, count, on_success, Some(cache))
+}
+
+/// Sends an eSIM email, reusing the transport from `cache` if one is given.
+/// Sends which need a connection of their own, through a proxy or with DSN
+/// or an authorization identity, don't use the cache.
+fn send_email_through(
+    args: &Args,
+    token: String,
+    image_path: &Path,
+    count: usize,
+    on_success: Option<&dyn Fn(&Args)>,
+    cache: Option<&mut TransportCache>,
+) -> Result<(), EsimMailerError> {
+    let emails = prepare_emails(args, image_path, count)?;
+
+    // Configure SMTP client with TLS
+    let auth_identity = args.auth_identity();
+    let provider: Provider = auth_identity.parse()?;
+    if let Some(proxy) = &args.proxy {
+        // The proxy looks up the mail server itself
+        return send_through_proxy(args, &provider, token, proxy, &emails, on_success);
+    }
+    if args.request_dsn || args.authzid.is_some() {
+        resolve_mail_server(args, &provider, &token)?;
+        return deliver_all(
+            &connection_transport(args, &provider, token),
+            &emails,
+            on_success,
+        );
+    }
+    let create = || {
+        resolve_mail_server(args, &provider, &token)?;
+        Ok(configure_mailer(
+            &provider,
+            auth_identity,
+            token,
+            &args.port_overrides,
+            args.min_tls_version,
+        )?)
+    };
+    match cache {
+        Some(cache) => deliver_all(
+            cache.get_or_try_insert_with(&provider, auth_identity, create)?,
+            &emails,
+            on_success,
+        ),
+        None => deliver_all(&create()?, &emails, on_success),
+    }
+}
+
+/// Sends an eSIM email through `mailer`, which can be shared by the emails
+/// of a batch sent at the same time.
+#[cfg(feature = "async")]
+pub async fn send_email_async(
+    args: &Args,
+    image_path: &Path,
+    count: usize,
+    mailer: &AsyncSmtpTransport<Tokio1Executor>,
+) -> Result<(), EsimMailerError> {
+    for (args, email) in prepare_emails(args, image_path, count)? {
+        rate_limit::wait_for_slot_async(&args).await?;
+        mailer.send(email).await?;
+    }
+    Ok(())
+}
+
+/// Runs the pre-send checks, printing any warnings, then builds the emails.
+fn prepare_emails(
+    args: &Args,
+    image_path: &Path,
+    count: usize,
+) -> Result<Vec<(Args, Message)>, EsimMailerError> {
+    check_from_allowed(args)?;
+    if let Some(warning) = check_from_matches_auth(args)? {
+        eprintln!("Warning: {}", warning);
+    }
+    if let Some(warning) = check_not_self_send(args)? {
+        eprintln!("Warning: {}", warning);
+    }
+    if let Some(warning) = check_no_tracking_pixels(args)? {
+        eprintln!("Warning: {}", warning);
+    }
+
+    build_emails(args, image_path, count)
+}
+
+/// Looks up the provider's mail server ahead of connecting when DNS retries
+/// are configured, so a temporary DNS failure doesn't fail the send.
+fn resolve_mail_server(
+    args: &Args,
+    provider: &Provider,
+    token: &str,
+) -> Result<(), EsimMailerError> {
+    if args.dns_retries == 0 {
+        return Ok(());
+    }
+    let config = provider.transport_config(&args.port_overrides, token);
+    dns::resolve_with_retry(
+        &SystemResolver,
+        &config.host,
+        config.port,
+        args.dns_retries,
+        args.dns_retry_delay.unwrap_or(dns::DEFAULT_DNS_RETRY_DELAY),
+    )
+}
+
+/// Sends the emails over a connection tunnelled through `proxy`.
+#[cfg(feature = "async")]
+fn send_through_proxy(
+    args: &Args,
+    provider: &Provider,
+    token: String,
+    proxy: &ProxyConfig,
+    emails: &[(Args, Message)],
+    on_success: Option<&dyn Fn(&Args)>,
+) -> Result<(), EsimMailerError> {
+    let transport = ProxyTransport {
+        transport: connection_transport(args, provider, token),
+        proxy: proxy.clone(),
+    };
+    deliver_all(&transport, emails, on_success)
+}
+
+#[cfg(not(feature = "async"))]
+fn send_through_proxy(
+    _args: &Args,
+    _provider: &Provider,
+    _token: String,
+    _proxy: &ProxyConfig,
+    _emails: &[(Args, Message)],
+    _on_success: Option<&dyn Fn(&Args)>,
+) -> Result<(), EsimMailerError> {
+    Err(EsimMailerError::ConfigError(
+        "Sending through a proxy requires the async feature".to_string(),
+    ))
+}
+
+fn connection_transport(args: &Args, provider: &Provider, token: String) -> ConnectionTransport {
+    ConnectionTransport {
+        config: provider.transport_config(&args.port_overrides, &token),
+        auth_identity: args.auth_identity().to_string(),
+        token,
+        authzid: args.authzid.clone().filter(|authzid| !authzid.is_empty()),
+        request_dsn: args.request_dsn,
+        min_tls_version: args.min_tls_version,
+        handshake_retry: handshake_retry_policy(args),
+    }
+}
+
+/// The SMTP settings an email would be sent with, without any credentials.
+#[derive(Debug, PartialEq, Eq)]
+pub struct TransportDescription {
+    pub provider: Provider,
+    pub host: String,
+    pub port: u16,
+    pub tls: TlsMode,
+    pub mechanism: Mechanism,
+    /// The SOCKS5 proxy connected through, as `host:port`
+    pub proxy: Option<String>,
+}
+
+impl Display for TransportDescription {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let tls = match self.tls {
+            TlsMode::StartTls => "STARTTLS",
+            TlsMode::Implicit => "implicit TLS",
+        };
+        write!(
+            f,
+            "{} via {}:{} using {}, authenticating with {}",
+            self.provider, self.host, self.port, tls, self.mechanism
+        )?;
+        if let Some(proxy) = &self.proxy {
+            write!(f, ", through the proxy {}", proxy)?;
+        }
+        Ok(())
+    }
+}
+
+/// Describes the SMTP settings used to send with `args`, for troubleshooting.
+/// The mechanism assumes an OAuth token unless a token is given in `args`,
+/// a token file or the environment.
+pub fn describe_transport(args: &Args) -> Result<TransportDescription, EsimMailerError> {
+    let provider: Provider = args.auth_identity().parse()?;
+    let token = resolve_token(args)?.unwrap_or_default();
+    let config = provider.transport_config(&args.port_overrides, &token);
+    Ok(TransportDescription {
+        provider,
+        host: config.host,
+        port: config.port,
+        tls: config.tls,
+        mechanism: config.mechanism,
+        proxy: args
+            .proxy
+            .as_ref()
+            .map(|proxy| format!("{}:{}", proxy.host, proxy.port)),
+    })
+}
+
+/// SMTP transports kept across the emails of a batch, one for each provider
+/// and account, so sends from the same account reuse a connection.
+pub struct TransportCache<T = SmtpTransport> {
+    transports: HashMap<(String, String), T>,
+}
+
+impl<T> Default for TransportCache<T> {
+    fn default() -> Self {
+        Self {
+            transports: HashMap::new(),
+        }
+    }
+}
+
+impl<T> TransportCache<T> {
+    /// Returns the transport for the provider and account, creating it with
+    /// `create` if there isn't one yet. Accounts are matched
+    /// case-insensitively.
+    pub fn get_or_try_insert_with<F>(
+        &mut self,
+        provider: &Provider,
+        auth_identity: &str,
+        create: F,
+    ) -> Result<&T, EsimMailerError>
+    where
+        F: FnOnce() -> Result<T, EsimMailerError>,
+    {
+        let key = (provider.to_string(), auth_identity.to_ascii_lowercase());
+        match self.transports.entry(key) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => Ok(entry.insert(create()?)),
+        }
+    }
+
+    /// Returns the number of transports in the cache.
+    pub fn len(&self) -> usize {
+        self.transports.len()
+    }
+
+    /// Returns whether the cache has no transports.
+    pub fn is_empty(&self) -> bool {
+        self.transports.is_empty()
+    }
+}
+
+fn deliver_all<T>(
+    mailer: &T,
+    emails: &[(Args, Message)],
+    on_success: Option<&dyn Fn(&Args)>,
+) -> Result<(), EsimMailerError>
+where
+    T: Transport + Handshake,
+    T::Ok: ServerReply,
+    T::Error: Error,
+    EsimMailerError: From<T::Error>,
+{
+    // Get through connecting and authenticating first when that's retried,
+    // so a hiccup there doesn't count against the send itself
+    if let Some(policy) = emails
+        .first()
+        .and_then(|(args, _)| handshake_retry_policy(args))
+    {
+        policy.run(|| mailer.handshake())?;
+    }
+    for (args, email) in emails {
+        rate_limit::wait_for_slot(args)?;
+        deliver(mailer, email, args, on_success)?;
+    }
+    Ok(())
+}
+
+/// The delay between attempts at the handshake when no other is set.
+const DEFAULT_HANDSHAKE_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Returns how to retry connecting and authenticating, or `None` if it
+/// shouldn't be retried. Rejected credentials are retried too, since servers
+/// occasionally reject a valid OAuth token.
+fn handshake_retry_policy(args: &Args) -> Option<RetryPolicy> {
+    (args.handshake_retries > 0).then(|| RetryPolicy {
+        max_attempts: args.handshake_retries + 1,
+        delay: args
+            .handshake_retry_delay
+            .unwrap_or(DEFAULT_HANDSHAKE_RETRY_DELAY),
+        retry_on: vec![
+            ErrorCategory::Transient,
+            ErrorCategory::Network,
+            ErrorCategory::Auth,
+        ],
+    })
+}
+
+// Trait for connecting and authenticating with the server ahead of sending
+trait Handshake {
+    fn handshake(&self) -> Result<(), EsimMailerError>;
+}
+
+impl Handshake for SmtpTransport {
+    fn handshake(&self) -> Result<(), EsimMailerError> {
+        // With connection pooling, the connection is kept for the send
+        if !self.test_connection()? {
+            return Err(EsimMailerError::NetworkError(
+                "The connection was closed after authenticating".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Trait for describing how the server replied when it accepted an email
+trait ServerReply {
+    fn reply(&self) -> Option<String>;
+}
+
+impl ServerReply for Response {
+    fn reply(&self) -> Option<String> {
+        let message = self.message().collect::<Vec<_>>().join(" ");
+        Some(
+            format!("{} {}", self.code(), message)
+                .trim_end()
+                .to_string(),
+        )
+    }
+}
+
+impl ServerReply for () {
+    fn reply(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Sends a single email, returning the server's reply if the transport
+/// reports one. The reply is also printed, for audit trails.
+fn deliver<T>(
+    mailer: &T,
+    email: &Message,
+    args: &Args,
+    on_success: Option<&dyn Fn(&Args)>,
+) -> Result<Option<String>, EsimMailerError>
+where
+    T: Transport,
+    T::Ok: ServerReply,
+    T::Error: Error,
+    EsimMailerError: From<T::Error>,
+{
+    // Send the email, in several sends if the BCC list is too long for one
+    let result = match args
+        .bcc_chunk_size
+        .and_then(|chunk_size| chunked_envelopes(email, chunk_size))
+    {
+        Some(envelopes) => send_chunks(mailer, email, &envelopes),
+        None => mailer.send(email).map_err(EsimMailerError::from),
+    };
+    match result {
+        Ok(response) => {
+            if let Some(on_success) = on_success {
+                on_success(args);
+            }
+            let reply = response.reply();
+            match &reply {
+                Some(reply) => println!("Email sent successfully! Server replied: {}", reply),
+                None => println!("Email sent successfully!"),
+            }
+            Ok(reply)
+        }
+        Err(e) => {
+            eprintln!("Could not send email: {:?}", e);
+            if let Some(source) = e.source() {
+                eprintln!("Error source: {:?}", source);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Splits the email's BCC recipients into envelopes of at most `chunk_size`,
+/// the first of which also holds the To and CC recipients. Returns `None` if
+/// every recipient fits in one send.
+fn chunked_envelopes(email: &Message, chunk_size: usize) -> Option<Vec<Envelope>> {
+    let envelope = email.envelope();
+    let visible: Vec<Address> = [
+        email.headers().get::<header::To>().map(Mailboxes::from),
+        email.headers().get::<header::Cc>().map(Mailboxes::from),
+    ]
+    .into_iter()
+    .flatten()
+    .flatten()
+    .map(|mailbox| mailbox.email)
+    .collect();
+    let (to, bcc): (Vec<Address>, Vec<Address>) = envelope
+        .to()
+        .iter()
+        .cloned()
+        .partition(|address| visible.contains(address));
+    if chunk_size == 0 || bcc.len() <= chunk_size {
+        return None;
+    }
+
+    let envelopes = bcc
+        .chunks(chunk_size)
+        .enumerate()
+        .filter_map(|(index, chunk)| {
+            let recipients = match index {
+                0 => to.iter().chain(chunk).cloned().collect(),
+                _ => chunk.to_vec(),
+            };
+            Envelope::new(envelope.from().cloned(), recipients).ok()
+        })
+        .collect();
+    Some(envelopes)
+}
+
+/// Sends the same email to each envelope in turn, carrying on past failures
+/// so one bad chunk doesn't stop the rest. Returns the first delivered
+/// chunk's reply, or the first error if no chunk was delivered.
+///
+/// If only some chunks were delivered, the error names the recipients which
+/// were missed and isn't retried, since retrying the email would send it to
+/// the delivered chunks again.
+fn send_chunks<T>(
+    mailer: &T,
+    email: &Message,
+    envelopes: &[Envelope],
+) -> Result<T::Ok, EsimMailerError>
+where
+    T: Transport,
+    T::Error: Display,
+    EsimMailerError: From<T::Error>,
+{
+    let formatted = email.formatted();
+    let mut reply = None;
+    let mut error = None;
+    let mut delivered = 0;
+    let mut missed = Vec::new();
+    for (index, envelope) in envelopes.iter().enumerate() {
+        match mailer.send_raw(envelope, &formatted) {
+            Ok(sent) => {
+                delivered += 1;
+                reply.get_or_insert(sent);
+            }
+            Err(e) => {
+                eprintln!(
+                    "Could not send to recipient chunk {} of {}: {}",
+                    index + 1,
+                    envelopes.len(),
+                    e
+                );
+                missed.extend(envelope.to().iter().map(Address::to_string));
+                error.get_or_insert(e);
+            }
+        }
+    }
+    match (reply, error) {
+        (Some(reply), None) => Ok(reply),
+        (None, Some(e)) => Err(e.into()),
+        (Some(_), Some(e)) => Err(EsimMailerError::SmtpError(format!(
+            "Sent to {} of {} recipient chunks, but not to {}: {}",
+            delivered,
+            envelopes.len(),
+            missed.join(", "),
+            e
+        ))),
+        (None, None) => mailer.send(email).map_err(EsimMailerError::from),
+    }
+}
+
+/// Checks whether the sender matches the account used to authenticate, since
+/// providers like Gmail reject mail sent From another address.
+///
+/// Returns a warning or an error depending on the `from_mismatch` policy.
+pub fn check_from_matches_auth(args: &Args) -> Result<Option<String>, EsimMailerError> {
+    let from = parse_mailbox(&args.email_from)?;
+    let auth = parse_mailbox(args.auth_identity())?;
+    if from
+        .email
+        .to_string()
+        .eq_ignore_ascii_case(auth.email.as_ref())
+    {
+        return Ok(None);
+    }
+
+    let problem = format!(
+        "Sending from '{}' but authenticating as '{}'",
+        from.email, auth.email
+    );
+    apply_check_policy(args.from_mismatch, problem)
+}
+
+/// Checks that the sender is on the sending provider's list of allowed
+/// senders, so mail isn't sent from an alias the provider hasn't verified.
+/// Providers without a list, or with an empty one, allow any sender.
+pub fn check_from_allowed(args: &Args) -> Result<(), EsimMailerError> {
+    let Ok(provider) = args.auth_identity().parse::<Provider>() else {
+        return Ok(());
+    };
+    let Some(allowed) = provider
+        .setting(&args.allowed_from)
+        .filter(|allowed| !allowed.is_empty())
+    else {
+        return Ok(());
+    };
+
+    let from = parse_mailbox(&args.email_from)?;
+    let is_allowed = allowed.iter().any(|address| {
+        split_name_and_address(address)
+            .1
+            .eq_ignore_ascii_case(from.email.as_ref())
+    });
+    if !is_allowed {
+        return Err(EsimMailerError::ConfigError(format!(
+            "'{}' isn't an allowed sender for {}",
+            from.email, provider
+        )));
+    }
+    Ok(())
+}
+
+/// Checks whether the recipient is the sender, which usually means the To
+/// field was left filled in by mistake. Copying yourself through CC or BCC
+/// isn't affected.
+///
+/// Returns a warning or an error depending on the `self_send` policy.
+pub fn check_not_self_send(args: &Args) -> Result<Option<String>, EsimMailerError> {
+    let from = parse_mailbox(&args.email_from)?;
+    let to = parse_mailbox(&args.email_to)?;
+    if !from
+        .email
+        .to_string()
+        .eq_ignore_ascii_case(to.email.as_ref())
+    {
+        return Ok(None);
+    }
+
+    let problem = format!("Sending to the sender's own address '{}'", from.email);
+    apply_check_policy(args.self_send, problem)
+}
+
+/// Checks the rendered body for images which could track when the email is
+/// opened: 1x1 images and images loaded from another server.
+///
+/// Returns a warning or an error depending on the `tracking_pixels` policy.
+pub fn check_no_tracking_pixels(args: &Args) -> Result<Option<String>, EsimMailerError> {
+    let body = html_body(&EmailTemplate::load(args)?, args, &SystemClock);
+    let Some(src) = tracking_image(&body) else {
+        return Ok(None);
+    };
+
+    let problem = format!("The body contains a possible tracking image '{}'", src);
+    apply_check_policy(args.tracking_pixels, problem)
+}
+
+/// The longest subject most inboxes show in full, in characters.
+const RECOMMENDED_SUBJECT_CHARS: usize = 78;
+
+/// Returns warnings about settings which make the email more likely to end
+/// up in spam or be shown badly, without stopping it from being sent.
+pub fn deliverability_warnings(args: &Args, image_path: &Path) -> Vec<String> {
+    let mut warnings = Vec::new();
+
+    if args
+        .list_unsubscribe
+        .as_deref()
+        .is_none_or(|list_unsubscribe| list_unsubscribe.trim().is_empty())
+    {
+        warnings.push(
+            "There's no List-Unsubscribe header, which providers expect from bulk senders"
+                .to_string(),
+        );
+    }
+
+    if args.activation_code.is_none()
+        && args.image_url.is_none()
+        && let Ok(provider) = args.auth_identity().parse::<Provider>()
+        && let Ok(metadata) = fs::metadata(image_path)
+    {
+        let max_bytes = max_image_bytes(args, &provider);
+        if metadata.len() > max_bytes as u64 {
+            warnings.push(format!(
+                "The QR code image is {} bytes, more than the {} bytes {} handles well",
+                metadata.len(),
+                max_bytes,
+                provider
+            ));
+        }
+    }
+
+    let subject = EmailTemplate::load(args)
+        .unwrap_or_else(|_| EmailTemplate::for_args(args))
+        .subject(args, 1);
+    if subject.chars().count() > RECOMMENDED_SUBJECT_CHARS {
+        warnings.push(format!(
+            "The subject is {} characters, so most inboxes will cut it off after {}",
+            subject.chars().count(),
+            RECOMMENDED_SUBJECT_CHARS
+        ));
+    }
+
+    let from_mismatch = Args {
+        from_mismatch: CheckPolicy::Warn,
+        ..args.clone()
+    };
+    if let Ok(Some(warning)) = check_from_matches_auth(&from_mismatch) {
+        warnings.push(warning);
+    }
+
+    if !args.plain_text {
+        warnings.push(
+            "There's no plain text version of the body, which spam filters count against HTML-only email"
+                .to_string(),
+        );
+    }
+
+    warnings
+}
+
+/// Returns the source of the first image in the HTML which is 1x1 or is
+/// loaded from another server.
+fn tracking_image(html: &str) -> Option<&str> {
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        let end = rest[start..]
+            .find('>')
+            .map_or(rest.len(), |end| start + end);
+        let tag = &rest[start + 1..end];
+        rest = &rest[end..];
+
+        let is_img = tag
+            .get(..3)
+            .is_some_and(|name| name.eq_ignore_ascii_case("img"))
+            && tag[3..].starts_with(char::is_whitespace);
+        if !is_img {
+            continue;
+        }
+        let src = attribute(tag, "src").unwrap_or_default();
+        let is_remote = ["http://", "https://", "//"]
+            .iter()
+            .any(|scheme| src.to_ascii_lowercase().starts_with(scheme));
+        let style: String = attribute(tag, "style")
+            .unwrap_or_default()
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<String>()
+            .to_ascii_lowercase();
+        let is_one_pixel = |dimension: &str| {
+            attribute(tag, dimension).is_some_and(|value| matches!(value.trim(), "1" | "1px"))
+                || style
+                    .split(';')
+                    .any(|declaration| declaration == format!("{}:1px", dimension))
+        };
+        if is_remote || (is_one_pixel("width") && is_one_pixel("height")) {
+            return Some(src);
+        }
+    }
+    None
+}
+
+fn apply_check_policy(
+    policy: CheckPolicy,
+    problem: String,
+) -> Result<Option<String>, EsimMailerError> {
+    match policy {
+        CheckPolicy::Allow => Ok(None),
+        CheckPolicy::Warn => Ok(Some(problem)),
+        CheckPolicy::Error => Err(EsimMailerError::ConfigError(problem)),
+    }
+}
+
+/// Shortens `text` to at most `max_chars` characters, replacing the end
+/// with `…` if anything was cut.
+fn truncate_with_ellipsis(text: String, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text;
+    }
+    let mut truncated: String = text.chars().take(max_chars.saturating_sub(1)).collect();
+    truncated.truncate(truncated.trim_end().len());
+    if max_chars > 0 {
+        truncated.push('…');
+    }
+    truncated
+}
+
+/// The longest a header line may be in octets, per RFC 5322 section 2.1.1.
+const MAX_HEADER_LINE_OCTETS: usize = 998;
+
+/// Rejects subjects which would be too long once encoded, since some servers
+/// refuse them with an unhelpful error.
+fn check_subject_length(subject: &str) -> Result<(), EsimMailerError> {
+    let mut headers = Headers::new();
+    headers.set(header::Subject::from(subject.to_string()));
+    // Unfold the encoded header to measure it as a single logical line.
+    let encoded = headers.to_string().replace("\r\n", "");
+    if encoded.len() > MAX_HEADER_LINE_OCTETS {
+        return Err(EsimMailerError::MessageError(format!(
+            "Subject is {} octets once encoded, the limit is {}",
+            encoded.len(),
+            MAX_HEADER_LINE_OCTETS
+        )));
+    }
+    Ok(())
+}
+
+/// The largest rendered body allowed when no other limit is set, far beyond
+/// any reasonable template.
+pub const DEFAULT_MAX_BODY_BYTES: usize = 1024 * 1024;
+
+/// Rejects bodies larger than the configured limit, which usually means a
+/// template or value has run away.
+fn check_body_length(args: &Args, body: &str) -> Result<(), EsimMailerError> {
+    let max_body_bytes = args.max_body_bytes.unwrap_or(DEFAULT_MAX_BODY_BYTES);
+    if body.len() > max_body_bytes {
+        return Err(EsimMailerError::MessageError(format!(
+            "Body is {} bytes, the limit is {}",
+            body.len(),
+            max_body_bytes
+        )));
+    }
+    Ok(())
+}
+
+/// The most images a body may embed unless another limit is configured.
+pub const DEFAULT_MAX_INLINE_IMAGES: usize = 3;
+
+/// Rejects bodies embedding more `cid:` images than the configured limit,
+/// e.g. a template repeating the QR code over and over.
+fn check_inline_image_count(args: &Args, body: &str) -> Result<(), EsimMailerError> {
+    let max_inline_images = args.max_inline_images.unwrap_or(DEFAULT_MAX_INLINE_IMAGES);
+    let mut count = 0;
+    let mut rest = body;
+    while let Some(start) = rest.find('<') {
+        let end = rest[start..]
+            .find('>')
+            .map_or(rest.len(), |end| start + end);
+        let tag = &rest[start + 1..end];
+        rest = &rest[end..];
+
+        let is_img = tag
+            .get(..3)
+            .is_some_and(|name| name.eq_ignore_ascii_case("img"))
+            && tag[3..].starts_with(char::is_whitespace);
+        let is_inline = attribute(tag, "src")
+            .is_some_and(|src| src.trim().to_ascii_lowercase().starts_with("cid:"));
+        if is_img && is_inline {
+            count += 1;
+        }
+    }
+    if count > max_inline_images {
+        return Err(EsimMailerError::MessageError(format!(
+            "Body embeds {} inline images, the limit is {}",
+            count, max_inline_images
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that the value looks like an RFC 5322 msg-id, e.g. `<id@example.com>`.
+fn check_msg_id(id: &str) -> Result<&str, EsimMailerError> {
+    let is_msg_id = id
+        .strip_prefix('<')
+        .and_then(|id| id.strip_suffix('>'))
+        .and_then(|id| id.split_once('@'))
+        .is_some_and(|(left, right)| {
+            !left.is_empty()
+                && !right.is_empty()
+                && !id.contains(|c: char| c.is_whitespace() || c.is_control())
+        });
+    if is_msg_id {
+        Ok(id)
+    } else {
+        Err(EsimMailerError::MessageError(format!(
+            "Invalid Message-ID '{}'",
+            id
+        )))
+    }
+}
+
+pub(crate) fn parse_mailbox(address: &str) -> Result<Mailbox, EsimMailerError> {
+    address
+        .parse()
+        .map_err(|e| EsimMailerError::MessageError(format!("Invalid address '{}': {}", address, e)))
+}
+
+fn parse_recipient(args: &Args, address: &str) -> Result<Mailbox, EsimMailerError> {
+    let mailbox = parse_mailbox(address)?;
+    if args.reject_disposable_recipients && is_disposable_domain(mailbox.email.domain()) {
+        return Err(EsimMailerError::ConfigError(format!(
+            "Refusing to send to disposable email address '{}'",
+            mailbox.email
+        )));
+    }
+    Ok(mailbox)
+}
+
+/// Returns the addresses to BCC: the comma-separated `bcc` list followed by
+/// the addresses in `bcc_group_file`, without duplicates. Blank lines and
+/// lines starting with `#` in the group file are ignored.
+fn bcc_mailboxes(args: &Args) -> Result<Vec<Mailbox>, EsimMailerError> {
+    let mut mailboxes = parse_bcc_list(
+        args,
+        split_addresses(args.bcc.as_deref().unwrap_or_default()),
+    )?;
+
+    if let Some(path) = &args.bcc_group_file {
+        let contents = fs::read_to_string(path).map_err(|e| {
+            EsimMailerError::ConfigError(format!(
+                "Could not read BCC group file '{}': {}",
+                path.display(),
+                e
+            ))
+        })?;
+        let group = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'));
+        for mailbox in parse_bcc_list(args, group)? {
+            let is_duplicate = mailboxes.iter().any(|existing| {
+                existing
+                    .email
+                    .to_string()
+                    .eq_ignore_ascii_case(mailbox.email.as_ref())
+            });
+            if !is_duplicate {
+                mailboxes.push(mailbox);
+            }
+        }
+    }
+    Ok(mailboxes)
+}
+
+/// Parses BCC addresses. Invalid addresses are either an error or skipped
+/// with a warning, depending on `invalid_bcc`.
+fn parse_bcc_list<'a>(
+    args: &Args,
+    addresses: impl IntoIterator<Item = &'a str>,
+) -> Result<Vec<Mailbox>, EsimMailerError> {
+    let mut mailboxes = Vec::new();
+    for address in addresses {
+        match parse_recipient(args, address) {
+            Ok(mailbox) => mailboxes.push(mailbox),
+            Err(e) if args.invalid_bcc == InvalidBccPolicy::Skip => {
+                eprintln!("Warning: Skipping BCC address: {}", e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(mailboxes)
+}
+
+/// Splits a comma-separated list of addresses, ignoring commas in quoted
+/// display names and angle brackets. Empty entries are dropped.
+fn split_addresses(list: &str) -> Vec<&str> {
+    let mut addresses = Vec::new();
+    let mut in_quotes = false;
+    let mut in_brackets = false;
+    let mut start = 0;
+    for (index, c) in list.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '<' if !in_quotes => in_brackets = true,
+            '>' if !in_quotes => in_brackets = false,
+            ',' if !in_quotes && !in_brackets => {
+                addresses.push(list[start..index].trim());
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    addresses.push(list[start..].trim());
+    addresses.retain(|address| !address.is_empty());
+    addresses
+}
+
+/// Shrinks the image if it's larger than the provider it's sent through
+/// allows, from `max_image_bytes` or else the provider's default. Images
+/// which can't be shrunk are sent as they are, with a warning.
+fn fit_image_to_provider(args: &Args, image_data: Vec<u8>) -> Vec<u8> {
+    let Ok(provider) = args.auth_identity().parse::<Provider>() else {
+        return image_data;
+    };
+    let max_bytes = max_image_bytes(args, &provider);
+    if image_data.len() <= max_bytes {
+        return image_data;
+    }
+
+    match shrink_png(&image_data, max_bytes) {
+        Ok(shrunk) => shrunk,
+        Err(e) => {
+            eprintln!(
+                "Warning: Could not shrink the {} byte image for {}: {}",
+                image_data.len(),
+                provider,
+                e
+            );
+            image_data
+        }
+    }
+}
+
+/// Returns the largest image to send through `provider`, as configured or
+/// the provider's default.
+fn max_image_bytes(args: &Args, provider: &Provider) -> usize {
+    provider
+        .setting(&args.max_image_bytes)
+        .copied()
+        .unwrap_or_else(|| provider.max_image_bytes())
+}
+
+#[cfg(feature = "qr")]
+fn shrink_png(data: &[u8], max_bytes: usize) -> Result<Vec<u8>, EsimMailerError> {
+    crate::qr::shrink_png(data, max_bytes)
+}
+
+#[cfg(not(feature = "qr"))]
+fn shrink_png(_data: &[u8], _max_bytes: usize) -> Result<Vec<u8>, EsimMailerError> {
+    Err(EsimMailerError::ConfigError(
+        "Shrinking images requires the qr feature".to_string(),
+    ))
+}
+
+#[cfg(feature = "qr")]
+fn activation_code_png(activation_code: &str) -> Result<Vec<u8>, EsimMailerError> {
+    crate::qr::activation_code_png(activation_code)
+}
+
+#[cfg(not(feature = "qr"))]
+fn activation_code_png(_activation_code: &str) -> Result<Vec<u8>, EsimMailerError> {
+    Err(EsimMailerError::ConfigError(
+        "Generating QR codes requires the qr feature".to_string(),
+    ))
+}
+
+fn image_content_type(args: &Args) -> Result<header::ContentType, EsimMailerError> {
+    let content_type = args.image_content_type.as_deref().unwrap_or("image/png");
+    header::ContentType::parse(content_type).map_err(|e| {
+        EsimMailerError::MessageError(format!(
+            "Invalid image content type '{}': {}",
+            content_type, e
+        ))
+    })
+}
+
+fn configure_mailer(
+    provider: &Provider,
+    email_address: &str,
+    token: String,
+    port_overrides: &HashMap<String, u16>,
+    min_tls_version: Option<TlsVersion>,
+) -> io::Result<SmtpTransport> {
+    let config = provider.transport_config(port_overrides, &token);
+    let tls_parameters = tls_parameters(&config.host, min_tls_version).map_err(io::Error::other)?;
+    let tls = match config.tls {
+        TlsMode::StartTls => Tls::Required(tls_parameters),
+        TlsMode::Implicit => Tls::Wrapper(tls_parameters),
+    };
+
+    Ok(SmtpTransport::relay(&config.host)
+        .map_err(io::Error::other)?
+        .credentials(Credentials::new(email_address.to_string(), token))
+        .authentication(vec![config.mechanism])
+        .port(config.port)
+        .tls(tls)
+        .build())
+}
+
+/// Builds an async transport for the account `args` sends from, keeping
+/// connections to the server in a pool configured by `pool_config`.
+#[cfg(feature = "async")]
+pub fn configure_async_mailer(
+    args: &Args,
+    token: String,
+    pool_config: PoolConfig,
+) -> Result<AsyncSmtpTransport<Tokio1Executor>, EsimMailerError> {
+    if args.proxy.is_some() {
+        return Err(EsimMailerError::ConfigError(
+            "Sending through a proxy isn't supported when sending asynchronously".to_string(),
+        ));
+    }
+    let auth_identity = args.auth_identity();
+    let provider: Provider = auth_identity.parse()?;
+    let config = provider.transport_config(&args.port_overrides, &token);
+    let tls_parameters = tls_parameters(&config.host, args.min_tls_version)?;
+    let tls = match config.tls {
+        TlsMode::StartTls => Tls::Required(tls_parameters),
+        TlsMode::Implicit => Tls::Wrapper(tls_parameters),
+    };
+
+    Ok(AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host)?
+        .credentials(Credentials::new(auth_identity.to_string(), token))
+        .authentication(vec![config.mechanism])
+        .port(config.port)
+        .tls(tls)
+        .pool_config(pool_config)
+        .build())
+}
+
+/// Returns the TLS settings for connecting to `host`, refusing TLS versions
+/// older than `min_tls_version` if one is set.
+fn tls_parameters(
+    host: &str,
+    min_tls_version: Option<TlsVersion>,
+) -> Result<TlsParameters, smtp::Error> {
+    let mut builder = TlsParameters::builder(host.to_string());
+    if let Some(min_tls_version) = min_tls_version {
+        builder = builder.set_min_tls_version(match min_tls_version {
+            TlsVersion::Tls10 => client::TlsVersion::Tlsv10,
+            TlsVersion::Tls11 => client::TlsVersion::Tlsv11,
+            TlsVersion::Tls12 => client::TlsVersion::Tlsv12,
+            TlsVersion::Tls13 => client::TlsVersion::Tlsv13,
+        });
+    }
+    builder.build()
+}
+
+/// How long to wait on the server when sending with [`ConnectionTransport`].
+const CONNECTION_TRANSPORT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Sends over a single SMTP connection driven command by command, for what
+/// `SmtpTransport` has no way to do: passing the MAIL and RCPT parameters
+/// delivery status notifications need, and authenticating with an
+/// authorization identity.
+struct ConnectionTransport {
+    config: SmtpConfig,
+    auth_identity: String,
+    token: String,
+    authzid: Option<String>,
+    request_dsn: bool,
+    min_tls_version: Option<TlsVersion>,
+    handshake_retry: Option<RetryPolicy>,
+}
+
+impl Transport for ConnectionTransport {
+    type Ok = Response;
+    type Error = EsimMailerError;
+
+    fn send_raw(&self, envelope: &Envelope, email: &[u8]) -> Result<Response, EsimMailerError> {
+        let (mut connection, ehlo) = match &self.handshake_retry {
+            Some(policy) => policy.run(|| Ok(self.connect()?))?,
+            None => self.connect()?,
+        };
+        let (mail_parameters, rcpt_parameters) = self.parameters(&ehlo);
+
+        connection.command(Mail::new(envelope.from().cloned(), mail_parameters))?;
+        for recipient in envelope.to() {
+            connection.command(Rcpt::new(recipient.clone(), rcpt_parameters.clone()))?;
+        }
+        connection.command(Data)?;
+        let response = connection.message(email)?;
+        connection.quit()?;
+        Ok(response)
+    }
+}
+
+// Each send opens its own connection, retrying the handshake there
+impl Handshake for ConnectionTransport {
+    fn handshake(&self) -> Result<(), EsimMailerError> {
+        Ok(())
+    }
+}
+
+impl ConnectionTransport {
+    /// Connects and authenticates, returning the connection along with the
+    /// server's capabilities.
+    fn connect(&self) -> Result<(SmtpConnection, Response), smtp::Error> {
+        let tls_parameters = tls_parameters(&self.config.host, self.min_tls_version)?;
+        let hello_name = ClientId::default();
+        let mut connection = SmtpConnection::connect(
+            (self.config.host.as_str(), self.config.port),
+            Some(CONNECTION_TRANSPORT_TIMEOUT),
+            &hello_name,
+            (self.config.tls == TlsMode::Implicit).then_some(&tls_parameters),
+            None,
+        )?;
+        if self.config.tls == TlsMode::StartTls {
+            connection.starttls(&tls_parameters, &hello_name)?;
+        }
+
+        // lettre doesn't keep track of DSN support, so ask for the
+        // capabilities again to see whether the server advertises it.
+        let ehlo = connection.command(Ehlo::new(hello_name))?;
+        authenticate(
+            &mut connection,
+            self.config.mechanism,
+            &self.credentials(),
+            self.auth_plain(),
+        )?;
+        Ok((connection, ehlo))
+    }
+
+    fn credentials(&self) -> Credentials {
+        Credentials::new(self.auth_identity.clone(), self.token.clone())
+    }
+
+    fn auth_plain(&self) -> Option<AuthPlain<'_>> {
+        self.authzid.as_deref().map(|authzid| AuthPlain {
+            authzid,
+            authcid: &self.auth_identity,
+            password: &self.token,
+        })
+    }
+
+    /// Returns the MAIL and RCPT parameters to send with, given the server's
+    /// capabilities.
+    fn parameters(&self, ehlo: &Response) -> (Vec<MailParameter>, Vec<RcptParameter>) {
+        if !self.request_dsn {
+            return (Vec::new(), Vec::new());
+        }
+        let parameters = dsn_parameters(ehlo);
+        if parameters.1.is_empty() {
+            eprintln!("Warning: The server doesn't support delivery status notifications");
+        }
+        parameters
+    }
+}
+
+/// Sends like [`ConnectionTransport`], but over a connection tunnelled
+/// through a SOCKS5 proxy. lettre can only run SMTP over a stream it didn't
+/// open itself with its async connection, so each send runs on a runtime of
+/// its own.
+#[cfg(feature = "async")]
+struct ProxyTransport {
+    transport: ConnectionTransport,
+    proxy: ProxyConfig,
+}
+
+#[cfg(feature = "async")]
+impl Transport for ProxyTransport {
+    type Ok = Response;
+    type Error = EsimMailerError;
+
+    fn send_raw(&self, envelope: &Envelope, email: &[u8]) -> Result<Response, EsimMailerError> {
+        self.block_on(async {
+            let (mut connection, ehlo) = match &self.transport.handshake_retry {
+                Some(policy) => policy.run_async(|| self.connect()).await?,
+                None => self.connect().await?,
+            };
+            let (mail_parameters, rcpt_parameters) = self.transport.parameters(&ehlo);
+
+            connection
+                .command(Mail::new(envelope.from().cloned(), mail_parameters))
+                .await?;
+            for recipient in envelope.to() {
+                connection
+                    .command(Rcpt::new(recipient.clone(), rcpt_parameters.clone()))
+                    .await?;
+            }
+            connection.command(Data).await?;
+            let response = connection.message(email).await?;
+            connection.quit().await?;
+            Ok(response)
+        })
+    }
+}
+
+// Each send opens its own connection, retrying the handshake there
+#[cfg(feature = "async")]
+impl Handshake for ProxyTransport {
+    fn handshake(&self) -> Result<(), EsimMailerError> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "async")]
+impl ProxyTransport {
+    /// Runs `future` to completion, giving up after
+    /// [`CONNECTION_TRANSPORT_TIMEOUT`].
+    fn block_on<T>(
+        &self,
+        future: impl Future<Output = Result<T, EsimMailerError>>,
+    ) -> Result<T, EsimMailerError> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .enable_time()
+            .build()?;
+        runtime.block_on(async {
+            tokio::time::timeout(CONNECTION_TRANSPORT_TIMEOUT, future)
+                .await
+                .map_err(|_| {
+                    EsimMailerError::NetworkError("Timed out sending through the proxy".to_string())
+                })?
+        })
+    }
+
+    /// Opens the tunnel, then connects and authenticates over it, returning
+    /// the connection along with the server's capabilities.
+    async fn connect(&self) -> Result<(AsyncSmtpConnection, Response), EsimMailerError> {
+        let config = &self.transport.config;
+        let tunnel = proxy::connect(
+            &self.proxy,
+            &config.host,
+            config.port,
+            CONNECTION_TRANSPORT_TIMEOUT,
+        )?;
+        tunnel.set_nonblocking(true)?;
+        let tunnel = tokio::net::TcpStream::from_std(tunnel)?;
+        let stream: Box<dyn AsyncTokioStream> = match config.tls {
+            TlsMode::StartTls => Box::new(tunnel),
+            TlsMode::Implicit => {
+                let connector = tls_connector(self.transport.min_tls_version)?;
+                let stream = tokio_native_tls::TlsConnector::from(connector)
+                    .connect(&config.host, tunnel)
+                    .await
+                    .map_err(|e| EsimMailerError::TlsError(e.to_string()))?;
+                Box::new(TlsTunnel(stream))
+            }
+        };
+
+        let hello_name = ClientId::default();
+        let mut connection =
+            AsyncSmtpConnection::connect_with_transport(stream, &hello_name).await?;
+        if config.tls == TlsMode::StartTls {
+            let tls_parameters = tls_parameters(&config.host, self.transport.min_tls_version)?;
+            connection.starttls(tls_parameters, &hello_name).await?;
+        }
+
+        let ehlo = connection.command(Ehlo::new(hello_name)).await?;
+        match self.transport.auth_plain() {
+            Some(auth_plain) if config.mechanism == Mechanism::Plain => {
+                connection.command(auth_plain).await?
+            }
+            _ => {
+                connection
+                    .auth(&[config.mechanism], &self.transport.credentials())
+                    .await?
+            }
+        };
+        Ok((connection, ehlo))
+    }
+}
+
+/// Returns a TLS connector for servers using implicit TLS, refusing TLS
+/// versions older than `min_tls_version` if one is set.
+#[cfg(feature = "async")]
+fn tls_connector(
+    min_tls_version: Option<TlsVersion>,
+) -> Result<native_tls::TlsConnector, EsimMailerError> {
+    let min_protocol_version = match min_tls_version {
+        None => None,
+        Some(TlsVersion::Tls10) => Some(native_tls::Protocol::Tlsv10),
+        Some(TlsVersion::Tls11) => Some(native_tls::Protocol::Tlsv11),
+        Some(TlsVersion::Tls12) => Some(native_tls::Protocol::Tlsv12),
+        Some(TlsVersion::Tls13) => {
+            return Err(EsimMailerError::TlsError(
+                "The TLS library can't require TLS 1.3".to_string(),
+            ));
+        }
+    };
+    native_tls::TlsConnector::builder()
+        .min_protocol_version(min_protocol_version)
+        .build()
+        .map_err(|e| EsimMailerError::TlsError(e.to_string()))
+}
+
+/// A TLS stream through the proxy, for servers using implicit TLS, which
+/// lettre can't start on a stream it was given.
+#[cfg(feature = "async")]
+#[derive(Debug)]
+struct TlsTunnel(tokio_native_tls::TlsStream<tokio::net::TcpStream>);
+
+#[cfg(feature = "async")]
+impl tokio::io::AsyncRead for TlsTunnel {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+#[cfg(feature = "async")]
+impl tokio::io::AsyncWrite for TlsTunnel {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncTokioStream for TlsTunnel {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.0.get_ref().get_ref().get_ref().peer_addr()
+    }
+}
+
+/// An `AUTH PLAIN` command with an authorization identity (RFC 4616), which
+/// lettre always leaves empty.
+struct AuthPlain<'a> {
+    authzid: &'a str,
+    authcid: &'a str,
+    password: &'a str,
+}
+
+impl Display for AuthPlain<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let response = format!("{}\0{}\0{}", self.authzid, self.authcid, self.password);
+        write!(f, "AUTH PLAIN {}\r\n", BASE64_STANDARD.encode(response))
+    }
+}
+
+/// Authenticates on `connection`, sending `auth_plain` instead when the
+/// mechanism is PLAIN. Other mechanisms have no authorization identity, so
+/// it's left out for them.
+fn authenticate(
+    connection: &mut SmtpConnection,
+    mechanism: Mechanism,
+    credentials: &Credentials,
+    auth_plain: Option<AuthPlain>,
+) -> Result<Response, smtp::Error> {
+    match auth_plain {
+        Some(auth_plain) if mechanism == Mechanism::Plain => connection.command(auth_plain),
+        _ => connection.auth(&[mechanism], credentials),
+    }
+}
+
+/// Returns the MAIL and RCPT parameters requesting delivery status
+/// notifications (RFC 3461), or no parameters if the EHLO response doesn't
+/// advertise DSN.
+fn dsn_parameters(ehlo: &Response) -> (Vec<MailParameter>, Vec<RcptParameter>) {
+    // The first line is the server's greeting, the rest are its capabilities.
+    let supports_dsn = ehlo.message().skip(1).any(|line| {
+        line.split_whitespace()
+            .next()
+            .is_some_and(|keyword| keyword.eq_ignore_ascii_case("DSN"))
+    });
+    if !supports_dsn {
+        return (Vec::new(), Vec::new());
+    }
+
+    (
+        // Bounces only need the headers, not the QR code.
+        vec![MailParameter::Other {
+            keyword: "RET".to_string(),
+            value: Some("HDRS".to_string()),
+        }],
+        vec![RcptParameter::Other {
+            keyword: "NOTIFY".to_string(),
+            value: Some("SUCCESS,FAILURE".to_string()),
+        }],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lettre::transport::stub::StubTransport;
+    use std::ops::Deref;
+
+    /// A file in the temporary directory which is removed once dropped, even
+    /// if the test fails.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, contents: impl AsRef<[u8]>) -> Self {
+            let path = std::env::temp_dir().join(name);
+            fs::write(&path, contents).unwrap();
+            Self(path)
+        }
+
+        /// Writes a stand-in for the QR code image.
+        fn image(name: &str) -> Self {
+            Self::new(name, b"fake image data")
+        }
+    }
+
+    impl Deref for TempFile {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_email_template_subject() {
+        let template = EmailTemplate::new();
+        let args = Args {
+            email_from: "sender@example.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            bcc: None,
+            provider: "TestProvider".to_string(),
+            name: "John".to_string(),
+            data_amount: "5GB".to_string(),
+            time_period: "30 days".to_string(),
+            location: "Egypt".to_string(),
+            ..Default::default()
+        };
+        let result = template.subject(&args, 1);
+        assert_eq!(result, "[TestProvider] Egypt eSIM - 1");
+    }
+
+    #[test]
+    fn test_email_template_empty_location_fallback() {
+        let template = EmailTemplate::new();
+        let mut args = Args {
+            provider: "Gmail".to_string(),
+            location: " ".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            template.subject(&args, 1),
+            "[Gmail] your destination eSIM - 1"
+        );
+        assert!(template.body(&args).contains("Gmail your destination"));
+
+        args.location_fallback = Some("anywhere".to_string());
+        assert_eq!(template.subject(&args, 1), "[Gmail] anywhere eSIM - 1");
+        assert!(template.body(&args).contains("Gmail anywhere"));
+    }
+
+    #[test]
+    fn test_email_template_subject_zero_count() {
+        let template = EmailTemplate::new();
+        let args = Args {
+            provider: "TestProvider".to_string(),
+            location: "Egypt".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(template.subject(&args, 0), "[TestProvider] Egypt eSIM");
+        assert_eq!(template.subject(&args, 2), "[TestProvider] Egypt eSIM - 2");
+    }
+
+    #[test]
+    fn test_email_template_body() {
+        let template = EmailTemplate::new();
+        let args = Args {
+            email_from: "sender@example.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            bcc: None,
+            provider: "TestProvider".to_string(),
+            name: "John".to_string(),
+            data_amount: "5GB".to_string(),
+            time_period: "30 days".to_string(),
+            location: "Egypt".to_string(),
+            ..Default::default()
+        };
+        let result = template.body(&args);
+        assert!(result.contains("John"));
+        assert!(result.contains("TestProvider"));
+        assert!(result.contains("5GB"));
+        assert!(result.contains("30 days"));
+        assert!(result.contains("Egypt"));
+    }
+
+    #[test]
+    fn test_expiry_date_with_fixed_clock() {
+        use crate::clock::FixedClock;
+
+        // 2023-11-14T22:13:20Z
+        let clock = FixedClock(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        assert_eq!(
+            expiry_date("30 days", &clock).as_deref(),
+            Some("2023-12-14")
+        );
+        assert_eq!(expiry_date("1 Day", &clock).as_deref(), Some("2023-11-15"));
+        assert_eq!(
+            expiry_date("16 weeks", &clock).as_deref(),
+            Some("2024-03-05")
+        );
+        assert_eq!(expiry_date("1 month", &clock), None);
+        assert_eq!(expiry_date("unlimited", &clock), None);
+
+        let clock = FixedClock(SystemTime::UNIX_EPOCH);
+        assert_eq!(
+            expiry_date("59 days", &clock).as_deref(),
+            Some("1970-03-01")
+        );
+    }
+
+    #[test]
+    fn test_build_email_expiry_reminder() -> Result<(), EsimMailerError> {
+        let image_path = TempFile::image("test_image_expiry_reminder.png");
+        let fixed = FixedParts {
+            boundary: "test-boundary".to_string(),
+            content_id: "qr_image_cid@test".to_string(),
+            // 2023-11-14T22:13:20Z
+            date: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        };
+        let mut args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            location: "Tokyo, Japan".to_string(),
+            time_period: "30 days".to_string(),
+            attach_expiry_reminder: true,
+            ..Default::default()
+        };
+        let render = |args: &Args| -> Result<String, EsimMailerError> {
+            let raw = render_raw(args, &image_path, 1, Some(&fixed))?;
+            Ok(String::from_utf8(raw).unwrap())
+        };
+
+        let with_reminder = render(&args)?;
+        assert!(with_reminder.contains("Content-Type: multipart/mixed"));
+        assert!(
+            with_reminder.contains("Content-Type: text/calendar; charset=utf-8; method=PUBLISH")
+        );
+        assert!(with_reminder.contains("DTSTAMP:20231114T221320Z\r\n"));
+        assert!(with_reminder.contains("DTSTART;VALUE=DATE:20231214\r\n"));
+        assert!(with_reminder.contains("DTEND;VALUE=DATE:20231215\r\n"));
+        assert!(with_reminder.contains("SUMMARY:Tokyo\\, Japan eSIM expires\r\n"));
+
+        args.attach_qr = true;
+        assert!(render(&args)?.contains("text/calendar"));
+        args.time_period = "unlimited".to_string();
+        assert!(!render(&args)?.contains("text/calendar"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_subject_truncated_with_ellipsis() {
+        let args = Args {
+            provider: "TestProvider".to_string(),
+            location: "Egypt".to_string(),
+            truncate_subject: Some(20),
+            ..Default::default()
+        };
+        let template = EmailTemplate::new();
+
+        let subject = template.subject(&args, 1);
+
+        assert_eq!(subject.chars().count(), 20);
+        assert!(subject.ends_with('…'));
+        assert!(template.subject(&args, 1).starts_with("[TestProvider]"));
+
+        let args = Args {
+            truncate_subject: Some(100),
+            ..args
+        };
+        assert!(!template.subject(&args, 1).ends_with('…'));
+    }
+
+    #[test]
+    fn test_check_subject_length() {
+        assert!(check_subject_length("[TestProvider] Egypt eSIM - 1").is_ok());
+        assert!(matches!(
+            check_subject_length(&"x".repeat(1000)),
+            Err(EsimMailerError::MessageError(_))
+        ));
+        // Non-ASCII subjects grow when encoded, so a shorter one can still be too long.
+        assert!(check_subject_length(&"é".repeat(400)).is_err());
+    }
+
+    #[test]
+    fn test_validate_qr_reference() -> Result<(), EsimMailerError> {
+        assert!(EmailTemplate::new().validate_qr_reference().is_ok());
+
+        let template = EmailTemplate {
+            body_template: "<p>Hello {{name}}</p>".to_string(),
+            ..EmailTemplate::new()
+        };
+        assert!(matches!(
+            template.validate_qr_reference(),
+            Err(EsimMailerError::TemplateError(_))
+        ));
+
+        let image_path = std::env::temp_dir().join("test_image_missing_qr_cid.png");
+        fs::write(&image_path, b"fake image data")?;
+
+        let mut args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            body_override: Some("<p>Hello {{name}}</p>".to_string()),
+            ..Default::default()
+        };
+        let inline = build_email(&args, &image_path, 1);
+        args.attach_qr = true;
+        let attached = build_email(&args, &image_path, 1);
+
+        fs::remove_file(image_path)?;
+
+        assert!(matches!(inline, Err(EsimMailerError::TemplateError(_))));
+        assert!(attached.is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_email_rejects_oversized_body() -> Result<(), EsimMailerError> {
+        let image_path = std::env::temp_dir().join("test_image_oversized_body.png");
+        fs::write(&image_path, b"fake image data")?;
+
+        let mut args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            name: "x".repeat(DEFAULT_MAX_BODY_BYTES),
+            ..Default::default()
+        };
+        let oversized = build_email(&args, &image_path, 1);
+        args.name = "x".repeat(2000);
+        args.max_body_bytes = Some(1000);
+        let over_limit = build_email(&args, &image_path, 1);
+        args.max_body_bytes = Some(4000);
+        let under_limit = build_email(&args, &image_path, 1);
+
+        fs::remove_file(image_path)?;
+
+        assert!(
+            matches!(oversized, Err(EsimMailerError::MessageError(e)) if e.contains("limit is 1048576"))
+        );
+        assert!(matches!(over_limit, Err(EsimMailerError::MessageError(_))));
+        assert!(under_limit.is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_email_limits_inline_images() {
+        let image_path = TempFile::image("test_image_inline_limit.png");
+        let image = r#"<img src="cid:{{QR_CID}}">"#;
+        let mut args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            body_override: Some(image.repeat(DEFAULT_MAX_INLINE_IMAGES)),
+            ..Default::default()
+        };
+        assert!(build_email(&args, &image_path, 1).is_ok());
+
+        args.body_override = Some(image.repeat(DEFAULT_MAX_INLINE_IMAGES + 1));
+        assert!(matches!(
+            build_email(&args, &image_path, 1),
+            Err(EsimMailerError::MessageError(e)) if e.contains("limit is 3")
+        ));
+
+        args.body_override = Some(format!(
+            r#"{}<img src="https://example.com/logo.png">"#,
+            image.repeat(2)
+        ));
+        args.max_inline_images = Some(2);
+        assert!(build_email(&args, &image_path, 1).is_ok());
+        args.max_inline_images = Some(1);
+        assert!(matches!(
+            build_email(&args, &image_path, 1),
+            Err(EsimMailerError::MessageError(_))
+        ));
+    }
+
+    #[test]
+    fn test_build_email_rejects_oversized_subject() -> Result<(), EsimMailerError> {
+        let image_path = std::env::temp_dir().join("test_image_long_subject.png");
+        fs::write(&image_path, b"fake image data")?;
+
+        let args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            location: "Egypt".repeat(200),
+            ..Default::default()
+        };
+        let result = build_email(&args, &image_path, 1);
+        let over_limit = build_email(
+            &Args {
+                truncate_subject: Some(2000),
+                ..args.clone()
+            },
+            &image_path,
+            1,
+        );
+        let truncated = build_email(
+            &Args {
+                truncate_subject: Some(100),
+                ..args
+            },
+            &image_path,
+            1,
+        );
+
+        fs::remove_file(image_path)?;
+
+        let err = result.unwrap_err();
+        assert!(matches!(err, EsimMailerError::MessageError(_)));
+        assert!(err.to_string().contains("Subject"));
+        assert!(matches!(over_limit, Err(EsimMailerError::MessageError(_))));
+        assert!(truncated.is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_from_matches_auth() {
+        let mut args = Args {
+            email_from: "Sales <sales@gmail.com>".to_string(),
+            auth_email: Some("SALES@gmail.com".to_string()),
+            from_mismatch: CheckPolicy::Error,
+            ..Default::default()
+        };
+        assert_eq!(check_from_matches_auth(&args).unwrap(), None);
+
+        args.auth_email = Some("owner@gmail.com".to_string());
+        assert!(matches!(
+            check_from_matches_auth(&args),
+            Err(EsimMailerError::ConfigError(_))
+        ));
+
+        args.from_mismatch = CheckPolicy::Warn;
+        let warning = check_from_matches_auth(&args).unwrap().unwrap();
+        assert!(warning.contains("sales@gmail.com"));
+        assert!(warning.contains("owner@gmail.com"));
+
+        args.from_mismatch = CheckPolicy::Allow;
+        assert_eq!(check_from_matches_auth(&args).unwrap(), None);
+    }
+
+    #[test]
+    fn test_check_from_allowed() {
+        let mut args = Args {
+            email_from: "Sales <Sales@gmail.com>".to_string(),
+            auth_email: Some("owner@gmail.com".to_string()),
+            allowed_from: HashMap::from([(
+                "gmail".to_string(),
+                vec![
+                    "Sales <sales@gmail.com>".to_string(),
+                    "owner@gmail.com".to_string(),
+                ],
+            )]),
+            ..Default::default()
+        };
+        assert!(check_from_allowed(&args).is_ok());
+
+        args.email_from = "unverified@gmail.com".to_string();
+        assert!(matches!(
+            check_from_allowed(&args),
+            Err(EsimMailerError::ConfigError(e)) if e.contains("unverified@gmail.com")
+        ));
+
+        args.allowed_from = HashMap::from([("Gmail".to_string(), Vec::new())]);
+        assert!(check_from_allowed(&args).is_ok());
+
+        args.allowed_from =
+            HashMap::from([("Outlook".to_string(), vec!["other@outlook.com".to_string()])]);
+        assert!(check_from_allowed(&args).is_ok());
+    }
+
+    #[test]
+    fn test_check_not_self_send() {
+        let mut args = Args {
+            email_from: "Sales <sales@gmail.com>".to_string(),
+            email_to: "SALES@gmail.com".to_string(),
+            self_send: CheckPolicy::Warn,
+            ..Default::default()
+        };
+        let warning = check_not_self_send(&args).unwrap().unwrap();
+        assert!(warning.contains("sales@gmail.com"));
+
+        args.self_send = CheckPolicy::Error;
+        assert!(matches!(
+            check_not_self_send(&args),
+            Err(EsimMailerError::ConfigError(_))
+        ));
+
+        args.self_send = CheckPolicy::Allow;
+        assert_eq!(check_not_self_send(&args).unwrap(), None);
+
+        args.self_send = CheckPolicy::Error;
+        args.email_to = "recipient@example.com".to_string();
+        args.bcc = Some("sales@gmail.com".to_string());
+        assert_eq!(check_not_self_send(&args).unwrap(), None);
+    }
+
+    #[test]
+    fn test_check_msg_id() {
+        assert!(check_msg_id("<abc123@mail.example.com>").is_ok());
+        assert!(check_msg_id("abc123@mail.example.com").is_err());
+        assert!(check_msg_id("<abc123>").is_err());
+        assert!(check_msg_id("<@example.com>").is_err());
+        assert!(check_msg_id("<abc 123@example.com>").is_err());
+    }
+
+    #[test]
+    fn test_build_email_sets_threading_headers() -> Result<(), EsimMailerError> {
+        let image_path = std::env::temp_dir().join("test_image_threading.png");
+        fs::write(&image_path, b"fake image data")?;
+
+        let mut args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            in_reply_to: Some("<second@example.com>".to_string()),
+            references: Some(vec![
+                "<first@example.com>".to_string(),
+                "<second@example.com>".to_string(),
+            ]),
+            ..Default::default()
+        };
+        let threaded = build_email(&args, &image_path, 1);
+
+        args.in_reply_to = Some("not-a-message-id".to_string());
+        let invalid = build_email(&args, &image_path, 1);
+
+        fs::remove_file(image_path)?;
+
+        let message = String::from_utf8(threaded?.formatted()).unwrap();
+        assert!(message.contains("In-Reply-To: <second@example.com>"));
+        assert!(message.contains("References: <first@example.com> <second@example.com>"));
+        assert!(matches!(invalid, Err(EsimMailerError::MessageError(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_email_template_localized_subject() {
+        let template = EmailTemplate::new();
+        let mut args = Args {
+            provider: "TestProvider".to_string(),
+            name: "John".to_string(),
+            location: "Egypt".to_string(),
+            ..Default::default()
+        };
+        let english_body = template.body(&args);
+
+        args.language = Some("de".to_string());
+        assert_eq!(
+            template.subject(&args, 1),
+            "[TestProvider] eSIM für Egypt - 1"
+        );
+        assert_eq!(template.body(&args), english_body);
+
+        args.language = Some("DE-at".to_string());
+        assert_eq!(
+            template.subject(&args, 1),
+            "[TestProvider] eSIM für Egypt - 1"
+        );
+
+        args.language = Some("xx".to_string());
+        assert_eq!(template.subject(&args, 1), "[TestProvider] Egypt eSIM - 1");
+    }
+
+    #[test]
+    fn test_email_template_render_language() {
+        let template = EmailTemplate::new();
+        let args = Args {
+            provider: "TestProvider".to_string(),
+            name: "John".to_string(),
+            location: "Egypt".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(template.languages(), vec!["en", "de", "es", "fr"]);
+
+        for strict in [true, false] {
+            let (subject, body) = template.render_language("fr", &args, strict).unwrap();
+            assert_eq!(subject, "[TestProvider] eSIM pour Egypt");
+            assert!(body.contains("John"));
+
+            let (subject, _) = template.render_language("en-GB", &args, strict).unwrap();
+            assert_eq!(subject, "[TestProvider] Egypt eSIM");
+        }
+    }
+
+    #[test]
+    fn test_email_template_render_missing_language() {
+        let template = EmailTemplate::new();
+        let args = Args {
+            provider: "TestProvider".to_string(),
+            location: "Egypt".to_string(),
+            ..Default::default()
+        };
+
+        let result = template.render_language("it", &args, true);
+        assert!(matches!(result, Err(EsimMailerError::TemplateError(e)) if e.contains("'it'")));
+
+        let (subject, body) = template.render_language("it", &args, false).unwrap();
+        assert_eq!(subject, "[TestProvider] Egypt eSIM");
+        assert_eq!(body, template.body(&args));
+    }
+
+    #[test]
+    fn test_email_template_from_file_frontmatter() -> Result<(), EsimMailerError> {
+        let path = std::env::temp_dir().join("test_template_frontmatter.html");
+        fs::write(
+            &path,
+            "---\nsubject: \"Your {{location}} eSIM from {{provider}}\"\n---\n\
+             <p>Hi {{name}}</p><img src=\"cid:{{QR_CID}}\">\n",
+        )?;
+        let template = EmailTemplate::from_file(&path);
+        fs::remove_file(&path)?;
+        let template = template?;
+
+        let mut args = Args {
+            provider: "Gmail".to_string(),
+            name: "John".to_string(),
+            location: "Egypt".to_string(),
+            preheader: Some(String::new()),
+            ..Default::default()
+        };
+        assert_eq!(template.subject(&args, 1), "Your Egypt eSIM from Gmail - 1");
+        assert_eq!(
+            template.body(&args),
+            "<p>Hi John</p><img src=\"cid:{{QR_CID}}\">\n"
+        );
+
+        args.language = Some("de".to_string());
+        assert_eq!(template.subject(&args, 0), "Your Egypt eSIM from Gmail");
+        Ok(())
+    }
+
+    #[test]
+    fn test_email_template_cached_after_file_removed() -> Result<(), EsimMailerError> {
+        let path = std::env::temp_dir().join("test_template_cached.html");
+        fs::write(&path, "<p>Hi {{name}}</p>\n")?;
+        let first = EmailTemplate::cached(&path);
+        fs::remove_file(&path)?;
+        first?;
+
+        let args = Args {
+            name: "John".to_string(),
+            template_file: Some(path),
+            preheader: Some(String::new()),
+            ..Default::default()
+        };
+        let template = EmailTemplate::load(&args)?;
+        assert_eq!(template.body(&args), "<p>Hi John</p>\n");
+
+        let missing = std::env::temp_dir().join("test_template_never_written.html");
+        assert!(matches!(
+            EmailTemplate::cached(&missing),
+            Err(EsimMailerError::Io(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_email_template_cached_reloads_modified_file() -> Result<(), EsimMailerError> {
+        let path = std::env::temp_dir().join("test_template_cached_modified.html");
+        let write = |body: &str, modified: SystemTime| -> io::Result<()> {
+            fs::write(&path, body)?;
+            fs::File::options()
+                .write(true)
+                .open(&path)?
+                .set_modified(modified)
+        };
+        let epoch = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        write("<p>Hi</p>", epoch)?;
+        let first = EmailTemplate::cached(&path);
+        write("<p>Bye</p>", epoch + Duration::from_secs(1))?;
+        let second = EmailTemplate::cached(&path);
+        fs::remove_file(&path)?;
+
+        assert_eq!(first?.body_template, "<p>Hi</p>");
+        assert_eq!(second?.body_template, "<p>Bye</p>");
+        Ok(())
+    }
+
+    #[test]
+    fn test_email_template_from_file_empty_body() -> Result<(), EsimMailerError> {
+        let bodies = [
+            ("test_template_empty.html", ""),
+            ("test_template_whitespace.html", " \n\t\n"),
+            (
+                "test_template_frontmatter_only.html",
+                "---\nsubject: Hi\n---\n\n",
+            ),
+        ];
+        for (name, contents) in bodies {
+            let path = std::env::temp_dir().join(name);
+            fs::write(&path, contents)?;
+            let template = EmailTemplate::from_file(&path);
+            fs::remove_file(&path)?;
+            assert!(
+                matches!(template, Err(EsimMailerError::TemplateError(e)) if e.contains("empty")),
+                "{:?} should be rejected",
+                contents
+            );
+        }
+
+        let path = std::env::temp_dir().join("test_template_not_empty.html");
+        fs::write(&path, "<p>Hi</p>")?;
+        let template = EmailTemplate::from_file(&path);
+        fs::remove_file(&path)?;
+        assert!(template?.validate_not_empty().is_ok());
+
+        let blank = EmailTemplate {
+            body_template: "  ".to_string(),
+            ..Default::default()
+        };
+        assert!(blank.validate_not_empty().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_email_template_from_file_without_frontmatter() -> Result<(), EsimMailerError> {
+        let path = std::env::temp_dir().join("test_template_no_frontmatter.html");
+        fs::write(&path, "<p>Hi {{name}}</p>\n---\n")?;
+        let template = EmailTemplate::from_file(&path);
+        let unclosed_path = std::env::temp_dir().join("test_template_unclosed.html");
+        fs::write(&unclosed_path, "---\nsubject: Hi\n<p>Hi</p>\n")?;
+        let unclosed = EmailTemplate::from_file(&unclosed_path);
+        fs::remove_file(&path)?;
+        fs::remove_file(&unclosed_path)?;
+
+        let args = Args {
+            provider: "Gmail".to_string(),
+            name: "John".to_string(),
+            location: "Egypt".to_string(),
+            preheader: Some(String::new()),
+            ..Default::default()
+        };
+        let template = template?;
+        assert_eq!(template.subject(&args, 1), "[Gmail] Egypt eSIM - 1");
+        assert_eq!(template.body(&args), "<p>Hi John</p>\n---\n");
+        assert!(matches!(unclosed, Err(EsimMailerError::TemplateError(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_email_template_with_partials() {
+        let partials = HashMap::from([
+            ("header".to_string(), "<h1>{{provider}}</h1>".to_string()),
+            ("footer".to_string(), "{{> signature}}<hr>".to_string()),
+            ("signature".to_string(), "<p>{{name}}</p>".to_string()),
+        ]);
+        let template = EmailTemplate {
+            body_template: "{{> header}}<p>Body</p>{{>footer}}".to_string(),
+            ..Default::default()
+        }
+        .with_partials(&partials)
+        .unwrap();
+        let args = Args {
+            provider: "TestProvider".to_string(),
+            name: "John".to_string(),
+            preheader: Some(String::new()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            template.body(&args),
+            "<h1>TestProvider</h1><p>Body</p><p>John</p><hr>"
+        );
+    }
+
+    #[test]
+    fn test_email_template_preheader() {
+        let template = EmailTemplate::new();
+        let mut args = Args {
+            provider: "TestProvider".to_string(),
+            location: "Egypt".to_string(),
+            ..Default::default()
+        };
+
+        let body = template.body(&args);
+        assert!(body.contains(">Your Egypt eSIM is ready</span>"));
+        assert!(body.find("<body>").unwrap() < body.find("<span").unwrap());
+
+        args.preheader = Some("Install your {{provider}} eSIM before you fly".to_string());
+        let body = template.body(&args);
+        assert!(body.contains(">Install your TestProvider eSIM before you fly</span>"));
+        assert!(!body.contains("Your Egypt eSIM is ready"));
+
+        args.preheader = Some(String::new());
+        assert!(!template.body(&args).contains(PREHEADER_STYLE));
+    }
+
+    #[test]
+    fn test_email_template_self_referential_partial() {
+        let partials = HashMap::from([("loop".to_string(), "again {{> loop}}".to_string())]);
+        let template = EmailTemplate {
+            body_template: "{{> loop}}".to_string(),
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            template.with_partials(&partials),
+            Err(EsimMailerError::TemplateError(_))
+        ));
+    }
+
+    #[test]
+    fn test_email_template_unknown_partial() {
+        let template = EmailTemplate {
+            body_template: "{{> missing}}".to_string(),
+            ..Default::default()
+        };
+
+        let err = template.with_partials(&HashMap::new()).err().unwrap();
+        assert!(err.to_string().contains("missing"));
+    }
+
+    #[test]
+    fn test_validate_html_well_formed() {
+        assert!(EmailTemplate::new().validate_html().is_ok());
+
+        let template = EmailTemplate {
+            body_template: "<!DOCTYPE html><html><head><style>p > a { color: red; }</style></head>\
+                <body><!-- <p> --><P>Hi<br></p><img alt='QR' SRC='cid:{{QR_CID}}'></body></html>"
+                .to_string(),
+            ..Default::default()
+        };
+        assert!(template.validate_html().is_ok());
+    }
+
+    #[test]
+    fn test_validate_html_unclosed_tag() {
+        let template = EmailTemplate {
+            body_template: "<html><body><p>Hello<div>eSIM</div></body></html>".to_string(),
+            ..Default::default()
+        };
+
+        let err = template.validate_html().unwrap_err();
+        assert!(matches!(err, EsimMailerError::TemplateError(_)));
+        assert!(err.to_string().contains("Expected </p> but found </body>"));
+
+        let template = EmailTemplate {
+            body_template: "<html><body><p>Hello</p>".to_string(),
+            ..Default::default()
+        };
+        assert!(template.validate_html().is_err());
+    }
+
+    #[test]
+    fn test_validate_html_misplaced_qr_cid() {
+        for body_template in [
+            "<p>cid:{{QR_CID}}</p>",
+            "<a href=\"cid:{{QR_CID}}\">QR</a>",
+            "<img alt=\"{{QR_CID}}\" src=\"qr.png\">",
+        ] {
+            let template = EmailTemplate {
+                body_template: body_template.to_string(),
+                ..Default::default()
+            };
+            assert!(
+                matches!(
+                    template.validate_html(),
+                    Err(EsimMailerError::TemplateError(_))
+                ),
+                "{}",
+                body_template
+            );
+        }
+    }
+
+    #[test]
+    fn test_html_to_text() {
+        let html = "<html><head><style>p { color: red; }</style></head><body>\n\
+            <p>Hello,</p>\n<p>Here's   a <b>5GB</b> eSIM &amp; more.</p>\n\
+            <p>Thanks,<br>\nJohn</p><p><img src=\"cid:qr\" alt=\"QR Code\" /></p></body></html>";
+        assert_eq!(
+            html_to_text(html),
+            "Hello,\n\nHere's a 5GB eSIM & more.\n\nThanks,\nJohn"
+        );
+    }
+
+    #[test]
+    fn test_html_to_text_formats_blocks() {
+        let html = "<h2>Your eSIM</h2>\n<p>Before you start:</p>\n<ul>\n\
+            <li>Connect to Wi-Fi</li>\n<li>Keep this email <i>open</i></li>\n</ul>\n\
+            <p>Then scan the code.</p>";
+        assert_eq!(
+            html_to_text(html),
+            "YOUR ESIM\n\nBefore you start:\n\n- Connect to Wi-Fi\n- Keep this email open\n\n\
+            Then scan the code."
+        );
+    }
+
+    #[test]
+    fn test_diff_rendered() {
+        let old_template = EmailTemplate {
+            body_template: "<p>Hello,</p>\n<p>Here's your {{location}} eSIM.</p>\n<p>Thanks</p>"
+                .to_string(),
+            ..Default::default()
+        };
+        let new_template = EmailTemplate {
+            body_template:
+                "<p>Hello,</p>\n<p>Here's your new {{location}} eSIM.</p>\n<p>Thanks</p>"
+                    .to_string(),
+            ..Default::default()
+        };
+        let args = Args {
+            location: "Egypt".to_string(),
+            preheader: Some(String::new()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            diff_rendered(&old_template, &new_template, &args),
+            "  <p>Hello,</p>\n\
+             - <p>Here's your Egypt eSIM.</p>\n\
+             + <p>Here's your new Egypt eSIM.</p>\n\
+             \x20 <p>Thanks</p>\n"
+        );
+        assert!(
+            diff_rendered(&old_template, &old_template, &args)
+                .lines()
+                .all(|line| line.starts_with("  "))
+        );
+    }
+
+    #[test]
+    fn test_inline_css() {
+        let html = concat!(
+            "<html><head><style>\n",
+            "  .note { color: #555; font-family: \"Arial\"; }\n",
+            "  @media (max-width: 600px) { .note { color: red; } }\n",
+            "  p.note, #footer { margin: 0 }\n",
+            "</style></head><body>",
+            "<p class=\"big note\" style=\"font-size: 20px;\">Hi</p>",
+            "<div class=\"note\">There</div><br/><span id=\"footer\">Bye</span>",
+            "</body></html>"
+        );
+
+        let inlined = inline_css(html);
+
+        assert!(inlined.contains(
+            "<p class=\"big note\" style=\"color: #555; font-family: 'Arial'; margin: 0; font-size: 20px\">"
+        ));
+        assert!(
+            inlined.contains("<div class=\"note\" style=\"color: #555; font-family: 'Arial'\">")
+        );
+        assert!(inlined.contains("<span id=\"footer\" style=\"margin: 0\">"));
+        assert!(inlined.contains("<br/>"));
+        assert!(inlined.contains("@media (max-width: 600px)"));
+        assert_eq!(inline_css("<p>No styles</p>"), "<p>No styles</p>");
+    }
+
+    #[test]
+    fn test_preview_inlines_css_when_enabled() {
+        let mut args = Args {
+            body_override: Some(
+                "<style>.code { font-weight: bold }</style><p class=\"code\">{{QR_CID}}</p>"
+                    .to_string(),
+            ),
+            ..Default::default()
+        };
+        assert!(preview(&args, 1).body_html.contains("<p class=\"code\">"));
+
+        args.inline_css = true;
+        assert!(
+            preview(&args, 1)
+                .body_html
+                .contains("<p class=\"code\" style=\"font-weight: bold\">")
+        );
+    }
+
+    #[test]
+    fn test_preview_includes_text_body_in_plain_text_mode() {
+        let mut args = Args {
+            provider: "TestProvider".to_string(),
+            name: "John".to_string(),
+            location: "Egypt".to_string(),
+            ..Default::default()
+        };
+
+        let preview = preview(&args, 1);
+        assert_eq!(preview.subject, "[TestProvider] Egypt eSIM - 1");
+        assert!(preview.body_html.contains("<p>"));
+        assert_eq!(preview.body_text, None);
+
+        args.plain_text = true;
+        let body_text = super::preview(&args, 1).body_text.unwrap();
+        assert!(body_text.contains("John"));
+        assert!(body_text.contains("TestProvider Egypt"));
+        assert!(!body_text.contains('<'));
+    }
+
+    #[test]
+    fn test_build_email_plain_text_alternative() -> Result<(), EsimMailerError> {
+        let image_path = std::env::temp_dir().join("test_image_plain_text.png");
+        fs::write(&image_path, b"fake image data")?;
+
+        let args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            name: "John".to_string(),
+            plain_text: true,
+            ..Default::default()
+        };
+        let result = build_email(&args, &image_path, 1);
+
+        fs::remove_file(image_path)?;
+
+        let message = String::from_utf8(result?.formatted()).unwrap();
+        assert!(message.contains("Content-Type: multipart/alternative"));
+        assert!(message.contains("Content-Type: text/plain; charset=utf-8"));
+        assert!(message.contains("Content-Type: multipart/related"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_partials() -> Result<(), EsimMailerError> {
+        let dir = std::env::temp_dir().join("test_load_partials");
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join("footer.html"), "<p>Thanks</p>")?;
+        fs::write(dir.join("notes.txt"), "ignored")?;
+
+        let partials = load_partials(&dir);
+        fs::remove_dir_all(&dir)?;
+
+        let partials = partials?;
+        assert_eq!(partials.len(), 1);
+        assert_eq!(partials["footer"], "<p>Thanks</p>");
+        Ok(())
+    }
+
+    #[test]
+    fn test_email_template_variables() {
+        let template = EmailTemplate::new();
+        let args = Args {
+            provider: "TestProvider".to_string(),
+            name: "John".to_string(),
+            data_amount: "5GB".to_string(),
+            time_period: "30 days".to_string(),
+            location: "Egypt".to_string(),
+            ..Default::default()
+        };
+        let variables = template.variables(&args);
+        assert_eq!(variables["provider"], "TestProvider");
+        assert_eq!(variables["name"], "John");
+        assert_eq!(variables["data_amount"], "5GB");
+        assert_eq!(variables["time_period"], "30 days");
+        assert_eq!(variables["location"], "Egypt");
+        assert_eq!(variables["guardian_note"], "");
+        assert_eq!(variables["qr_width"], "200");
+        assert_eq!(variables["qr_height"], "200");
+        assert_eq!(variables["support_phone"], "");
+        assert_eq!(variables["smdp_address"], "");
+        assert_eq!(variables["dir"], "ltr");
+        assert!(variables["greeting"].starts_with("Good "));
+        assert_eq!(variables.len(), 12);
+    }
+
+    #[test]
+    fn test_email_template_greeting() {
+        let template = EmailTemplate {
+            body_template: "<p>{{greeting}}, {{name}}!</p>".to_string(),
+            ..Default::default()
+        };
+        let args = Args {
+            name: "John".to_string(),
+            preheader: Some(String::new()),
+            ..Default::default()
+        };
+        // 2023-11-14 is the day of 1_699_920_000 in UTC
+        let at = |hour: u64| {
+            FixedClock(SystemTime::UNIX_EPOCH + Duration::from_secs(1_699_920_000 + hour * 3_600))
+        };
+
+        assert_eq!(
+            template.body_at(&args, &at(9)),
+            "<p>Good morning, John!</p>"
+        );
+        assert_eq!(
+            template.body_at(&args, &at(14)),
+            "<p>Good afternoon, John!</p>"
+        );
+        assert_eq!(
+            template.body_at(&args, &at(20)),
+            "<p>Good evening, John!</p>"
+        );
+
+        // 8pm in UTC is 9am the next day in Auckland
+        let args = Args {
+            utc_offset_minutes: Some(13 * 60),
+            ..args
+        };
+        assert_eq!(
+            template.body_at(&args, &at(20)),
+            "<p>Good morning, John!</p>"
+        );
+    }
+
+    #[test]
+    fn test_render_raw_greeting_follows_fixed_date() -> Result<(), EsimMailerError> {
+        let image_path = TempFile::image("test_image_fixed_greeting.png");
+        let args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            body_override: Some(r#"<p>{{greeting}}</p><img src="cid:{{QR_CID}}">"#.to_string()),
+            preheader: Some(String::new()),
+            ..Default::default()
+        };
+        let render_at = |secs: u64| -> Result<String, EsimMailerError> {
+            let fixed = FixedParts {
+                boundary: "test-boundary".to_string(),
+                content_id: "qr_image_cid@test".to_string(),
+                date: SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+            };
+            let raw = render_raw(&args, &image_path, 1, Some(&fixed))?;
+            Ok(String::from_utf8(raw).unwrap())
+        };
+
+        // 9am and 8pm on 2023-11-14 in UTC
+        assert!(render_at(1_699_952_400)?.contains("<p>Good morning</p>"));
+        assert!(render_at(1_699_992_000)?.contains("<p>Good evening</p>"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_html_body_markdown() -> Result<(), EsimMailerError> {
+        let template = EmailTemplate {
+            body_template: "# Your {{location}} eSIM\n\n\
+                            Hi **{{name}}**, scan this code:\n\n\
+                            ![QR code](cid:{{QR_CID}})\n"
+                .to_string(),
+            ..Default::default()
+        };
+        let args = Args {
+            name: "John".to_string(),
+            location: "Egypt".to_string(),
+            preheader: Some(String::new()),
+            markdown: true,
+            ..Default::default()
+        };
+
+        let html = html_body(&template, &args, &SystemClock);
+        assert_eq!(
+            html,
+            "<h1>Your Egypt eSIM</h1>\n\
+             <p>Hi <strong>John</strong>, scan this code:</p>\n\
+             <p><img src=\"cid:{{QR_CID}}\" alt=\"QR code\"></p>\n"
+        );
+        EmailTemplate {
+            body_template: html.clone(),
+            ..Default::default()
+        }
+        .validate_html()?;
+        check_qr_referenced(&html)?;
+        assert_eq!(
+            text_body(&template, &args, &html, &SystemClock),
+            "Your Egypt eSIM\n\nHi John, scan this code:"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_html_body_right_to_left() {
+        let template = EmailTemplate {
+            body_template: "<html><body><p>{{name}}</p></body></html>".to_string(),
+            ..Default::default()
+        };
+        let mut args = Args {
+            name: "سارة".to_string(),
+            preheader: Some(String::new()),
+            text_direction: Some(Direction::Rtl),
+            ..Default::default()
+        };
+        assert_eq!(
+            html_body(&template, &args, &SystemClock),
+            r#"<html dir="rtl"><body><p>سارة</p></body></html>"#
+        );
+
+        let template = EmailTemplate {
+            body_template: r#"<p dir="{{dir}}">{{name}}</p>"#.to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            html_body(&template, &args, &SystemClock),
+            r#"<p dir="rtl">سارة</p>"#
+        );
+
+        let template = EmailTemplate {
+            body_template: "<p>{{name}}</p>".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            html_body(&template, &args, &SystemClock),
+            r#"<div dir="rtl"><p>سارة</p></div>"#
+        );
+
+        args.text_direction = None;
+        assert_eq!(html_body(&template, &args, &SystemClock), "<p>سارة</p>");
+    }
+
+    #[test]
+    fn test_email_template_smdp_address() {
+        let template = EmailTemplate {
+            body_template: "<p>Server: {{smdp_address}}</p>".to_string(),
+            ..Default::default()
+        };
+        let args = Args {
+            activation_code: Some("LPA:1$smdp.example.com$ABCD-1234".to_string()),
+            preheader: Some(String::new()),
+            ..Default::default()
+        };
+        assert_eq!(template.body(&args), "<p>Server: smdp.example.com</p>");
+    }
+
+    #[test]
+    fn test_email_template_support_phone() {
+        let template = EmailTemplate {
+            body_template: "<p>Questions? Call {{support_phone}}</p>".to_string(),
+            ..Default::default()
+        };
+        let mut args = Args {
+            support_phone: Some("+1 (555) 010-2030".to_string()),
+            preheader: Some(String::new()),
+            ..Default::default()
+        };
+        assert_eq!(
+            template.body(&args),
+            r#"<p>Questions? Call <a href="tel:+15550102030">+1 (555) 010-2030</a></p>"#
+        );
+
+        args.support_phone = None;
+        assert_eq!(template.body(&args), "<p>Questions? Call </p>");
+    }
+
+    #[test]
+    fn test_email_template_qr_dimensions() {
+        let template = EmailTemplate::new();
+        let mut args = Args {
+            qr_width: Some(160),
+            ..Default::default()
+        };
+        assert!(
+            template
+                .body(&args)
+                .contains("alt=\"QR Code\" width=\"160\" height=\"160\"")
+        );
+
+        args.qr_height = Some(120);
+        assert!(
+            template
+                .body(&args)
+                .contains("width=\"160\" height=\"120\"")
+        );
+    }
+
+    #[test]
+    fn parse_valid_provider() {
+        let gmail = "foobar@gmail.com".parse::<Provider>();
+        assert_eq!(gmail, Ok(Provider::Gmail));
+
+        let outlook = "foobar@outlook.com".parse::<Provider>();
+        assert_eq!(outlook, Ok(Provider::Outlook));
+
+        let hotmail = "foobar@hotmail.com".parse::<Provider>();
+        assert_eq!(hotmail, Ok(Provider::Outlook));
+
+        for domain in ["fastmail.com", "fastmail.fm", "messagingengine.com"] {
+            let fastmail = format!("foobar@{}", domain).parse::<Provider>();
+            assert_eq!(fastmail, Ok(Provider::Fastmail));
+        }
+
+        for domain in ["zoho.com", "zohomail.com"] {
+            let zoho = format!("foobar@{}", domain).parse::<Provider>();
+            assert_eq!(zoho, Ok(Provider::Zoho));
+        }
+
+        for domain in ["gmx.com", "gmx.net", "web.de"] {
+            let gmx = format!("foobar@{}", domain).parse::<Provider>();
+            assert_eq!(gmx, Ok(Provider::Gmx));
+        }
+    }
+
+    #[test]
+    fn parse_provider_domain_case_insensitive() {
+        assert_eq!("foobar@GMAIL.COM".parse::<Provider>(), Ok(Provider::Gmail));
+        assert_eq!(
+            "FooBar@Outlook.com".parse::<Provider>(),
+            Ok(Provider::Outlook)
+        );
+        assert_eq!(
+            "foobar@FastMail.FM".parse::<Provider>(),
+            Ok(Provider::Fastmail)
+        );
+
+        let result = "FooBar@Yahoo.com".parse::<Provider>();
+        assert_eq!(result, Err(ParseProviderError("FooBar@Yahoo.com".into())));
+
+        assert_eq!(canonical_email("FooBar@GMail.Com"), "FooBar@gmail.com");
+        assert_eq!(canonical_email("not-an-email"), "not-an-email");
+    }
+
+    #[test]
+    fn parse_provider_trailing_dot() {
+        assert_eq!("foobar@gmail.com.".parse::<Provider>(), Ok(Provider::Gmail));
+
+        let result = "foobar@gmail.com..".parse::<Provider>();
+        assert_eq!(result, Err(ParseProviderError("foobar@gmail.com..".into())));
+    }
+
+    #[test]
+    fn parse_invalid_provider() {
+        let result = "foobar@yahoo.com".parse::<Provider>();
+        assert_eq!(result, Err(ParseProviderError("foobar@yahoo.com".into())));
+    }
+
+    #[test]
+    fn test_is_disposable_domain() {
+        assert!(is_disposable_domain("mailinator.com"));
+        assert!(is_disposable_domain("YopMail.com"));
+        assert!(!is_disposable_domain("gmail.com"));
+        assert!(!is_disposable_domain(""));
+    }
+
+    #[test]
+    fn test_build_email_rejects_disposable_recipient() -> Result<(), EsimMailerError> {
+        let image_path = std::env::temp_dir().join("test_image_disposable.png");
+        fs::write(&image_path, b"fake image data")?;
+
+        let mut args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "someone@Mailinator.com".to_string(),
+            reject_disposable_recipients: true,
+            ..Default::default()
+        };
+        let disposable = build_email(&args, &image_path, 1);
+
+        args.email_to = "someone@example.com".to_string();
+        let normal = build_email(&args, &image_path, 1);
+
+        fs::remove_file(image_path)?;
+
+        assert!(matches!(disposable, Err(EsimMailerError::ConfigError(_))));
+        assert!(normal.is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_email_cc_and_bcc_display_names() -> Result<(), EsimMailerError> {
+        let image_path = std::env::temp_dir().join("test_image_display_names.png");
+        fs::write(&image_path, b"fake image data")?;
+
+        let args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            cc: Some("Support Team <support@example.com>".to_string()),
+            bcc: Some("Accounts <acct@example.com>".to_string()),
+            ..Default::default()
+        };
+        let result = build_email(&args, &image_path, 1);
+
+        fs::remove_file(image_path)?;
+
+        let email = result?;
+        let message = String::from_utf8(email.formatted()).unwrap();
+        assert!(message.contains("Cc: \"Support Team\" <support@example.com>"));
+        assert!(
+            email
+                .envelope()
+                .to()
+                .iter()
+                .any(|address| address.to_string() == "acct@example.com")
+        );
+
+        let bcc = parse_recipient(&args, args.bcc.as_deref().unwrap())?;
+        assert_eq!(bcc.name.as_deref(), Some("Accounts"));
+        assert_eq!(bcc.email.to_string(), "acct@example.com");
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_email_redirect_to() -> Result<(), EsimMailerError> {
+        let image_path = std::env::temp_dir().join("test_image_redirect.png");
+        fs::write(&image_path, b"fake image data")?;
+
+        let args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "Real Customer <customer@example.com>".to_string(),
+            cc: Some("support@example.com".to_string()),
+            bcc: Some("records@example.com".to_string()),
+            redirect_to: Some("staging-inbox@example.com".to_string()),
+            ..Default::default()
+        };
+        let result = build_email(&args, &image_path, 1);
+
+        fs::remove_file(image_path)?;
+
+        let email = result?;
+        let recipients: Vec<String> = email
+            .envelope()
+            .to()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(recipients, vec!["staging-inbox@example.com"]);
+
+        let message = String::from_utf8(email.formatted()).unwrap();
+        assert!(message.contains("To: staging-inbox@example.com\r\n"));
+        assert!(message.contains("X-Original-To: customer@example.com\r\n"));
+        assert!(!message.contains("Cc:"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_email_body_override() -> Result<(), EsimMailerError> {
+        let image_path = std::env::temp_dir().join("test_image_body_override.png");
+        fs::write(&image_path, b"fake image data")?;
+
+        let args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            name: "Alex".to_string(),
+            body_override: Some(
+                "<p>One-off eSIM from {{name}}</p><img src=\"cid:{{QR_CID}}\">".to_string(),
+            ),
+            ..Default::default()
+        };
+        let fixed = FixedParts {
+            boundary: "test-boundary".to_string(),
+            content_id: "qr_image_cid@test".to_string(),
+            date: SystemTime::UNIX_EPOCH,
+        };
+        let result = render_raw(&args, &image_path, 1, Some(&fixed));
+
+        fs::remove_file(image_path)?;
+
+        let message = String::from_utf8(result?).unwrap();
+        assert!(
+            message.contains("<p>One-off eSIM from Alex</p><img src=\"cid:qr_image_cid@test\">")
+        );
+        assert!(!message.contains("Here's a"));
+        assert!(message.contains("Content-ID: <qr_image_cid@test>"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_email_x_mailer_header() -> Result<(), EsimMailerError> {
+        let image_path = std::env::temp_dir().join("test_image_x_mailer.png");
+        fs::write(&image_path, b"fake image data")?;
+
+        let mut args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            ..Default::default()
+        };
+        let with_header = build_email(&args, &image_path, 1);
+        args.omit_x_mailer = true;
+        let without_header = build_email(&args, &image_path, 1);
+
+        fs::remove_file(image_path)?;
+
+        let message = String::from_utf8(with_header?.formatted()).unwrap();
+        assert!(message.contains(&format!(
+            "X-Mailer: esim-mailer/{}\r\n",
+            env!("CARGO_PKG_VERSION")
+        )));
+        let message = String::from_utf8(without_header?.formatted()).unwrap();
+        assert!(!message.contains("X-Mailer"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_encoded_subject() {
+        let mut args = Args {
+            subject_charset: Some(Charset::Latin1),
+            ..Default::default()
+        };
+        let mut headers = Headers::new();
+        headers.insert_raw(encoded_subject(&args, "[Café Móvil] España eSIM - 1").unwrap());
+        assert_eq!(
+            headers.to_string(),
+            "Subject: =?ISO-8859-1?Q?=5BCaf=E9_M=F3vil=5D_Espa=F1a_eSIM_-_1?=\r\n"
+        );
+
+        // Left to lettre when there's nothing to encode or it can't be encoded
+        assert!(encoded_subject(&args, "Egypt eSIM - 1").is_none());
+        assert!(encoded_subject(&args, "東京 eSIM - 1").is_none());
+        args.subject_charset = None;
+        assert!(encoded_subject(&args, "España eSIM - 1").is_none());
+    }
+
+    #[test]
+    fn test_build_email_latin1_subject() -> Result<(), EsimMailerError> {
+        let image_path = TempFile::image("test_image_latin1_subject.png");
+        let args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            provider: "Café Móvil".to_string(),
+            location: "España".to_string(),
+            subject_charset: Some(Charset::Latin1),
+            ..Default::default()
+        };
+
+        let latin1 = String::from_utf8(build_email(&args, &image_path, 1)?.formatted()).unwrap();
+        assert!(
+            latin1.contains("Subject: =?ISO-8859-1?Q?=5BCaf=E9_M=F3vil=5D_Espa=F1a_eSIM_-_1?=\r\n")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_rewritten_from() {
+        let from: Mailbox = "eSIM Team <noreply@mail.company.com>".parse().unwrap();
+        let mut args = Args {
+            from_domain_rewrites: HashMap::from([(
+                "Mail.Company.com".to_string(),
+                "company.com".to_string(),
+            )]),
+            ..Default::default()
+        };
+        assert_eq!(
+            rewritten_from(&args, &from).unwrap(),
+            Some("eSIM Team <noreply@company.com>".parse().unwrap())
+        );
+
+        let other: Mailbox = "noreply@company.com".parse().unwrap();
+        assert_eq!(rewritten_from(&args, &other).unwrap(), None);
+
+        args.from_domain_rewrites =
+            HashMap::from([("mail.company.com".to_string(), "not a domain".to_string())]);
+        assert!(matches!(
+            rewritten_from(&args, &from),
+            Err(EsimMailerError::ConfigError(_))
+        ));
+    }
+
+    #[test]
+    fn test_build_email_rewrites_from_domain() -> Result<(), EsimMailerError> {
+        let image_path = TempFile::image("test_image_from_rewrite.png");
+        let args = Args {
+            email_from: "eSIM Team <noreply@mail.company.com>".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            from_domain_rewrites: HashMap::from([(
+                "mail.company.com".to_string(),
+                "company.com".to_string(),
+            )]),
+            ..Default::default()
+        };
+
+        let rewritten = build_email(&args, &image_path, 1)?;
+        assert_eq!(
+            rewritten.headers().get_raw("From"),
+            Some("eSIM Team <noreply@company.com>")
+        );
+        assert_eq!(
+            rewritten.envelope().from().map(Address::to_string),
+            Some("noreply@mail.company.com".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_order_id_header() {
+        let mut args = Args {
+            order_id: Some("ORD-2024-0042".to_string()),
+            ..Default::default()
+        };
+        let header = |args: &Args| {
+            let mut headers = Headers::new();
+            headers.insert_raw(order_id_header(args).unwrap().unwrap());
+            headers.to_string()
+        };
+        assert_eq!(header(&args), "X-Order-Id: ORD-2024-0042\r\n");
+
+        args.order_id_header = Some("X-Shop-Order".to_string());
+        assert_eq!(header(&args), "X-Shop-Order: ORD-2024-0042\r\n");
+
+        args.order_id = Some("ORD-1\r\nBcc: victim@example.com".to_string());
+        assert!(matches!(
+            order_id_header(&args),
+            Err(EsimMailerError::MessageError(_))
+        ));
+
+        args.order_id = Some("ORD-1".to_string());
+        args.order_id_header = Some("X Order".to_string());
+        assert!(matches!(
+            order_id_header(&args),
+            Err(EsimMailerError::MessageError(_))
+        ));
+
+        args.order_id = Some(String::new());
+        assert!(order_id_header(&args).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_build_email_order_id_header() -> Result<(), EsimMailerError> {
+        let image_path = TempFile::image("test_image_order_id.png");
+        let args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            order_id: Some("ORD-2024-0042".to_string()),
+            ..Default::default()
+        };
+
+        let email = build_email(&args, &image_path, 1)?;
+        assert_eq!(email.headers().get_raw("X-Order-Id"), Some("ORD-2024-0042"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_deliverability_warnings() -> Result<(), EsimMailerError> {
+        let image_path = TempFile::new("test_image_deliverability.png", vec![0; 300 * 1024]);
+        let mut args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            provider: "TestProvider".to_string(),
+            location: "Egypt".to_string(),
+            ..Default::default()
+        };
+        let missing = deliverability_warnings(&args, &image_path);
+        assert_eq!(missing.len(), 2);
+        assert!(missing[0].contains("List-Unsubscribe"));
+        assert!(missing[1].contains("plain text"));
+
+        args.list_unsubscribe = Some("mailto:unsubscribe@example.com".to_string());
+        args.plain_text = true;
+        assert!(deliverability_warnings(&args, &image_path).is_empty());
+        assert_eq!(
+            build_email(&args, &image_path, 1)?
+                .headers()
+                .get_raw("List-Unsubscribe"),
+            Some("<mailto:unsubscribe@example.com>")
+        );
+
+        // The image is over Outlook's limit, the subject is too long and the
+        // sender doesn't match the account
+        args.email_from = "sales@outlook.com".to_string();
+        args.auth_email = Some("test@outlook.com".to_string());
+        args.location = "x".repeat(100);
+        assert_eq!(deliverability_warnings(&args, &image_path).len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_email_organization_header() -> Result<(), EsimMailerError> {
+        let image_path = std::env::temp_dir().join("test_image_organization.png");
+        fs::write(&image_path, b"fake image data")?;
+
+        let mut args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            organization: Some("Example Travel Ltd".to_string()),
+            ..Default::default()
+        };
+        let with_header = build_email(&args, &image_path, 1);
+        args.organization = None;
+        let without_header = build_email(&args, &image_path, 1);
+
+        fs::remove_file(image_path)?;
+
+        let message = String::from_utf8(with_header?.formatted()).unwrap();
+        assert!(message.contains("Organization: Example Travel Ltd\r\n"));
+        let message = String::from_utf8(without_header?.formatted()).unwrap();
+        assert!(!message.contains("Organization"));
+        Ok(())
+    }
+
+    fn ehlo_response(capabilities: &[&str]) -> Response {
+        use lettre::transport::smtp::response::{Category, Code, Detail, Severity};
+
+        let mut lines = vec!["smtp.example.com at your service".to_string()];
+        lines.extend(capabilities.iter().map(|line| line.to_string()));
+        Response::new(
+            Code::new(
+                Severity::PositiveCompletion,
+                Category::MailSystem,
+                Detail::Zero,
+            ),
+            lines,
+        )
+    }
+
+    #[test]
+    fn test_dsn_parameters_when_supported() {
+        let (mail_parameters, rcpt_parameters) =
+            dsn_parameters(&ehlo_response(&["SIZE 35882577", "dsn", "AUTH PLAIN"]));
+
+        assert_eq!(
+            mail_parameters
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            vec!["RET=HDRS"]
+        );
+        assert_eq!(
+            rcpt_parameters
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            vec!["NOTIFY=SUCCESS,FAILURE"]
+        );
+    }
+
+    #[test]
+    fn test_dsn_parameters_when_unsupported() {
+        let (mail_parameters, rcpt_parameters) =
+            dsn_parameters(&ehlo_response(&["SIZE 35882577", "8BITMIME", "DSNX"]));
+
+        assert!(mail_parameters.is_empty());
+        assert!(rcpt_parameters.is_empty());
+    }
+
+    #[test]
+    fn test_authenticate_with_authzid() {
+        use std::io::{BufRead, BufReader, Write};
+        use std::net::TcpListener;
+        use std::sync::mpsc;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (received, lines) = mpsc::channel();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            stream.write_all(b"220 localhost ESMTP\r\n").unwrap();
+            for reply in [
+                "250-localhost\r\n250 AUTH PLAIN LOGIN\r\n",
+                "235 2.7.0 Authentication successful\r\n",
+            ] {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                received.send(line).unwrap();
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+        });
+
+        let mut connection = SmtpConnection::connect(
+            ("127.0.0.1", port),
+            Some(Duration::from_secs(5)),
+            &ClientId::default(),
+            None,
+            None,
+        )
+        .unwrap();
+        let credentials = Credentials::new("user@example.com".to_string(), "secret".to_string());
+        authenticate(
+            &mut connection,
+            Mechanism::Plain,
+            &credentials,
+            Some(AuthPlain {
+                authzid: "shared@example.com",
+                authcid: "user@example.com",
+                password: "secret",
+            }),
+        )
+        .unwrap();
+
+        assert!(lines.recv().unwrap().starts_with("EHLO "));
+        let auth = lines.recv().unwrap();
+        assert_eq!(
+            auth,
+            "AUTH PLAIN c2hhcmVkQGV4YW1wbGUuY29tAHVzZXJAZXhhbXBsZS5jb20Ac2VjcmV0\r\n"
+        );
+        let encoded = auth.trim_end().strip_prefix("AUTH PLAIN ").unwrap();
+        assert_eq!(
+            BASE64_STANDARD.decode(encoded).unwrap(),
+            b"shared@example.com\0user@example.com\0secret"
+        );
+    }
+
+    #[test]
+    fn test_connection_transport_only_when_needed() {
+        let args = Args {
+            email_from: "user@fastmail.com".to_string(),
+            ..Default::default()
+        };
+        let transport = connection_transport(&args, &Provider::Fastmail, "secret".to_string());
+        assert_eq!(transport.authzid, None);
+        assert!(!transport.request_dsn);
+
+        let args = Args {
+            authzid: Some("shared@fastmail.com".to_string()),
+            ..args
+        };
+        let transport = connection_transport(&args, &Provider::Fastmail, "secret".to_string());
+        assert_eq!(transport.authzid.as_deref(), Some("shared@fastmail.com"));
+        assert_eq!(transport.auth_identity, "user@fastmail.com");
+    }
+
+    #[test]
+    fn test_connection_transport_retries_handshake() {
+        use std::io::Write;
+        use std::net::TcpListener;
+
+        // The server turns away every connection with a transient error
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let connections = Arc::new(Mutex::new(0));
+        let accepted = Arc::clone(&connections);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                *accepted.lock().unwrap() += 1;
+                stream
+                    .write_all(b"421 4.3.2 Service not available, try again later\r\n")
+                    .unwrap();
+            }
+        });
+
+        let args = Args {
+            email_from: "user@fastmail.com".to_string(),
+            handshake_retries: 2,
+            handshake_retry_delay: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        let mut transport = connection_transport(&args, &Provider::Fastmail, "secret".to_string());
+        transport.config.host = "127.0.0.1".to_string();
+        transport.config.port = port;
+        transport.config.tls = TlsMode::StartTls;
+        let email = Message::builder()
+            .from("user@fastmail.com".parse().unwrap())
+            .to("recipient@example.com".parse().unwrap())
+            .body(String::new())
+            .unwrap();
+
+        let error = transport.send(&email).unwrap_err();
+
+        assert!(matches!(error, EsimMailerError::TransientError(_)));
+        assert_eq!(*connections.lock().unwrap(), 3);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_send_through_unreachable_proxy() {
+        // Nothing listens on the port once the listener is dropped
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let args = Args {
+            email_from: "user@fastmail.com".to_string(),
+            proxy: Some(ProxyConfig {
+                host: "127.0.0.1".to_string(),
+                port,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let email = Message::builder()
+            .from("user@fastmail.com".parse().unwrap())
+            .to("recipient@example.com".parse().unwrap())
+            .body(String::new())
+            .unwrap();
+        let proxy = args.proxy.as_ref().unwrap();
+        let error = send_through_proxy(
+            &args,
+            &Provider::Fastmail,
+            "secret".to_string(),
+            proxy,
+            &[(args.clone(), email)],
+            None,
+        )
+        .unwrap_err();
+        assert!(matches!(error, EsimMailerError::NetworkError(_)));
+        assert_eq!(error.category(), ErrorCategory::Network);
+    }
+
+    #[test]
+    fn test_configure_mailer_gmail() {
+        let token = "ya29.a0AfB_byC1x2Y3z4";
+        assert_eq!(
+            Provider::Gmail.transport_config(&HashMap::new(), token),
+            SmtpConfig {
+                host: "smtp.gmail.com".to_string(),
+                port: 587,
+                tls: TlsMode::StartTls,
+                mechanism: Mechanism::Xoauth2,
+            }
+        );
+
+        let result = configure_mailer(
+            &Provider::Gmail,
+            "test@gmail.com",
+            token.to_string(),
+            &HashMap::new(),
+            None,
+        );
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_configure_mailer_outlook() {
+        let token = "EwBwA8l6BAAUO9chh8cJscQLmU+LSWpbnr0vmwwAAQ".repeat(4);
+        assert_eq!(
+            Provider::Outlook.transport_config(&HashMap::new(), &token),
+            SmtpConfig {
+                host: "smtp-mail.outlook.com".to_string(),
+                port: 587,
+                tls: TlsMode::StartTls,
+                mechanism: Mechanism::Xoauth2,
+            }
+        );
+
+        let result = configure_mailer(
+            &Provider::Outlook,
+            "test@outlook.com",
+            token,
+            &HashMap::new(),
+            None,
+        );
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_configure_mailer_min_tls_version() {
+        let result = configure_mailer(
+            &Provider::Gmail,
+            "test@gmail.com",
+            "token".to_string(),
+            &HashMap::new(),
+            Some(TlsVersion::Tls12),
+        );
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_configure_mailer_fastmail() {
+        let config = Provider::Fastmail.smtp_config();
+        assert_eq!(config.host, "smtp.fastmail.com");
+        assert_eq!(config.port, 465);
+        assert_eq!(config.tls, TlsMode::Implicit);
+        assert_eq!(config.mechanism, Mechanism::Plain);
+
+        let result = configure_mailer(
+            &Provider::Fastmail,
+            "test@fastmail.com",
+            "app-password".to_string(),
+            &HashMap::new(),
+            None,
+        );
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_configure_mailer_zoho() {
+        let config = Provider::Zoho.smtp_config();
+        assert_eq!(config.host, "smtp.zoho.com");
+        assert_eq!(config.port, 587);
+        assert_eq!(config.tls, TlsMode::StartTls);
+        assert_eq!(config.mechanism, Mechanism::Login);
+
+        let provider: Provider = "test@zohomail.com".parse().unwrap();
+        let result = configure_mailer(
+            &provider,
+            "test@zohomail.com",
+            "app-password".to_string(),
+            &HashMap::new(),
+            None,
+        );
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_configure_mailer_gmx() -> Result<(), EsimMailerError> {
+        for email_from in ["test@gmx.com", "test@gmx.net", "test@web.de"] {
+            let args = Args {
+                email_from: email_from.to_string(),
+                ..Default::default()
+            };
+            let description = describe_transport(&args)?;
+            assert_eq!(description.provider, Provider::Gmx);
+            assert_eq!(description.host, "mail.gmx.net");
+            assert_eq!(description.port, 587);
+            assert_eq!(description.tls, TlsMode::StartTls);
+            assert_eq!(description.mechanism, Mechanism::Login);
+
+            let result = configure_mailer(
+                &description.provider,
+                email_from,
+                "password".to_string(),
+                &HashMap::new(),
+                None,
+            );
+            assert!(result.is_ok());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_describe_transport() -> Result<(), EsimMailerError> {
+        let mut args = Args {
+            email_from: "test@gmail.com".to_string(),
+            ..Default::default()
+        };
+        let description = describe_transport(&args)?;
+        assert_eq!(
+            description,
+            TransportDescription {
+                provider: Provider::Gmail,
+                host: "smtp.gmail.com".to_string(),
+                port: 587,
+                tls: TlsMode::StartTls,
+                mechanism: Mechanism::Xoauth2,
+                proxy: None,
+            }
+        );
+        assert_eq!(
+            description.to_string(),
+            "Gmail via smtp.gmail.com:587 using STARTTLS, authenticating with XOAUTH2"
+        );
+
+        args.port_overrides = HashMap::from([("gmail".to_string(), 465)]);
+        args.token = Some("abcd efgh ijkl mnop".to_string());
+        let description = describe_transport(&args)?;
+        assert_eq!(description.port, 465);
+        assert_eq!(description.tls, TlsMode::Implicit);
+        assert_eq!(description.mechanism, Mechanism::Login);
+        assert!(!description.to_string().contains("abcd"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_describe_transport_token_file_and_proxy() -> Result<(), EsimMailerError> {
+        let token_file = TempFile::new("esim_mailer_describe_token.txt", "abcd efgh ijkl mnop\n");
+        let args = Args {
+            email_from: "test@gmail.com".to_string(),
+            token_file: Some(token_file.to_path_buf()),
+            proxy: Some(ProxyConfig {
+                host: "proxy.example.com".to_string(),
+                port: 1080,
+                username: Some("user".to_string()),
+                password: Some("hunter2".to_string()),
+            }),
+            ..Default::default()
+        };
+        let description = describe_transport(&args)?;
+
+        assert_eq!(description.mechanism, Mechanism::Login);
+        assert_eq!(description.proxy.as_deref(), Some("proxy.example.com:1080"));
+        let text = description.to_string();
+        assert!(text.ends_with(", through the proxy proxy.example.com:1080"));
+        assert!(!text.contains("abcd"));
+        assert!(!text.contains("hunter2"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_smtp_config_for_token() {
+        let config = Provider::Gmail
+            .smtp_config()
+            .for_token("ya29.a0AfB_byC1x2Y3z4");
+        assert_eq!(config.mechanism, Mechanism::Xoauth2);
+
+        let config = Provider::Gmail
+            .smtp_config()
+            .for_token("abcd efgh ijkl mnop");
+        assert_eq!(config.mechanism, Mechanism::Login);
+
+        let config = Provider::Zoho
+            .smtp_config()
+            .for_token("abcd efgh ijkl mnop");
+        assert_eq!(config.mechanism, Mechanism::Login);
+    }
+
+    #[test]
+    fn test_smtp_config_port_override() {
+        let port_overrides = HashMap::from([("gmail".to_string(), 465)]);
+
+        let config = Provider::Gmail.smtp_config_with_overrides(&port_overrides);
+        assert_eq!(config.host, "smtp.gmail.com");
+        assert_eq!(config.port, 465);
+        assert_eq!(config.tls, TlsMode::Implicit);
+
+        let config = Provider::Outlook.smtp_config_with_overrides(&port_overrides);
+        assert_eq!(config, Provider::Outlook.smtp_config());
+
+        let config = Provider::Fastmail
+            .smtp_config_with_overrides(&HashMap::from([("Fastmail".to_string(), 587)]));
+        assert_eq!(config.port, 587);
+        assert_eq!(config.tls, TlsMode::StartTls);
+
+        let result = configure_mailer(
+            &Provider::Gmail,
+            "test@gmail.com",
+            "token".to_string(),
+            &port_overrides,
+            None,
+        );
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_build_email_attach_qr() -> Result<(), EsimMailerError> {
+        let image_path = std::env::temp_dir().join("test_image_attach_qr.png");
+        fs::write(&image_path, b"fake image data")?;
+
+        let args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            name: "John".to_string(),
+            attach_qr: true,
+            ..Default::default()
+        };
+        let result = build_email(&args, &image_path, 1);
+
+        fs::remove_file(image_path)?;
+
+        let message = String::from_utf8(result?.formatted()).unwrap();
+        assert!(message.contains("Content-Type: multipart/mixed"));
+        assert!(!message.contains("multipart/related"));
+        assert!(!message.contains("Content-Disposition: inline"));
+        assert!(!message.contains("cid:"));
+        assert_eq!(
+            message
+                .matches("Content-Disposition: attachment; filename=\"test_image_attach_qr.png\"")
+                .count(),
+            1
+        );
+        assert!(message.contains("attached to this email"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_no_tracking_pixels() {
+        let mut args = Args {
+            body_override: Some(
+                "<p>Hi {{name}}</p><img src=\"cid:{{QR_CID}}\">\
+                 <img width=\"1\" height=\"1\" src=\"https://t.example.com/open.gif\">"
+                    .to_string(),
+            ),
+            tracking_pixels: CheckPolicy::Warn,
+            ..Default::default()
+        };
+        let warning = check_no_tracking_pixels(&args).unwrap().unwrap();
+        assert!(warning.contains("https://t.example.com/open.gif"));
+
+        args.tracking_pixels = CheckPolicy::Error;
+        assert!(matches!(
+            check_no_tracking_pixels(&args),
+            Err(EsimMailerError::ConfigError(_))
+        ));
+
+        args.body_override = Some(
+            "<img src=\"data:image/gif;base64,R0lGOD\" style=\"width: 1px; height: 1px\">"
+                .to_string(),
+        );
+        assert!(check_no_tracking_pixels(&args).is_err());
+
+        args.body_override = None;
+        assert_eq!(check_no_tracking_pixels(&args).unwrap(), None);
+    }
+
+    #[test]
+    fn test_build_email_sender_header() -> Result<(), EsimMailerError> {
+        let image_path = std::env::temp_dir().join("test_image_sender.png");
+        fs::write(&image_path, b"fake image data")?;
+
+        let mut args = Args {
+            email_from: "Support <support@example.com>".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            auth_email: Some("agent@gmail.com".to_string()),
+            ..Default::default()
+        };
+        let mismatch = build_email(&args, &image_path, 1);
+        args.omit_sender = true;
+        let omitted = build_email(&args, &image_path, 1);
+        args.omit_sender = false;
+        args.auth_email = Some("SUPPORT@example.com".to_string());
+        let matching = build_email(&args, &image_path, 1);
+
+        fs::remove_file(image_path)?;
+
+        let sender = |message: Message| message.headers().get_raw("Sender").map(str::to_string);
+        assert_eq!(sender(mismatch?).as_deref(), Some("agent@gmail.com"));
+        assert_eq!(sender(omitted?), None);
+        assert_eq!(sender(matching?), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_addresses() {
+        assert_eq!(
+            split_addresses("a@example.com, \"Doe, Jane\" <jane@example.com>,, b@example.com "),
+            vec![
+                "a@example.com",
+                "\"Doe, Jane\" <jane@example.com>",
+                "b@example.com"
+            ]
+        );
+        assert!(split_addresses(" , ").is_empty());
+    }
+
+    #[test]
+    fn test_build_email_invalid_bcc_policy() -> Result<(), EsimMailerError> {
+        let image_path = std::env::temp_dir().join("test_image_invalid_bcc.png");
+        fs::write(&image_path, b"fake image data")?;
+
+        let mut args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            bcc: Some("records@example.com, not an address, archive@example.com".to_string()),
+            ..Default::default()
+        };
+        let abort = build_email(&args, &image_path, 1);
+        args.invalid_bcc = InvalidBccPolicy::Skip;
+        let skip = build_email(&args, &image_path, 1);
+
+        fs::remove_file(image_path)?;
+
+        assert!(matches!(abort, Err(EsimMailerError::MessageError(_))));
+        let recipients: Vec<String> = skip?
+            .envelope()
+            .to()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            recipients,
+            vec![
+                "recipient@example.com",
+                "records@example.com",
+                "archive@example.com"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_email_image_disposition() -> Result<(), EsimMailerError> {
+        let image_path = std::env::temp_dir().join("test_image_disposition.png");
+        fs::write(&image_path, b"fake image data")?;
+
+        let mut args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            ..Default::default()
+        };
+        let inline = build_email(&args, &image_path, 1);
+        args.image_disposition = Some(ImageDisposition::Attachment);
+        let referenced_attachment = build_email(&args, &image_path, 1);
+        args.attach_qr = true;
+        args.image_disposition = None;
+        let attachment = build_email(&args, &image_path, 1);
+        args.image_disposition = Some(ImageDisposition::Inline);
+        let unreferenced_inline = build_email(&args, &image_path, 1);
+
+        fs::remove_file(image_path)?;
+
+        let formatted =
+            |message: Message| String::from_utf8_lossy(&message.formatted()).to_string();
+        let inline = formatted(inline?);
+        assert!(inline.contains("Content-Disposition: inline\r\n"));
+        let referenced_attachment = formatted(referenced_attachment?);
+        assert!(
+            referenced_attachment.contains(
+                "Content-Disposition: attachment; filename=\"test_image_disposition.png\""
+            )
+        );
+        assert!(referenced_attachment.contains("Content-ID: <qr_image_cid@"));
+        assert!(
+            formatted(attachment?).contains(
+                "Content-Disposition: attachment; filename=\"test_image_disposition.png\""
+            )
+        );
+        assert!(
+            formatted(unreferenced_inline?)
+                .contains("Content-Disposition: inline; filename=\"test_image_disposition.png\"")
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "qr")]
+    #[test]
+    fn test_fit_image_to_provider() {
+        // Noise in 4 pixel blocks, like the modules of a QR code, doesn't
+        // compress well, so this is around 600 KB as a PNG
+        let (width, height) = (640, 640);
+        let mut state: u32 = 12345;
+        let blocks: Vec<u8> = (0..(width / 4) * (height / 4) * 3)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect();
+        let pixels: Vec<u8> = (0..width * height * 3)
+            .map(|index| {
+                let (x, y, channel) = ((index / 3) % width, (index / 3) / width, index % 3);
+                blocks[((y / 4) * (width / 4) + x / 4) * 3 + channel]
+            })
+            .collect();
+        let mut image_data = Vec::new();
+        let mut encoder = png::Encoder::new(&mut image_data, width as u32, height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&pixels).unwrap();
+        writer.finish().unwrap();
+        assert!(image_data.len() > Provider::Outlook.max_image_bytes());
+        assert!(image_data.len() < Provider::Gmail.max_image_bytes());
+
+        let mut args = Args {
+            email_from: "test@gmail.com".to_string(),
+            ..Default::default()
+        };
+        let for_gmail = fit_image_to_provider(&args, image_data.clone());
+        args.email_from = "test@outlook.com".to_string();
+        let for_outlook = fit_image_to_provider(&args, image_data.clone());
+        args.max_image_bytes = HashMap::from([("outlook".to_string(), 1024 * 1024)]);
+        let for_outlook_with_override = fit_image_to_provider(&args, image_data.clone());
+
+        assert_eq!(for_gmail, image_data);
+        assert!(for_outlook.len() <= Provider::Outlook.max_image_bytes());
+        assert_eq!(for_outlook_with_override, image_data);
+    }
+
+    #[test]
+    fn test_build_email_cc_reply_to() -> Result<(), EsimMailerError> {
+        let image_path = std::env::temp_dir().join("test_image_cc_reply_to.png");
+        fs::write(&image_path, b"fake image data")?;
+
+        let mut args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            reply_to: Some("support@example.com".to_string()),
+            ..Default::default()
+        };
+        let without_flag = build_email(&args, &image_path, 1);
+        args.cc_reply_to = true;
+        let with_flag = build_email(&args, &image_path, 1);
+        args.cc = Some("Support@Example.com".to_string());
+        let already_cc = build_email(&args, &image_path, 1);
+
+        fs::remove_file(image_path)?;
+
+        let cc = |message: &Message| message.headers().get_raw("Cc").map(str::to_string);
+        let without_flag = without_flag?;
+        assert_eq!(
+            without_flag.headers().get_raw("Reply-To"),
+            Some("support@example.com")
+        );
+        assert_eq!(cc(&without_flag), None);
+        assert_eq!(cc(&with_flag?), Some("support@example.com".to_string()));
+        let already_cc = already_cc?;
+        assert_eq!(cc(&already_cc), Some("Support@Example.com".to_string()));
+        assert_eq!(already_cc.envelope().to().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_email_bcc_group_file() -> Result<(), EsimMailerError> {
+        let image_path = std::env::temp_dir().join("test_image_bcc_group.png");
+        let group_path = std::env::temp_dir().join("test_bcc_group.txt");
+        fs::write(&image_path, b"fake image data")?;
+        fs::write(
+            &group_path,
+            "# Archive group\narchive@example.com\n\nRecords@Example.com\n",
+        )?;
+
+        let args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            bcc: Some("records@example.com".to_string()),
+            bcc_group_file: Some(group_path.clone()),
+            ..Default::default()
+        };
+        let result = build_email(&args, &image_path, 1);
+        fs::remove_file(&group_path)?;
+        let missing = build_email(&args, &image_path, 1);
+
+        fs::remove_file(image_path)?;
+
+        let recipients: Vec<String> = result?
+            .envelope()
+            .to()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            recipients,
+            vec![
+                "recipient@example.com",
+                "records@example.com",
+                "archive@example.com"
+            ]
+        );
+        assert!(matches!(missing, Err(EsimMailerError::ConfigError(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_email_provider_bcc() -> Result<(), EsimMailerError> {
+        let image_path = std::env::temp_dir().join("test_image_provider_bcc.png");
+        fs::write(&image_path, b"fake image data")?;
+
+        let mut args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            bcc: Some("records@example.com".to_string()),
+            provider_bcc: HashMap::from([
+                ("gmail".to_string(), "archive@gmail.com".to_string()),
+                ("Outlook".to_string(), "".to_string()),
+            ]),
+            ..Default::default()
+        };
+        let gmail = build_email(&args, &image_path, 1);
+        args.email_from = "test@outlook.com".to_string();
+        let outlook = build_email(&args, &image_path, 1);
+
+        fs::remove_file(image_path)?;
+
+        let recipients = |message: Message| -> Vec<String> {
+            message
+                .envelope()
+                .to()
+                .iter()
+                .map(|address| address.to_string())
+                .collect()
+        };
+        assert_eq!(
+            recipients(gmail?),
+            vec![
+                "recipient@example.com",
+                "records@example.com",
+                "archive@gmail.com"
+            ]
+        );
+        assert_eq!(
+            recipients(outlook?),
+            vec!["recipient@example.com", "records@example.com"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_provider_requires_oauth() {
+        assert!(Provider::Gmail.requires_oauth());
+        assert!(Provider::Outlook.requires_oauth());
+        assert!(!Provider::Fastmail.requires_oauth());
+        assert!(!Provider::Zoho.requires_oauth());
+        assert!(!Provider::Gmx.requires_oauth());
+    }
+
+    #[test]
+    fn test_provider_setup_instructions() {
+        let providers = [
+            (Provider::Gmail, "OAuth"),
+            (Provider::Outlook, "OAuth"),
+            (Provider::Fastmail, "app password"),
+            (Provider::Zoho, "app password"),
+            (Provider::Gmx, "SMTP access"),
+        ];
+        for (provider, keyword) in providers {
+            let instructions = provider.setup_instructions();
+            assert!(
+                instructions.contains(keyword),
+                "{} instructions don't mention {}",
+                provider,
+                keyword
+            );
+        }
+
+        // The token can only be given through the GUI or the environment
+        for provider in [Provider::Fastmail, Provider::Zoho, Provider::Gmx] {
+            let instructions = provider.setup_instructions();
+            assert!(instructions.contains("App Password field"));
+            assert!(instructions.contains(crate::token::TOKEN_ENV_VAR));
+        }
+    }
+
+    #[test]
+    fn test_provider_display() {
+        assert_eq!(Provider::Gmail.to_string(), "Gmail");
+        assert_eq!(Provider::Outlook.to_string(), "Outlook");
+        assert_eq!(Provider::Fastmail.to_string(), "Fastmail");
+        assert_eq!(Provider::Zoho.to_string(), "Zoho");
+        assert_eq!(Provider::Gmx.to_string(), "GMX");
+    }
+
+    #[test]
+    fn test_send_email() -> io::Result<()> {
+        // Create a temporary test image
+        let temp_dir = std::env::temp_dir();
+        let image_path = temp_dir.join("test_image.png");
+        fs::write(&image_path, b"fake image data")?;
+
+        let args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            bcc: Some("bcc@example.com".to_string()),
+            provider: "TestProvider".to_string(),
+            name: "Test User".to_string(),
+            data_amount: "1GB".to_string(),
+            time_period: "7 days".to_string(),
+            location: "TestLocation".to_string(),
+            ..Default::default()
+        };
+
+        // Test the function - it should fail when trying to send
+        let result = send_email(&args, "fake_token".to_string(), &image_path, 1);
+
+        // Clean up the temporary file
+        fs::remove_file(image_path)?;
+
+        // We expect an error from the SMTP client
+        assert!(result.is_err());
+        let err = result.unwrap_err();
+        assert!(err.to_string().contains("Could not send email"));
+        assert!(
+            err.to_string()
+                .contains("mechanism does not expect a challenge")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_email_image_content_type_override() -> Result<(), EsimMailerError> {
+        let image_path = std::env::temp_dir().join("test_image_override.bin");
+        fs::write(&image_path, b"fake image data")?;
+
+        let args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            image_content_type: Some("image/jpeg".to_string()),
+            ..Default::default()
+        };
+        let result = build_email(&args, &image_path, 1);
+
+        fs::remove_file(image_path)?;
+
+        let message = String::from_utf8(result?.formatted()).unwrap();
+        assert!(message.contains("Content-Type: image/jpeg"));
+        assert!(!message.contains("Content-Type: image/png"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_email_invalid_image_content_type() {
+        let image_path = std::env::temp_dir().join("test_image_invalid_type.bin");
+        fs::write(&image_path, b"fake image data").unwrap();
+
+        let args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            image_content_type: Some("not a mime type".to_string()),
+            ..Default::default()
+        };
+        let result = build_email(&args, &image_path, 1);
+
+        fs::remove_file(image_path).unwrap();
+
+        assert!(matches!(result, Err(EsimMailerError::MessageError(_))));
+    }
+
+    #[cfg(feature = "qr")]
+    #[test]
+    fn test_build_email_from_activation_code() -> Result<(), EsimMailerError> {
+        let args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            activation_code: Some("LPA:1$smdp.example.com$ABCD-1234".to_string()),
+            ..Default::default()
+        };
+        let image_path = std::env::temp_dir().join("test_image_not_needed.png");
+        let message = String::from_utf8(build_email(&args, &image_path, 1)?.formatted()).unwrap();
+
+        assert!(message.contains("Content-Type: image/png"));
+        // Base64 of the PNG signature
+        assert!(message.contains("iVBORw0KGgo"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_deliver_calls_on_success_hook() -> Result<(), EsimMailerError> {
+        let image_path = std::env::temp_dir().join("test_image_hook.png");
+        fs::write(&image_path, b"fake image data")?;
+
+        let args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            ..Default::default()
+        };
+        let result = build_email(&args, &image_path, 1);
+        fs::remove_file(image_path)?;
+        let email = result?;
+
+        let calls = std::cell::Cell::new(0);
+        let on_success = |sent: &Args| {
+            assert_eq!(sent.email_to, "recipient@example.com");
+            calls.set(calls.get() + 1);
+        };
+        let mailer = StubTransport::new_ok();
+        deliver(&mailer, &email, &args, Some(&on_success))?;
+
+        assert_eq!(calls.get(), 1);
+        assert_eq!(mailer.messages().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_deliver_chunks_bcc() -> Result<(), EsimMailerError> {
+        let image_path = std::env::temp_dir().join("test_image_bcc_chunks.png");
+        fs::write(&image_path, b"fake image data")?;
+
+        let bcc: Vec<String> = (1..=5).map(|n| format!("audit{}@example.com", n)).collect();
+        let mut args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            cc: Some("manager@example.com".to_string()),
+            bcc: Some(bcc.join(", ")),
+            bcc_chunk_size: Some(2),
+            ..Default::default()
+        };
+        let result = build_email(&args, &image_path, 1);
+        fs::remove_file(image_path)?;
+        let email = result?;
+
+        let mailer = StubTransport::new_ok();
+        deliver(&mailer, &email, &args, None)?;
+
+        let recipients: Vec<Vec<String>> = mailer
+            .messages()
+            .iter()
+            .map(|(envelope, _)| envelope.to().iter().map(Address::to_string).collect())
+            .collect();
+        assert_eq!(
+            recipients,
+            vec![
+                vec![
+                    "recipient@example.com",
+                    "manager@example.com",
+                    "audit1@example.com",
+                    "audit2@example.com",
+                ],
+                vec!["audit3@example.com", "audit4@example.com"],
+                vec!["audit5@example.com"],
+            ]
+        );
+
+        args.bcc_chunk_size = Some(5);
+        let mailer = StubTransport::new_ok();
+        deliver(&mailer, &email, &args, None)?;
+        assert_eq!(mailer.messages().len(), 1);
+
+        let mailer = StubTransport::new_error();
+        args.bcc_chunk_size = Some(2);
+        assert!(deliver(&mailer, &email, &args, None).is_err());
+        Ok(())
+    }
+
+    /// A transport which fails to send to any envelope with a recipient ending
+    /// in `rejected`, recording the recipients of the envelopes it accepts.
+    struct RejectingTransport {
+        rejected: &'static str,
+        sent: std::cell::RefCell<Vec<Vec<String>>>,
+    }
+
+    impl Transport for RejectingTransport {
+        type Ok = ();
+        type Error = EsimMailerError;
+
+        fn send_raw(&self, envelope: &Envelope, _email: &[u8]) -> Result<(), EsimMailerError> {
+            let recipients: Vec<String> = envelope.to().iter().map(Address::to_string).collect();
+            if recipients
+                .iter()
+                .any(|recipient| recipient.ends_with(self.rejected))
+            {
+                return Err(EsimMailerError::TransientError(
+                    "451 try again later".to_string(),
+                ));
+            }
+            self.sent.borrow_mut().push(recipients);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_deliver_chunks_partial_failure_is_not_retried() -> Result<(), EsimMailerError> {
+        let image_path = std::env::temp_dir().join("test_image_bcc_chunks_partial.png");
+        fs::write(&image_path, b"fake image data")?;
+
+        let bcc: Vec<String> = (1..=5).map(|n| format!("audit{}@example.com", n)).collect();
+        let args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            bcc: Some(bcc.join(", ")),
+            bcc_chunk_size: Some(2),
+            ..Default::default()
+        };
+        let result = build_email(&args, &image_path, 1);
+        fs::remove_file(image_path)?;
+        let email = result?;
+
+        let mailer = RejectingTransport {
+            rejected: "audit3@example.com",
+            sent: Default::default(),
+        };
+        let error = deliver(&mailer, &email, &args, None).unwrap_err();
+
+        assert_eq!(mailer.sent.borrow().len(), 2);
+        assert!(matches!(error, EsimMailerError::SmtpError(_)));
+        assert_eq!(error.category(), ErrorCategory::Permanent);
+        assert!(
+            error
+                .to_string()
+                .contains("audit3@example.com, audit4@example.com")
+        );
+
+        // Nothing was delivered, so the send can be retried as a whole
+        let mailer = RejectingTransport {
+            rejected: "@example.com",
+            sent: Default::default(),
+        };
+        let error = deliver(&mailer, &email, &args, None).unwrap_err();
+        assert!(mailer.sent.borrow().is_empty());
+        assert_eq!(error.category(), ErrorCategory::Transient);
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_emails_for_guardian() -> Result<(), EsimMailerError> {
+        let image_path = std::env::temp_dir().join("test_image_guardian.png");
+        fs::write(&image_path, b"fake image data")?;
+
+        let args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "child@example.com".to_string(),
+            bcc: Some("records@example.com".to_string()),
+            guardian_email: Some("parent@example.com".to_string()),
+            guardian_note: Some("Please help Sam install this.".to_string()),
+            ..Default::default()
+        };
+        let result = build_emails(&args, &image_path, 1);
+        fs::remove_file(image_path)?;
+        let emails = result?;
+
+        let template = EmailTemplate::new();
+        let recipients: Vec<Vec<String>> = emails
+            .iter()
+            .map(|(_, email)| {
+                email
+                    .envelope()
+                    .to()
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            recipients,
+            vec![
+                vec!["child@example.com", "records@example.com"],
+                vec!["parent@example.com"]
+            ]
+        );
+        assert!(!template.body(&emails[0].0).contains("Please help Sam"));
+        assert!(template.body(&emails[1].0).contains("Please help Sam"));
+
+        let mailer = StubTransport::new_ok();
+        deliver_all(&mailer, &emails, None)?;
+        assert_eq!(mailer.messages().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_emails_without_guardian() -> Result<(), EsimMailerError> {
+        let image_path = std::env::temp_dir().join("test_image_no_guardian.png");
+        fs::write(&image_path, b"fake image data")?;
+
+        let args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "child@example.com".to_string(),
+            guardian_email: Some(String::new()),
+            ..Default::default()
+        };
+        let result = build_emails(&args, &image_path, 1);
+        fs::remove_file(image_path)?;
+
+        let emails = result?;
+        assert_eq!(emails.len(), 1);
+        assert_eq!(emails[0].0.recipient_role, RecipientRole::Primary);
+        assert!(
+            !EmailTemplate::new()
+                .body(&emails[0].0)
+                .contains(DEFAULT_GUARDIAN_NOTE)
+        );
+        Ok(())
+    }
+
+    impl Handshake for StubTransport {
+        fn handshake(&self) -> Result<(), EsimMailerError> {
+            Ok(())
+        }
+    }
+
+    /// A transport whose handshake fails the first `failures` times.
+    struct FlakyHandshakeTransport {
+        failures: usize,
+        handshakes: std::cell::Cell<usize>,
+        sent: std::cell::Cell<usize>,
+    }
+
+    impl Transport for FlakyHandshakeTransport {
+        type Ok = ();
+        type Error = lettre::transport::stub::Error;
+
+        fn send_raw(&self, _envelope: &Envelope, _email: &[u8]) -> Result<(), Self::Error> {
+            self.sent.set(self.sent.get() + 1);
+            Ok(())
+        }
+    }
+
+    impl Handshake for FlakyHandshakeTransport {
+        fn handshake(&self) -> Result<(), EsimMailerError> {
+            self.handshakes.set(self.handshakes.get() + 1);
+            if self.handshakes.get() <= self.failures {
+                return Err(EsimMailerError::AuthError(
+                    "454 4.7.0 Temporary authentication failure".to_string(),
+                ));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_deliver_all_retries_handshake() -> Result<(), EsimMailerError> {
+        let image_path = std::env::temp_dir().join("test_image_handshake_retry.png");
+        fs::write(&image_path, b"fake image data")?;
+
+        let mut args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            handshake_retries: 1,
+            handshake_retry_delay: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        let emails = build_emails(&args, &image_path, 1);
+        args.handshake_retries = 0;
+        let emails_without_retry = build_emails(&args, &image_path, 1);
+        fs::remove_file(image_path)?;
+
+        let flaky = || FlakyHandshakeTransport {
+            failures: 1,
+            handshakes: std::cell::Cell::new(0),
+            sent: std::cell::Cell::new(0),
+        };
+        let mailer = flaky();
+        deliver_all(&mailer, &emails?, None)?;
+        assert_eq!(mailer.handshakes.get(), 2);
+        assert_eq!(mailer.sent.get(), 1);
+
+        // Without retries the handshake is left to the send
+        let mailer = flaky();
+        deliver_all(&mailer, &emails_without_retry?, None)?;
+        assert_eq!(mailer.handshakes.get(), 0);
+        assert_eq!(mailer.sent.get(), 1);
+        Ok(())
+    }
+
+    /// A transport which accepts every email with a fixed server reply.
+    struct ReplyTransport(&'static str);
+
+    impl Transport for ReplyTransport {
+        type Ok = Response;
+        type Error = smtp::Error;
+
+        fn send_raw(&self, _envelope: &Envelope, _email: &[u8]) -> Result<Response, smtp::Error> {
+            Ok(self.0.parse().unwrap())
+        }
+    }
+
+    #[test]
+    fn test_transport_cache_reuses_transport_per_account() -> Result<(), EsimMailerError> {
+        let mut cache: TransportCache<StubTransport> = TransportCache::default();
+        let created = std::cell::Cell::new(0);
+        let create = || {
+            created.set(created.get() + 1);
+            Ok(StubTransport::new_ok())
+        };
+
+        let senders = ["sender@gmail.com", "Sender@gmail.com", "other@gmail.com"];
+        for sender in senders {
+            let provider: Provider = sender.parse()?;
+            cache.get_or_try_insert_with(&provider, sender, create)?;
+        }
+
+        assert_eq!(created.get(), 2);
+        assert_eq!(cache.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_deliver_returns_server_reply() -> Result<(), EsimMailerError> {
+        let image_path = std::env::temp_dir().join("test_image_reply.png");
+        fs::write(&image_path, b"fake image data")?;
+
+        let args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            ..Default::default()
+        };
+        let result = build_email(&args, &image_path, 1);
+        fs::remove_file(image_path)?;
+        let email = result?;
+
+        let mailer = ReplyTransport("250 2.0.0 OK 1700000000 queued as 12345\r\n");
+        let reply = deliver(&mailer, &email, &args, None)?;
+        assert_eq!(
+            reply.as_deref(),
+            Some("250 2.0.0 OK 1700000000 queued as 12345")
+        );
+
+        let reply = deliver(&StubTransport::new_ok(), &email, &args, None)?;
+        assert_eq!(reply, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_deliver_skips_on_success_hook_on_failure() -> Result<(), EsimMailerError> {
+        let image_path = std::env::temp_dir().join("test_image_hook_failure.png");
+        fs::write(&image_path, b"fake image data")?;
+
+        let args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            ..Default::default()
+        };
+        let result = build_email(&args, &image_path, 1);
+        fs::remove_file(image_path)?;
+        let email = result?;
+
+        let calls = std::cell::Cell::new(0);
+        let on_success = |_: &Args| calls.set(calls.get() + 1);
+        let result = deliver(
+            &StubTransport::new_error(),
+            &email,
+            &args,
+            Some(&on_success),
+        );
+
+        assert!(matches!(result, Err(EsimMailerError::SmtpError(_))));
+        assert_eq!(calls.get(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_raw_with_fixed_parts_is_reproducible() -> Result<(), EsimMailerError> {
+        let image_path = std::env::temp_dir().join("test_image_render_raw.png");
+        fs::write(&image_path, b"fake image data")?;
+
+        let args = Args {
+            email_from: "test@gmail.com".to_string(),
+            email_to: "recipient@example.com".to_string(),
+            ..Default::default()
+        };
+        let fixed = FixedParts {
+            boundary: "fixed-boundary-1234".to_string(),
+            content_id: "qr_image_cid@fixed".to_string(),
+            date: SystemTime::UNIX_EPOCH,
+        };
+        let first = render_raw(&args, &image_path, 1, Some(&fixed));
+        let second = render_raw(&args, &image_path, 1, Some(&fixed));
+        let random = render_raw(&args, &image_path, 1, None);
+
+        fs::remove_file(image_path)?;
+
+        let first = first?;
+        assert_eq!(first, second?);
+        assert_ne!(first, random?);
+        assert!(
+            String::from_utf8(first)
+                .unwrap()
+                .contains("fixed-boundary-1234")
+        );
         Ok(())
     }
 
@@ -320,6 +6223,7 @@ mod tests {
             data_amount: "1GB".to_string(),
             time_period: "7 days".to_string(),
             location: "TestLocation".to_string(),
+            ..Default::default()
         };
 
         // Create a temporary test image first
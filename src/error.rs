@@ -0,0 +1,203 @@
+use crate::email::ParseProviderError;
+use lettre::transport::{smtp, stub};
+use std::error::Error;
+use std::io;
+
+/// An error which can be returned when building or sending an eSIM email.
+#[derive(Debug, thiserror::Error)]
+pub enum EsimMailerError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("Unsupported email provider: {0}")]
+    UnsupportedProvider(#[from] ParseProviderError),
+
+    #[error("Invalid configuration: {0}")]
+    ConfigError(String),
+
+    #[error("Invalid template: {0}")]
+    TemplateError(String),
+
+    #[error("Invalid message: {0}")]
+    MessageError(String),
+
+    #[error("Could not send email: {0}")]
+    SmtpError(String),
+
+    #[error("Could not send email: {0}")]
+    TransientError(String),
+
+    #[error("Network error: {0}")]
+    NetworkError(String),
+
+    #[error("Could not establish a secure connection to the mail server: {0}")]
+    TlsError(String),
+
+    #[error("Could not authenticate with the mail server: {0}")]
+    AuthError(String),
+}
+
+/// A broad category of error, used to decide how an error should be handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// A temporary failure reported by the server which may succeed later.
+    Transient,
+    /// A failure to reach or talk to the server.
+    Network,
+    /// The server rejected the credentials.
+    Auth,
+    /// A failure which will not succeed if tried again.
+    Permanent,
+}
+
+impl EsimMailerError {
+    /// Returns the category this error belongs to.
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            Self::TransientError(_) => ErrorCategory::Transient,
+            Self::NetworkError(_) => ErrorCategory::Network,
+            Self::AuthError(_) => ErrorCategory::Auth,
+            Self::Io(_)
+            | Self::UnsupportedProvider(_)
+            | Self::ConfigError(_)
+            | Self::TemplateError(_)
+            | Self::MessageError(_)
+            | Self::TlsError(_)
+            | Self::SmtpError(_) => ErrorCategory::Permanent,
+        }
+    }
+}
+
+impl From<smtp::Error> for EsimMailerError {
+    fn from(e: smtp::Error) -> Self {
+        let message = e.to_string();
+        match e.status().map(|code| code.to_string()) {
+            // 530, 534 and 535 are the codes servers use to reject credentials.
+            Some(code) if matches!(code.as_str(), "530" | "534" | "535") => {
+                Self::AuthError(message)
+            }
+            Some(_) if e.is_transient() => Self::TransientError(message),
+            Some(_) => Self::SmtpError(message),
+            // Failed TLS handshakes are reported as connection errors, so look
+            // for the TLS error underneath.
+            None if e.is_tls() || source_chain_has::<native_tls::Error>(&e) => {
+                Self::TlsError(message)
+            }
+            None if e.is_timeout()
+                || !(e.is_response() || e.is_client() || e.is_transport_shutdown()) =>
+            {
+                Self::NetworkError(message)
+            }
+            None => Self::SmtpError(message),
+        }
+    }
+}
+
+/// Returns whether any error in the chain of sources is of type `E`.
+fn source_chain_has<E: Error + 'static>(error: &dyn Error) -> bool {
+    let mut source = error.source();
+    while let Some(error) = source {
+        if error.is::<E>() {
+            return true;
+        }
+        source = error.source();
+    }
+    false
+}
+
+impl From<stub::Error> for EsimMailerError {
+    fn from(e: stub::Error) -> Self {
+        Self::SmtpError(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lettre::SmtpTransport;
+    use lettre::transport::smtp::authentication::{Credentials, Mechanism};
+    use lettre::transport::smtp::client::{Tls, TlsParameters};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Starts a server which sends `greeting`, then answers each line it
+    /// receives with the next of `replies`. Returns the port it listens on.
+    fn fake_server(greeting: &'static str, replies: &'static [&'static str]) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            stream.write_all(greeting.as_bytes()).unwrap();
+            for reply in replies {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                    break;
+                }
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+        });
+        port
+    }
+
+    fn connect(transport: SmtpTransport) -> EsimMailerError {
+        transport.test_connection().unwrap_err().into()
+    }
+
+    #[test]
+    fn test_connection_refused_is_network_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let error = connect(
+            SmtpTransport::builder_dangerous("127.0.0.1")
+                .port(port)
+                .build(),
+        );
+
+        assert!(matches!(error, EsimMailerError::NetworkError(_)));
+        assert_eq!(error.category(), ErrorCategory::Network);
+    }
+
+    #[test]
+    fn test_failed_handshake_is_tls_error() {
+        let port = fake_server("220 localhost ESMTP not actually TLS\r\n", &[]);
+        let tls_parameters = TlsParameters::new("localhost".to_string()).unwrap();
+
+        let error = connect(
+            SmtpTransport::builder_dangerous("127.0.0.1")
+                .port(port)
+                .tls(Tls::Wrapper(tls_parameters))
+                .build(),
+        );
+
+        assert!(matches!(error, EsimMailerError::TlsError(_)));
+    }
+
+    #[test]
+    fn test_rejected_credentials_is_auth_error() {
+        let port = fake_server(
+            "220 localhost ESMTP\r\n",
+            &[
+                "250-localhost\r\n250 AUTH PLAIN\r\n",
+                "535 5.7.8 Authentication credentials invalid\r\n",
+            ],
+        );
+
+        let error = connect(
+            SmtpTransport::builder_dangerous("127.0.0.1")
+                .port(port)
+                .credentials(Credentials::new(
+                    "user@example.com".to_string(),
+                    "wrong".to_string(),
+                ))
+                .authentication(vec![Mechanism::Plain])
+                .build(),
+        );
+
+        assert!(matches!(error, EsimMailerError::AuthError(_)));
+        assert_eq!(error.category(), ErrorCategory::Auth);
+    }
+}
@@ -5,9 +5,11 @@ use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::thread;
 
-use crate::email::{self, EmailTemplate};
+use crate::batch::{BatchConfig, EmailJob, send_batch};
+use crate::email::{self, TransportCache};
 use crate::oauth::OAuthClient;
-use crate::{Args, send_email};
+use crate::token::resolve_token;
+use crate::{Args, EsimMailerError};
 
 // Trait for email operations to allow mocking in tests
 pub trait EmailOperations: Send + Sync {
@@ -17,7 +19,8 @@ pub trait EmailOperations: Send + Sync {
         token: String,
         path: &Path,
         count: usize,
-    ) -> std::io::Result<()>;
+        cache: &mut TransportCache,
+    ) -> Result<(), EsimMailerError>;
     fn get_token(
         &self,
         provider: &email::Provider,
@@ -43,8 +46,9 @@ impl EmailOperations for DefaultEmailOperations {
         token: String,
         path: &Path,
         count: usize,
-    ) -> std::io::Result<()> {
-        send_email(args, token, path, count)
+        cache: &mut TransportCache,
+    ) -> Result<(), EsimMailerError> {
+        email::send_email_cached(args, token, path, count, None, cache)
     }
 
     fn get_token(
@@ -73,6 +77,8 @@ pub struct AppState {
 
     #[serde(skip)]
     pub is_sending: Arc<Mutex<bool>>,
+
+    pub smoke_test: bool,
 }
 
 #[derive(serde::Deserialize, serde::Serialize)]
@@ -127,10 +133,14 @@ impl EsimMailerApp {
     }
 
     fn generate_preview(&mut self) {
-        let template = EmailTemplate::new();
-        let subject = template.subject(&self.state.args, 1);
-        let body = template.body(&self.state.args);
-        self.state.email_preview = format!("Subject: {}\n\nBody:\n{}", subject, body);
+        let preview = email::preview(&self.state.args, 1);
+        self.state.email_preview = format!(
+            "Subject: {}\n\nBody:\n{}",
+            preview.subject, preview.body_html
+        );
+        if let Some(body_text) = preview.body_text {
+            self.state.email_preview += &format!("\n\nPlain text:\n{}", body_text);
+        }
     }
 
     fn send_email_async(&self) {
@@ -141,34 +151,73 @@ impl EsimMailerApp {
 
         let args = self.state.args.clone();
         let image_paths = self.state.image_paths.clone();
+        let smoke_test = self.state.smoke_test;
 
-        let email_provider: email::Provider =
-            args.email_from.parse().expect("Invalid email provider");
+        let email_provider: email::Provider = args
+            .auth_identity()
+            .parse()
+            .expect("Invalid email provider");
 
         thread::spawn(move || {
-            let token = email_ops.get_token(&email_provider, &args.email_from);
+            let token = match resolve_token(&args) {
+                Ok(Some(token)) => Ok(token),
+                Ok(None) if email_provider.requires_oauth() => {
+                    email_ops.get_token(&email_provider, args.auth_identity())
+                }
+                Ok(None) => Err(format!(
+                    "{} needs an app password, enter it in the App Password field. {}",
+                    email_provider,
+                    email_provider.setup_instructions()
+                )
+                .into()),
+                Err(e) => Err(e.into()),
+            };
 
             match token {
                 Ok(token) => {
-                    for (index, path) in image_paths.iter().enumerate() {
-                        match email_ops.send_email(&args, token.clone(), path, index + 1) {
+                    let jobs: Vec<EmailJob> = image_paths
+                        .into_iter()
+                        .enumerate()
+                        .map(|(index, image_path)| EmailJob {
+                            args: args.clone(),
+                            image_path,
+                            count: index + 1,
+                        })
+                        .collect();
+                    // Stop at the first failure rather than retrying a broken connection.
+                    let config = BatchConfig {
+                        fail_fast: true,
+                        smoke_test,
+                        ..Default::default()
+                    };
+
+                    // Reuse the connection to the server across the batch
+                    let mut cache = TransportCache::default();
+                    send_batch(&jobs, &config, |job| {
+                        let result = email_ops.send_email(
+                            &job.args,
+                            token.clone(),
+                            &job.image_path,
+                            job.count,
+                            &mut cache,
+                        );
+                        let mut status_lock = status.lock().unwrap();
+                        match &result {
                             Ok(_) => {
-                                let mut status_lock = status.lock().unwrap();
                                 *status_lock =
-                                    format!("{} eSIM emails sent successfully.", index + 1);
+                                    format!("{} eSIM emails sent successfully.", job.count);
                             }
                             Err(e) => {
-                                let mut status_lock = status.lock().unwrap();
                                 *status_lock = format!("Error sending email: {}", e);
-                                break;
                             }
                         }
-                    }
+                        result
+                    });
                     *is_sending.lock().unwrap() = false;
                 }
                 Err(e) => {
                     let mut status_lock = status.lock().unwrap();
-                    *status_lock = format!("Error getting OAuth token: {}", e);
+                    *status_lock = format!("Error getting token: {}", e);
                     *is_sending.lock().unwrap() = false;
                 }
             }
@@ -178,53 +227,45 @@ impl EsimMailerApp {
     pub fn update_form_field(&mut self, field: &str, value: String) -> bool {
         let mut changed = false;
         match field {
-            "From" => {
-                if self.state.args.email_from != value {
-                    self.state.args.email_from = value;
-                    changed = true;
-                }
+            "From" if self.state.args.email_from != value => {
+                self.state.args.email_from = value;
+                changed = true;
             }
-            "To" => {
-                if self.state.args.email_to != value {
-                    self.state.args.email_to = value;
-                    changed = true;
-                }
+            "To" if self.state.args.email_to != value => {
+                self.state.args.email_to = value;
+                changed = true;
             }
-            "BCC" => {
-                if self.state.args.bcc.as_deref().unwrap_or("") != value {
-                    self.state.args.bcc = Some(value);
-                    changed = true;
-                }
+            "CC" if self.state.args.cc.as_deref().unwrap_or("") != value => {
+                self.state.args.cc = Some(value);
+                changed = true;
             }
-            "Provider" => {
-                if self.state.args.provider != value {
-                    self.state.args.provider = value;
-                    changed = true;
-                }
+            "BCC" if self.state.args.bcc.as_deref().unwrap_or("") != value => {
+                self.state.args.bcc = Some(value);
+                changed = true;
             }
-            "Name" => {
-                if self.state.args.name != value {
-                    self.state.args.name = value;
-                    changed = true;
-                }
+            "Provider" if self.state.args.provider != value => {
+                self.state.args.provider = value;
+                changed = true;
             }
-            "Data Amount" => {
-                if self.state.args.data_amount != value {
-                    self.state.args.data_amount = value;
-                    changed = true;
-                }
+            "Name" if self.state.args.name != value => {
+                self.state.args.name = value;
+                changed = true;
             }
-            "Time Period" => {
-                if self.state.args.time_period != value {
-                    self.state.args.time_period = value;
-                    changed = true;
-                }
+            "Data Amount" if self.state.args.data_amount != value => {
+                self.state.args.data_amount = value;
+                changed = true;
             }
-            "Location" => {
-                if self.state.args.location != value {
-                    self.state.args.location = value;
-                    changed = true;
-                }
+            "Time Period" if self.state.args.time_period != value => {
+                self.state.args.time_period = value;
+                changed = true;
+            }
+            "Location" if self.state.args.location != value => {
+                self.state.args.location = value;
+                changed = true;
+            }
+            "App Password" if self.state.args.token.as_deref().unwrap_or("") != value => {
+                self.state.args.token = Some(value).filter(|token| !token.is_empty());
+                changed = true;
             }
             _ => {}
         }
@@ -259,6 +300,7 @@ impl eframe::App for EsimMailerApp {
                         let fields = [
                             ("From", self.state.args.email_from.clone()),
                             ("To", self.state.args.email_to.clone()),
+                            ("CC", self.state.args.cc.clone().unwrap_or_default()),
                             ("BCC", self.state.args.bcc.clone().unwrap_or_default()),
                             ("Provider", self.state.args.provider.clone()),
                             ("Name", self.state.args.name.clone()),
@@ -283,17 +325,34 @@ impl eframe::App for EsimMailerApp {
                             });
                             ui.end_row();
                         }
+
+                        // Never saved, see Args::token
+                        let mut token = self.state.args.token.clone().unwrap_or_default();
+                        ui.horizontal(|ui| {
+                            ui.label("App Password:");
+                            if ui
+                                .add(
+                                    egui::TextEdit::singleline(&mut token)
+                                        .password(true)
+                                        .hint_text("Only for providers without OAuth")
+                                        .desired_width(f32::INFINITY),
+                                )
+                                .changed()
+                            {
+                                self.update_form_field("App Password", token);
+                            }
+                        });
+                        ui.end_row();
                     });
 
                 ui.add_space(10.0);
 
-                if ui.button("Select QR codes").clicked() {
-                    if let Some(paths) = FileDialog::new()
+                if ui.button("Select QR codes").clicked()
+                    && let Some(paths) = FileDialog::new()
                         .add_filter("Image Files", &["png", "jpg", "jpeg", "gif"])
                         .pick_files()
-                    {
-                        self.state.image_paths = paths;
-                    }
+                {
+                    self.state.image_paths = paths;
                 }
 
                 ui.label(format!(
@@ -301,6 +360,11 @@ impl eframe::App for EsimMailerApp {
                     self.state.image_paths.len()
                 ));
 
+                ui.checkbox(
+                    &mut self.state.smoke_test,
+                    "Send a test email to myself before the batch",
+                );
+
                 ui.add_space(10.0);
 
                 ui.group(|ui| {
@@ -370,9 +434,10 @@ mod tests {
             _token: String,
             _path: &Path,
             _count: usize,
-        ) -> std::io::Result<()> {
+            _cache: &mut TransportCache,
+        ) -> Result<(), EsimMailerError> {
             if self.should_fail {
-                return Err(std::io::Error::new(std::io::ErrorKind::Other, "Mock error"));
+                return Err(EsimMailerError::SmtpError("Mock error".to_string()));
             }
             let mut count = self.send_count.lock().unwrap();
             *count += 1;
@@ -413,6 +478,7 @@ mod tests {
             data_amount: "5GB".to_string(),
             time_period: "30 days".to_string(),
             location: "Egypt".to_string(),
+            ..Default::default()
         };
 
         app.generate_preview();
@@ -434,13 +500,16 @@ mod tests {
         let mut app = EsimMailerApp::new_with_email_ops(mock_ops.clone());
 
         // Setup test data
+        let image_path = std::env::temp_dir().join("test_gui_send_email_success.png");
+        std::fs::write(&image_path, b"fake image data").unwrap();
         app.state.args.email_from = "test@gmail.com".to_string();
-        app.state.image_paths = vec![PathBuf::from("test.png")];
+        app.state.image_paths = vec![image_path.clone()];
 
         app.send_email_async();
 
         // Give the async operation time to complete
         std::thread::sleep(std::time::Duration::from_millis(100));
+        std::fs::remove_file(image_path).unwrap();
 
         assert_eq!(*mock_ops.send_count.lock().unwrap(), 1);
         assert!(
@@ -452,23 +521,70 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_send_email_smoke_test() {
+        let mock_ops = Arc::new(MockEmailOperations::new(false));
+        let mut app = EsimMailerApp::new_with_email_ops(mock_ops.clone());
+
+        let image_path = std::env::temp_dir().join("test_gui_send_email_smoke_test.png");
+        std::fs::write(&image_path, b"fake image data").unwrap();
+        app.state.args.email_from = "test@gmail.com".to_string();
+        app.state.image_paths = vec![image_path.clone()];
+        app.state.smoke_test = true;
+
+        app.send_email_async();
+
+        // Give the async operation time to complete
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        std::fs::remove_file(image_path).unwrap();
+
+        // The test email to the sender, then the email itself
+        assert_eq!(*mock_ops.send_count.lock().unwrap(), 2);
+    }
+
     #[test]
     fn test_send_email_failure() {
         let mock_ops = Arc::new(MockEmailOperations::new(true));
         let mut app = EsimMailerApp::new_with_email_ops(mock_ops);
 
         // Setup test data
+        let image_path = std::env::temp_dir().join("test_gui_send_email_failure.png");
+        std::fs::write(&image_path, b"fake image data").unwrap();
         app.state.args.email_from = "test@gmail.com".to_string();
-        app.state.image_paths = vec![PathBuf::from("test.png")];
+        app.state.image_paths = vec![image_path.clone()];
 
         app.send_email_async();
 
         // Give the async operation time to complete
         std::thread::sleep(std::time::Duration::from_millis(100));
+        std::fs::remove_file(image_path).unwrap();
 
         assert!(app.state.status.lock().unwrap().contains("Error"));
     }
 
+    #[test]
+    fn test_send_email_app_password_provider_without_token() {
+        let mock_ops = Arc::new(MockEmailOperations::new(false));
+        let mut app = EsimMailerApp::new_with_email_ops(mock_ops.clone());
+
+        app.state.args.email_from = "test@zoho.com".to_string();
+        app.state.image_paths = vec![PathBuf::from("test.png")];
+
+        app.send_email_async();
+
+        // Give the async operation time to complete
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        assert_eq!(*mock_ops.send_count.lock().unwrap(), 0);
+        assert!(
+            app.state
+                .status
+                .lock()
+                .unwrap()
+                .contains("Zoho needs an app password")
+        );
+    }
+
     #[test]
     fn test_form_field_updates() {
         let email_ops = Arc::new(MockEmailOperations::new(false));
@@ -505,6 +621,15 @@ mod tests {
         assert!(!app.update_form_field("Location", "Japan".to_string()));
         assert!(!app.update_form_field("BCC", "bcc@example.com".to_string()));
 
+        // Test app password, which is cleared when emptied
+        assert!(app.update_form_field("App Password", "abcd efgh ijkl mnop".to_string()));
+        assert_eq!(
+            app.get_form_state().token.as_deref(),
+            Some("abcd efgh ijkl mnop")
+        );
+        assert!(app.update_form_field("App Password", "".to_string()));
+        assert_eq!(app.get_form_state().token, None);
+
         // Test empty BCC
         assert!(app.update_form_field("BCC", "".to_string()));
         assert_eq!(app.get_form_state().bcc, Some("".to_string()));
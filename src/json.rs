@@ -0,0 +1,132 @@
+use crate::batch::EmailJob;
+use crate::csv::checked_job;
+use crate::{Args, EsimMailerError};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// One job as written in a JSON file, with the fields of [`Args`] alongside
+/// the image path and count.
+#[derive(Debug, serde::Deserialize)]
+struct JsonJob {
+    #[serde(flatten)]
+    args: Args,
+
+    #[serde(default)]
+    image_path: Option<PathBuf>,
+
+    #[serde(default)]
+    count: Option<usize>,
+}
+
+/// Reads a JSON array of jobs. Each object holds the fields of [`Args`] along
+/// with an optional `image_path`, relative to the JSON file, and `count`,
+/// which is 1 when missing or 0.
+///
+/// Fails on the first entry which couldn't be sent, naming its index in the
+/// array.
+pub fn load_jobs_json(path: &Path) -> Result<Vec<EmailJob>, EsimMailerError> {
+    let contents = fs::read_to_string(path).map_err(|e| {
+        EsimMailerError::ConfigError(format!(
+            "Could not read JSON file '{}': {}",
+            path.display(),
+            e
+        ))
+    })?;
+    let entries: Vec<serde_json::Value> = serde_json::from_str(&contents).map_err(|e| {
+        EsimMailerError::ConfigError(format!(
+            "{}: Expected an array of jobs: {}",
+            path.display(),
+            e
+        ))
+    })?;
+
+    let directory = path.parent().unwrap_or(Path::new(""));
+    entries
+        .into_iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            serde_json::from_value::<JsonJob>(entry)
+                .map_err(|e| e.to_string())
+                .and_then(|job| {
+                    let count = job.count.filter(|count| *count > 0).unwrap_or(1);
+                    checked_job(directory, job.args, job.image_path, count)
+                })
+                .map_err(|message| {
+                    EsimMailerError::ConfigError(format!(
+                        "{} entry {}: {}",
+                        path.display(),
+                        index,
+                        message
+                    ))
+                })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_jobs_json() {
+        let json_path = std::env::temp_dir().join("test_load_jobs_json.json");
+        let image_path = std::env::temp_dir().join("test_load_jobs_json.png");
+        fs::write(&image_path, b"fake image data").unwrap();
+        fs::write(
+            &json_path,
+            r#"[
+                {
+                    "email_from": "test@gmail.com",
+                    "email_to": "jane@example.com",
+                    "name": "Jane",
+                    "location": "Japan",
+                    "image_path": "test_load_jobs_json.png",
+                    "count": 2
+                },
+                {
+                    "email_from": "test@gmail.com",
+                    "email_to": "sam@example.com",
+                    "activation_code": "LPA:1$example.com$DEF"
+                }
+            ]"#,
+        )
+        .unwrap();
+
+        let jobs = load_jobs_json(&json_path);
+
+        fs::remove_file(json_path).unwrap();
+        fs::remove_file(&image_path).unwrap();
+
+        let jobs = jobs.unwrap();
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs[0].args.email_to, "jane@example.com");
+        assert_eq!(jobs[0].args.name, "Jane");
+        assert_eq!(jobs[0].args.location, "Japan");
+        assert_eq!(jobs[0].image_path, image_path);
+        assert_eq!(jobs[0].count, 2);
+        assert_eq!(
+            jobs[1].args.activation_code.as_deref(),
+            Some("LPA:1$example.com$DEF")
+        );
+        assert_eq!(jobs[1].count, 1);
+    }
+
+    #[test]
+    fn test_load_jobs_json_malformed_entry() {
+        let json_path = std::env::temp_dir().join("test_load_jobs_json_malformed.json");
+        fs::write(
+            &json_path,
+            r#"[
+                {"email_to": "jane@example.com", "activation_code": "LPA:1$example.com$ABC"},
+                {"email_to": "sam@example.com", "count": "lots"}
+            ]"#,
+        )
+        .unwrap();
+
+        let jobs = load_jobs_json(&json_path);
+
+        fs::remove_file(json_path).unwrap();
+
+        assert!(matches!(jobs, Err(EsimMailerError::ConfigError(e)) if e.contains("entry 1")));
+    }
+}
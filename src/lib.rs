@@ -1,10 +1,28 @@
 pub mod args;
+pub mod batch;
+pub mod cli;
+pub mod clock;
+pub mod csv;
+pub mod dns;
+pub mod download;
 pub mod email;
 mod embedded;
+pub mod error;
 pub mod gui;
+pub mod json;
+pub mod lpa;
+pub mod markdown;
 pub mod oauth;
+pub mod proxy;
+#[cfg(feature = "qr")]
+pub mod qr;
+pub mod rate_limit;
+pub mod registry;
+pub mod retry;
+pub mod token;
 
 // Re-export commonly used items
-pub use args::Args;
+pub use args::{Args, CheckPolicy, RecipientRole, TlsVersion};
 pub use email::send_email;
+pub use error::EsimMailerError;
 pub use oauth::OAuthClient;
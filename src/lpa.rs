@@ -0,0 +1,132 @@
+use crate::EsimMailerError;
+
+/// The prefix every eSIM activation code starts with.
+pub const LPA_PREFIX: &str = "LPA:";
+
+/// The parts of an eSIM activation code such as
+/// `LPA:1$smdp.example.com$MATCHING-ID`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LpaComponents {
+    /// Address of the SM-DP+ server the profile is downloaded from
+    pub smdp_address: String,
+
+    /// Identifies the profile on the SM-DP+ server, which may be empty
+    pub matching_id: String,
+
+    /// Object identifier of the SM-DP+ server (optional)
+    pub smdp_oid: Option<String>,
+
+    /// Whether a confirmation code is needed to download the profile
+    pub confirmation_code_required: bool,
+}
+
+/// Parses an activation code of the form
+/// `LPA:1$SMDP_ADDRESS$MATCHING_ID[$SMDP_OID[$1]]`, as defined in GSMA SGP.22.
+pub fn validate_lpa(s: &str) -> Result<LpaComponents, EsimMailerError> {
+    let invalid = |problem: &str| {
+        EsimMailerError::ConfigError(format!("Invalid activation code '{}': {}", s, problem))
+    };
+
+    let rest = s
+        .trim()
+        .strip_prefix(LPA_PREFIX)
+        .ok_or_else(|| invalid(&format!("must start with '{}'", LPA_PREFIX)))?;
+    let fields: Vec<&str> = rest.split('$').collect();
+    if fields[0] != "1" {
+        return Err(invalid("only format 1 is supported"));
+    }
+    if !(3..=5).contains(&fields.len()) {
+        return Err(invalid("expected 2 to 4 fields after the format"));
+    }
+
+    let smdp_address = fields[1];
+    let is_hostname = smdp_address.split('.').all(|label| {
+        !label.is_empty()
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    });
+    if !is_hostname {
+        return Err(invalid("the SM-DP+ address isn't a valid host name"));
+    }
+
+    let matching_id = fields[2];
+    if !matching_id
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        return Err(invalid(
+            "the matching ID may only contain letters, digits and dashes",
+        ));
+    }
+
+    let smdp_oid = fields.get(3).filter(|oid| !oid.is_empty());
+    if smdp_oid.is_some_and(|oid| {
+        !oid.split('.')
+            .all(|arc| !arc.is_empty() && arc.chars().all(|c| c.is_ascii_digit()))
+    }) {
+        return Err(invalid("the SM-DP+ OID isn't a valid object identifier"));
+    }
+
+    let confirmation_code_required = match fields.get(4) {
+        None => false,
+        Some(&"1") => true,
+        Some(_) => return Err(invalid("the confirmation code flag must be '1'")),
+    };
+
+    Ok(LpaComponents {
+        smdp_address: smdp_address.to_string(),
+        matching_id: matching_id.to_string(),
+        smdp_oid: smdp_oid.map(|oid| oid.to_string()),
+        confirmation_code_required,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_lpa() {
+        let components = validate_lpa("LPA:1$smdp.example.com$ABCD-1234-EFGH-5678").unwrap();
+        assert_eq!(
+            components,
+            LpaComponents {
+                smdp_address: "smdp.example.com".to_string(),
+                matching_id: "ABCD-1234-EFGH-5678".to_string(),
+                smdp_oid: None,
+                confirmation_code_required: false,
+            }
+        );
+
+        let components = validate_lpa("LPA:1$smdp.example.com$$1.3.6.1.4.1.31746$1").unwrap();
+        assert_eq!(components.matching_id, "");
+        assert_eq!(components.smdp_oid.as_deref(), Some("1.3.6.1.4.1.31746"));
+        assert!(components.confirmation_code_required);
+    }
+
+    #[test]
+    fn test_validate_lpa_malformed() {
+        for activation_code in [
+            "",
+            "1$smdp.example.com$ABCD",
+            "LPA:2$smdp.example.com$ABCD",
+            "LPA:1$smdp.example.com",
+            "LPA:1$$ABCD",
+            "LPA:1$smdp..example.com$ABCD",
+            "LPA:1$smdp.example.com$AB CD",
+            "LPA:1$smdp.example.com$ABCD$not-an-oid",
+            "LPA:1$smdp.example.com$ABCD$$2",
+            "LPA:1$smdp.example.com$ABCD$1.2$1$extra",
+        ] {
+            assert!(
+                matches!(
+                    validate_lpa(activation_code),
+                    Err(EsimMailerError::ConfigError(_))
+                ),
+                "{}",
+                activation_code
+            );
+        }
+    }
+}
@@ -1,18 +1,60 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 use eframe::egui;
+use esim_mailer::cli::{self, CliOptions};
 use esim_mailer::gui::EsimMailerApp;
+use std::env;
+use std::process::ExitCode;
 
-fn main() -> Result<(), eframe::Error> {
-    let options = eframe::NativeOptions {
+fn main() -> ExitCode {
+    let options = match CliOptions::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if options.to_stdin {
+        return match cli::run_interactive(&options) {
+            Ok(report) => {
+                println!(
+                    "Sent {} of {} emails.",
+                    report.sent.len(),
+                    options.image_paths.len()
+                );
+                for (index, e) in &report.failed {
+                    eprintln!(
+                        "Error sending {}: {}",
+                        options.image_paths[*index].display(),
+                        e
+                    );
+                }
+                report.exit_code(options.exit_policy)
+            }
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    let native_options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
             .with_min_inner_size([320.0, 480.0])
             .with_resizable(true),
         ..Default::default()
     };
-    eframe::run_native(
+    let result = eframe::run_native(
         "eSIM Mailer",
-        options,
+        native_options,
         Box::new(|cc| Ok(Box::new(EsimMailerApp::new(cc)))),
-    )
+    );
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
 }
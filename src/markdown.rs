@@ -0,0 +1,319 @@
+/// A block of Markdown, separated from the next by a blank line or a change
+/// of kind.
+#[derive(Debug, PartialEq)]
+enum Block {
+    Heading(usize, String),
+    Paragraph(String),
+    List { ordered: bool, items: Vec<String> },
+}
+
+/// A run of text inside a block.
+#[derive(Debug, PartialEq)]
+enum Inline {
+    Text(String),
+    Code(String),
+    Strong(Vec<Inline>),
+    Emphasis(Vec<Inline>),
+    Link(Vec<Inline>, String),
+    Image(String, String),
+}
+
+/// Converts Markdown to HTML.
+///
+/// Only the parts of Markdown useful in an email are supported: `#`
+/// headings, paragraphs, `-`, `*` and numbered lists, `**bold**`,
+/// `*italic*`, `` `code` ``, `[links](url)` and `![images](url)`.
+/// Underscores are left alone so `{{placeholders}}` such as `{{QR_CID}}`
+/// come through unchanged.
+pub fn to_html(markdown: &str) -> String {
+    let mut html = String::new();
+    for block in blocks(markdown) {
+        match block {
+            Block::Heading(level, text) => {
+                html.push_str(&format!("<h{0}>{1}</h{0}>\n", level, inline_html(&text)));
+            }
+            Block::Paragraph(text) => {
+                html.push_str(&format!("<p>{}</p>\n", inline_html(&text)));
+            }
+            Block::List { ordered, items } => {
+                let tag = if ordered { "ol" } else { "ul" };
+                html.push_str(&format!("<{}>\n", tag));
+                for item in items {
+                    html.push_str(&format!("<li>{}</li>\n", inline_html(&item)));
+                }
+                html.push_str(&format!("</{}>\n", tag));
+            }
+        }
+    }
+    html
+}
+
+/// Converts Markdown to plain text by dropping the markup. Links are
+/// followed by their URL in brackets and images are left out.
+pub fn to_text(markdown: &str) -> String {
+    let mut blocks_text = Vec::new();
+    for block in blocks(markdown) {
+        let text = match block {
+            Block::Heading(_, text) | Block::Paragraph(text) => inline_text(&parse_inline(&text)),
+            Block::List { ordered, items } => items
+                .iter()
+                .enumerate()
+                .map(|(index, item)| {
+                    let marker = if ordered {
+                        format!("{}.", index + 1)
+                    } else {
+                        "-".to_string()
+                    };
+                    format!("{} {}", marker, inline_text(&parse_inline(item)))
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        };
+        if !text.trim().is_empty() {
+            blocks_text.push(text.trim().to_string());
+        }
+    }
+    blocks_text.join("\n\n")
+}
+
+fn blocks(markdown: &str) -> Vec<Block> {
+    let mut blocks = Vec::new();
+    for line in markdown.lines().map(str::trim) {
+        if line.is_empty() {
+            blocks.push(None);
+            continue;
+        }
+
+        let hashes = line.chars().take_while(|c| *c == '#').count();
+        if (1..=6).contains(&hashes) && line[hashes..].starts_with(' ') {
+            blocks.push(Some(Block::Heading(
+                hashes,
+                line[hashes..].trim().to_string(),
+            )));
+            continue;
+        }
+
+        if let Some((ordered, item)) = list_item(line) {
+            match blocks.last_mut() {
+                Some(Some(Block::List {
+                    ordered: last_ordered,
+                    items,
+                })) if *last_ordered == ordered => items.push(item.to_string()),
+                _ => blocks.push(Some(Block::List {
+                    ordered,
+                    items: vec![item.to_string()],
+                })),
+            }
+            continue;
+        }
+
+        match blocks.last_mut() {
+            Some(Some(Block::Paragraph(text))) => {
+                text.push(' ');
+                text.push_str(line);
+            }
+            _ => blocks.push(Some(Block::Paragraph(line.to_string()))),
+        }
+    }
+    blocks.into_iter().flatten().collect()
+}
+
+/// Returns whether the line is an item of an ordered list, and its text, if
+/// it's a list item.
+fn list_item(line: &str) -> Option<(bool, &str)> {
+    if let Some(item) = ["- ", "* ", "+ "]
+        .iter()
+        .find_map(|marker| line.strip_prefix(marker))
+    {
+        return Some((false, item.trim()));
+    }
+    let digits = line.chars().take_while(char::is_ascii_digit).count();
+    line[digits..]
+        .strip_prefix(". ")
+        .filter(|_| digits > 0)
+        .map(|item| (true, item.trim()))
+}
+
+fn parse_inline(text: &str) -> Vec<Inline> {
+    let mut inlines = Vec::new();
+    let mut plain = String::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let parsed = match c {
+            '\\' => rest[1..].chars().next().map(|escaped| {
+                plain.push(escaped);
+                (None, &rest[1 + escaped.len_utf8()..])
+            }),
+            '{' if rest.starts_with("{{") => rest.find("}}").map(|end| {
+                plain.push_str(&rest[..end + 2]);
+                (None, &rest[end + 2..])
+            }),
+            '`' => delimited(rest, "`")
+                .map(|(code, after)| (Some(Inline::Code(code.to_string())), after)),
+            '*' if rest.starts_with("**") => delimited(rest, "**")
+                .map(|(inner, after)| (Some(Inline::Strong(parse_inline(inner))), after)),
+            '*' => delimited(rest, "*")
+                .map(|(inner, after)| (Some(Inline::Emphasis(parse_inline(inner))), after)),
+            '!' if rest.starts_with("![") => link(&rest[1..]).map(|(alt, url, after)| {
+                (Some(Inline::Image(alt.to_string(), url.to_string())), after)
+            }),
+            '[' => link(rest).map(|(label, url, after)| {
+                (
+                    Some(Inline::Link(parse_inline(label), url.to_string())),
+                    after,
+                )
+            }),
+            _ => None,
+        };
+        match parsed {
+            Some((inline, after)) => {
+                if let Some(inline) = inline {
+                    if !plain.is_empty() {
+                        inlines.push(Inline::Text(std::mem::take(&mut plain)));
+                    }
+                    inlines.push(inline);
+                }
+                rest = after;
+            }
+            None => {
+                plain.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    if !plain.is_empty() {
+        inlines.push(Inline::Text(plain));
+    }
+    inlines
+}
+
+/// Splits `text`, which starts with `delimiter`, into what's between it and
+/// the next `delimiter` and what follows that. As in CommonMark, what's
+/// between them can't start or end with whitespace, so `2 * 3 * 4` is left
+/// as it is.
+fn delimited<'a>(text: &'a str, delimiter: &str) -> Option<(&'a str, &'a str)> {
+    let inner = &text[delimiter.len()..];
+    let end = inner.find(delimiter)?;
+    let between = &inner[..end];
+    if between.is_empty() || between.trim() != between {
+        return None;
+    }
+    Some((between, &inner[end + delimiter.len()..]))
+}
+
+/// Splits `[label](url)` at the start of `text` into the label, the URL and
+/// what follows.
+fn link(text: &str) -> Option<(&str, &str, &str)> {
+    let label_end = text.find("](")?;
+    let url_end = text[label_end..].find(')')? + label_end;
+    Some((
+        &text[1..label_end],
+        text[label_end + 2..url_end].trim(),
+        &text[url_end + 1..],
+    ))
+}
+
+fn inline_html(text: &str) -> String {
+    parse_inline(text).iter().map(to_inline_html).collect()
+}
+
+fn to_inline_html(inline: &Inline) -> String {
+    let children = |inlines: &[Inline]| inlines.iter().map(to_inline_html).collect::<String>();
+    match inline {
+        Inline::Text(text) => escape(text),
+        Inline::Code(code) => format!("<code>{}</code>", escape(code)),
+        Inline::Strong(inlines) => format!("<strong>{}</strong>", children(inlines)),
+        Inline::Emphasis(inlines) => format!("<em>{}</em>", children(inlines)),
+        Inline::Link(label, url) => {
+            format!(
+                r#"<a href="{}">{}</a>"#,
+                escape_attribute(url),
+                children(label)
+            )
+        }
+        Inline::Image(alt, url) => format!(
+            r#"<img src="{}" alt="{}">"#,
+            escape_attribute(url),
+            escape_attribute(alt)
+        ),
+    }
+}
+
+fn inline_text(inlines: &[Inline]) -> String {
+    inlines
+        .iter()
+        .map(|inline| match inline {
+            Inline::Text(text) | Inline::Code(text) => text.clone(),
+            Inline::Strong(inlines) | Inline::Emphasis(inlines) => inline_text(inlines),
+            Inline::Link(label, url) => format!("{} ({})", inline_text(label), url),
+            Inline::Image(..) => String::new(),
+        })
+        .collect()
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn escape_attribute(text: &str) -> String {
+    escape(text).replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_html() {
+        let markdown = "# Your {{location}} eSIM\n\
+                        \n\
+                        Hi **{{name}}**, scan the *code* below.\n\
+                        It includes {{data_amount}} & more.\n\
+                        \n\
+                        ![QR code](cid:{{QR_CID}})\n\
+                        \n\
+                        - Open `Settings`\n\
+                        - See [the guide](https://example.com/help?a=1&b=2)\n\
+                        \n\
+                        1. Scan\n\
+                        2. Travel\n";
+
+        assert_eq!(
+            to_html(markdown),
+            "<h1>Your {{location}} eSIM</h1>\n\
+             <p>Hi <strong>{{name}}</strong>, scan the <em>code</em> below. \
+             It includes {{data_amount}} &amp; more.</p>\n\
+             <p><img src=\"cid:{{QR_CID}}\" alt=\"QR code\"></p>\n\
+             <ul>\n<li>Open <code>Settings</code></li>\n\
+             <li>See <a href=\"https://example.com/help?a=1&amp;b=2\">the guide</a></li>\n</ul>\n\
+             <ol>\n<li>Scan</li>\n<li>Travel</li>\n</ol>\n"
+        );
+    }
+
+    #[test]
+    fn test_to_html_unmatched_markers() {
+        assert_eq!(
+            to_html("2 * 3 = 6, not \\*bold\\* or [a link"),
+            "<p>2 * 3 = 6, not *bold* or [a link</p>\n"
+        );
+    }
+
+    #[test]
+    fn test_to_text() {
+        let markdown = "# Your eSIM\n\n\
+                        Hi **{{name}}**, see [the guide](https://example.com/help).\n\n\
+                        ![QR code](cid:{{QR_CID}})\n\n\
+                        1. Scan\n\
+                        2. Travel\n";
+
+        assert_eq!(
+            to_text(markdown),
+            "Your eSIM\n\n\
+             Hi {{name}}, see the guide (https://example.com/help).\n\n\
+             1. Scan\n\
+             2. Travel"
+        );
+    }
+}
@@ -8,17 +8,90 @@ use oauth2::{
     AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, EndpointNotSet, EndpointSet,
     PkceCodeChallenge, RedirectUrl, RefreshToken, Scope, TokenResponse, TokenUrl,
 };
-use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
 use std::io::{self, BufRead, BufReader, Write};
 use std::net::TcpListener;
+use std::path::Path;
 use url::Url;
 use webbrowser;
 
-#[derive(Serialize, Deserialize)]
-struct CachedToken {
-    refresh_token: String,
+/// Environment variables which can hold a provider's [`OAuthConfig`], named
+/// by [`env_var_name`], e.g. `ESIM_MAILER_GMAIL_OAUTH_CLIENT_ID`.
+pub const CLIENT_ID_ENV_VAR: &str = "OAUTH_CLIENT_ID";
+pub const CLIENT_SECRET_ENV_VAR: &str = "OAUTH_CLIENT_SECRET";
+pub const REDIRECT_URI_ENV_VAR: &str = "OAUTH_REDIRECT_URI";
+
+/// Returns the full name of one of the environment variables above for a
+/// provider.
+pub fn env_var_name(email_provider: &email::Provider, name: &str) -> String {
+    let provider: String = email_provider
+        .to_string()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("ESIM_MAILER_{}_{}", provider, name)
+}
+
+/// OAuth client credentials to use for a provider instead of the ones built
+/// into the app.
+#[derive(Clone, PartialEq, Eq, serde::Deserialize)]
+pub struct OAuthConfig {
+    pub client_id: String,
+
+    /// Client secret (optional, not needed for PKCE-only clients)
+    #[serde(default)]
+    pub client_secret: Option<String>,
+
+    /// Redirect URI (optional, defaults to the local code receiver)
+    #[serde(default)]
+    pub redirect_uri: Option<String>,
+}
+
+impl OAuthConfig {
+    /// Loads the config from a JSON file.
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        serde_json::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Loads the provider's config from the environment. Returns `None` if
+    /// no client ID is set for it.
+    pub fn from_env(email_provider: &email::Provider) -> Option<Self> {
+        Self::from_lookup(|name| env::var(env_var_name(email_provider, name)).ok())
+    }
+
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Option<Self> {
+        let non_empty = |name| lookup(name).filter(|value| !value.is_empty());
+        Some(Self {
+            client_id: non_empty(CLIENT_ID_ENV_VAR)?,
+            client_secret: non_empty(CLIENT_SECRET_ENV_VAR),
+            redirect_uri: non_empty(REDIRECT_URI_ENV_VAR),
+        })
+    }
+}
+
+// Keep the client secret out of logs
+impl fmt::Debug for OAuthConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OAuthConfig")
+            .field("client_id", &self.client_id)
+            .field(
+                "client_secret",
+                &self.client_secret.as_ref().map(|_| "[redacted]"),
+            )
+            .field("redirect_uri", &self.redirect_uri)
+            .finish()
+    }
 }
 
 // Trait for token storage
@@ -53,29 +126,28 @@ pub struct DefaultBrowserOpener;
 
 impl BrowserOpener for DefaultBrowserOpener {
     fn open_url(&self, url: &str) -> io::Result<()> {
-        webbrowser::open(url).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        webbrowser::open(url).map_err(io::Error::other)
     }
 }
 
 // Trait for OAuth code receiver
 pub trait OAuthCodeReceiver: Send + Sync {
-    fn receive_code(&self) -> io::Result<String>;
-}
-
-// Default implementation using TcpListener
-pub struct LocalServerCodeReceiver {
-    port: u16,
+    fn receive_code(&self, redirect_uri: &Url) -> io::Result<String>;
 }
 
-impl Default for LocalServerCodeReceiver {
-    fn default() -> Self {
-        Self { port: 9999 }
-    }
-}
+// Default implementation using TcpListener on the redirect URI's port
+#[derive(Default)]
+pub struct LocalServerCodeReceiver;
 
 impl OAuthCodeReceiver for LocalServerCodeReceiver {
-    fn receive_code(&self) -> io::Result<String> {
-        let listener = TcpListener::bind(format!("127.0.0.1:{}", self.port))?;
+    fn receive_code(&self, redirect_uri: &Url) -> io::Result<String> {
+        let port = redirect_uri.port_or_known_default().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Redirect URI {} has no port", redirect_uri),
+            )
+        })?;
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
 
         for stream in listener.incoming() {
             match stream {
@@ -97,10 +169,7 @@ impl OAuthCodeReceiver for LocalServerCodeReceiver {
             }
         }
 
-        Err(io::Error::new(
-            io::ErrorKind::Other,
-            "Failed to get authorization code",
-        ))
+        Err(io::Error::other("Failed to get authorization code"))
     }
 }
 
@@ -109,6 +178,7 @@ pub struct OAuthClient {
     token_storage: Box<dyn TokenStorage>,
     browser_opener: Box<dyn BrowserOpener>,
     code_receiver: Box<dyn OAuthCodeReceiver>,
+    oauth_configs: HashMap<String, OAuthConfig>,
 }
 
 impl Default for OAuthClient {
@@ -116,7 +186,13 @@ impl Default for OAuthClient {
         Self {
             token_storage: Box::new(MemoryTokenStorage::default()),
             browser_opener: Box::new(DefaultBrowserOpener),
-            code_receiver: Box::new(LocalServerCodeReceiver::default()),
+            code_receiver: Box::new(LocalServerCodeReceiver),
+            oauth_configs: [email::Provider::Gmail, email::Provider::Outlook]
+                .into_iter()
+                .filter_map(|provider| {
+                    OAuthConfig::from_env(&provider).map(|config| (provider.to_string(), config))
+                })
+                .collect(),
         }
     }
 }
@@ -131,9 +207,22 @@ impl OAuthClient {
             token_storage,
             browser_opener,
             code_receiver,
+            oauth_configs: HashMap::new(),
         }
     }
 
+    /// Uses these client credentials for the provider instead of the
+    /// built-in ones.
+    pub fn with_oauth_config(
+        mut self,
+        email_provider: &email::Provider,
+        oauth_config: OAuthConfig,
+    ) -> Self {
+        self.oauth_configs
+            .insert(email_provider.to_string(), oauth_config);
+        self
+    }
+
     pub fn get_or_refresh_token(
         &mut self,
         email_provider: &email::Provider,
@@ -142,15 +231,14 @@ impl OAuthClient {
         let email_hash = format!("{:x}", Sha256::digest(email.as_bytes()));
         let cache_key = format!("{}_{}", email_provider, email_hash);
 
-        if let Some(refresh_token) = self.token_storage.get_token(&cache_key) {
-            if let Ok((access_token, new_refresh_token)) =
+        if let Some(refresh_token) = self.token_storage.get_token(&cache_key)
+            && let Ok((access_token, new_refresh_token)) =
                 self.refresh_oauth_token(email_provider, &refresh_token)
-            {
-                if new_refresh_token != refresh_token {
-                    self.token_storage.set_token(&cache_key, new_refresh_token);
-                }
-                return Ok(access_token);
+        {
+            if new_refresh_token != refresh_token {
+                self.token_storage.set_token(&cache_key, new_refresh_token);
             }
+            return Ok(access_token);
         }
 
         let (access_token, refresh_token) = self.perform_oauth(email_provider)?;
@@ -159,8 +247,9 @@ impl OAuthClient {
     }
 
     fn perform_oauth(&self, email_provider: &email::Provider) -> io::Result<(String, String)> {
-        let config = get_provider_config(email_provider);
-        let client = create_oauth_client(email_provider);
+        let config = get_provider_config(email_provider)?;
+        let client =
+            create_oauth_client(email_provider, email_provider.setting(&self.oauth_configs))?;
 
         let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
 
@@ -172,19 +261,22 @@ impl OAuthClient {
 
         self.browser_opener.open_url(auth_url.as_str())?;
 
-        let code = self.code_receiver.receive_code()?;
+        let redirect_uri = client
+            .redirect_uri()
+            .ok_or_else(|| io::Error::other("No redirect URI configured"))?;
+        let code = self.code_receiver.receive_code(redirect_uri.url())?;
 
         let token = client
             .exchange_code(AuthorizationCode::new(code))
             .set_pkce_verifier(pkce_verifier)
             .request(&BlockingHttpClient::new())
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            .map_err(io::Error::other)?;
 
         let access_token = token.access_token().secret().clone();
         let refresh_token = token
             .refresh_token()
             .map(|rt| rt.secret().clone())
-            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "No refresh token provided"))?;
+            .ok_or_else(|| io::Error::other("No refresh token provided"))?;
 
         Ok((access_token, refresh_token))
     }
@@ -194,12 +286,13 @@ impl OAuthClient {
         email_provider: &email::Provider,
         refresh_token: &str,
     ) -> io::Result<(String, String)> {
-        let client = create_oauth_client(email_provider);
+        let client =
+            create_oauth_client(email_provider, email_provider.setting(&self.oauth_configs))?;
 
         let token_result = client
             .exchange_refresh_token(&RefreshToken::new(refresh_token.to_string()))
             .request(&BlockingHttpClient::new())
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            .map_err(io::Error::other)?;
 
         let access_token = token_result.access_token().secret().clone();
         let refresh_token = token_result
@@ -233,43 +326,71 @@ struct ProviderConfig {
     scope: &'static str,
 }
 
-fn get_provider_config(email_provider: &email::Provider) -> ProviderConfig {
+fn get_provider_config(email_provider: &email::Provider) -> io::Result<ProviderConfig> {
     match email_provider {
-        email::Provider::Gmail => ProviderConfig {
+        email::Provider::Gmail => Ok(ProviderConfig {
             client_id: GMAIL_CLIENT_ID,
             encrypted_client_secret: Some(GMAIL_SECRET),
             auth_url: "https://accounts.google.com/o/oauth2/v2/auth",
             token_url: "https://oauth2.googleapis.com/token",
             redirect_uri: "http://localhost:9999",
             scope: "https://mail.google.com/",
-        },
-        email::Provider::Outlook => ProviderConfig {
+        }),
+        email::Provider::Outlook => Ok(ProviderConfig {
             client_id: OUTLOOK_CLIENT_ID,
             encrypted_client_secret: None, // Outlook doesn't require a client secret for PKCE flow
             auth_url: "https://login.microsoftonline.com/common/oauth2/v2.0/authorize",
             token_url: "https://login.microsoftonline.com/common/oauth2/v2.0/token",
             redirect_uri: "http://localhost:9999",
             scope: "https://outlook.office.com/SMTP.Send offline_access",
-        },
+        }),
+        email::Provider::Fastmail
+        | email::Provider::Zoho
+        | email::Provider::Gmx
+        | email::Provider::Custom(_) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "{} does not support OAuth, use an app password",
+                email_provider
+            ),
+        )),
     }
 }
 
 fn create_oauth_client(
     email_provider: &email::Provider,
-) -> BasicClient<EndpointSet, EndpointNotSet, EndpointNotSet, EndpointNotSet, EndpointSet> {
-    let config = get_provider_config(email_provider);
-    let client_secret = config.encrypted_client_secret.map(decrypt_client_secret);
-
-    let mut client = BasicClient::new(ClientId::new(config.client_id.to_string()))
+    oauth_config: Option<&OAuthConfig>,
+) -> io::Result<BasicClient<EndpointSet, EndpointNotSet, EndpointNotSet, EndpointNotSet, EndpointSet>>
+{
+    let config = get_provider_config(email_provider)?;
+    let (client_id, client_secret, redirect_uri) = match oauth_config {
+        Some(oauth_config) => (
+            oauth_config.client_id.clone(),
+            oauth_config.client_secret.clone(),
+            oauth_config
+                .redirect_uri
+                .clone()
+                .unwrap_or_else(|| config.redirect_uri.to_string()),
+        ),
+        None => (
+            config.client_id.to_string(),
+            config.encrypted_client_secret.map(decrypt_client_secret),
+            config.redirect_uri.to_string(),
+        ),
+    };
+    let redirect_uri = RedirectUrl::new(redirect_uri)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let mut client = BasicClient::new(ClientId::new(client_id))
         .set_auth_uri(AuthUrl::new(config.auth_url.to_string()).unwrap())
         .set_token_uri(TokenUrl::new(config.token_url.to_string()).unwrap())
-        .set_redirect_uri(RedirectUrl::new(config.redirect_uri.to_string()).unwrap());
+        .set_redirect_uri(redirect_uri);
 
     if let Some(secret) = client_secret.map(ClientSecret::new) {
         client = client.set_client_secret(secret);
     }
 
-    client
+    Ok(client)
 }
 
 fn decrypt_client_secret(encrypted_secret: &[u8]) -> String {
@@ -322,9 +443,9 @@ mod tests {
     }
 
     impl OAuthCodeReceiver for MockCodeReceiver {
-        fn receive_code(&self) -> io::Result<String> {
+        fn receive_code(&self, _redirect_uri: &Url) -> io::Result<String> {
             if self.should_fail {
-                Err(io::Error::new(io::ErrorKind::Other, "Failed to get code"))
+                Err(io::Error::other("Failed to get code"))
             } else {
                 Ok(self.code.clone())
             }
@@ -381,10 +502,7 @@ mod tests {
         struct FailingBrowserOpener;
         impl BrowserOpener for FailingBrowserOpener {
             fn open_url(&self, _url: &str) -> io::Result<()> {
-                Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "Failed to open browser",
-                ))
+                Err(io::Error::other("Failed to open browser"))
             }
         }
 
@@ -471,30 +589,174 @@ mod tests {
 
     #[test]
     fn test_get_provider_config() {
-        let gmail_config = get_provider_config(&email::Provider::Gmail);
+        let gmail_config = get_provider_config(&email::Provider::Gmail).unwrap();
         assert_eq!(gmail_config.client_id, GMAIL_CLIENT_ID);
         assert_eq!(
             gmail_config.auth_url,
             "https://accounts.google.com/o/oauth2/v2/auth"
         );
 
-        let outlook_config = get_provider_config(&email::Provider::Outlook);
+        let outlook_config = get_provider_config(&email::Provider::Outlook).unwrap();
         assert_eq!(outlook_config.client_id, OUTLOOK_CLIENT_ID);
         assert_eq!(
             outlook_config.auth_url,
             "https://login.microsoftonline.com/common/oauth2/v2.0/authorize"
         );
+
+        let fastmail_config = get_provider_config(&email::Provider::Fastmail);
+        assert_eq!(
+            fastmail_config.err().map(|e| e.kind()),
+            Some(io::ErrorKind::Unsupported)
+        );
+
+        let zoho_config = get_provider_config(&email::Provider::Zoho);
+        assert_eq!(
+            zoho_config.err().map(|e| e.kind()),
+            Some(io::ErrorKind::Unsupported)
+        );
     }
 
     #[test]
     fn test_create_oauth_client() {
-        let gmail_client = create_oauth_client(&email::Provider::Gmail);
+        let gmail_client = create_oauth_client(&email::Provider::Gmail, None).unwrap();
         assert_eq!(gmail_client.client_id().as_str(), GMAIL_CLIENT_ID);
 
-        let outlook_client = create_oauth_client(&email::Provider::Outlook);
+        let outlook_client = create_oauth_client(&email::Provider::Outlook, None).unwrap();
         assert_eq!(outlook_client.client_id().as_str(), OUTLOOK_CLIENT_ID);
     }
 
+    #[test]
+    fn test_create_oauth_client_with_config() {
+        let oauth_config = OAuthConfig {
+            client_id: "custom-client-id".to_string(),
+            client_secret: Some("custom-secret".to_string()),
+            redirect_uri: Some("http://localhost:8080".to_string()),
+        };
+        let client = create_oauth_client(&email::Provider::Gmail, Some(&oauth_config)).unwrap();
+        assert_eq!(client.client_id().as_str(), "custom-client-id");
+        assert_eq!(
+            client.redirect_uri().map(|uri| uri.as_str()),
+            Some("http://localhost:8080")
+        );
+
+        let oauth_config = OAuthConfig {
+            redirect_uri: Some("not a url".to_string()),
+            ..oauth_config
+        };
+        assert!(create_oauth_client(&email::Provider::Gmail, Some(&oauth_config)).is_err());
+    }
+
+    #[test]
+    fn test_oauth_config_is_per_provider() {
+        let oauth_config = OAuthConfig {
+            client_id: "gmail-client-id".to_string(),
+            client_secret: Some("gmail-secret".to_string()),
+            redirect_uri: None,
+        };
+        let client =
+            OAuthClient::default().with_oauth_config(&email::Provider::Gmail, oauth_config);
+
+        let gmail_client = create_oauth_client(
+            &email::Provider::Gmail,
+            email::Provider::Gmail.setting(&client.oauth_configs),
+        )
+        .unwrap();
+        assert_eq!(gmail_client.client_id().as_str(), "gmail-client-id");
+
+        let outlook_client = create_oauth_client(
+            &email::Provider::Outlook,
+            email::Provider::Outlook.setting(&client.oauth_configs),
+        )
+        .unwrap();
+        assert_eq!(outlook_client.client_id().as_str(), OUTLOOK_CLIENT_ID);
+    }
+
+    #[test]
+    fn test_env_var_name() {
+        assert_eq!(
+            env_var_name(&email::Provider::Gmail, CLIENT_ID_ENV_VAR),
+            "ESIM_MAILER_GMAIL_OAUTH_CLIENT_ID"
+        );
+        assert_eq!(
+            env_var_name(&email::Provider::Outlook, REDIRECT_URI_ENV_VAR),
+            "ESIM_MAILER_OUTLOOK_OAUTH_REDIRECT_URI"
+        );
+    }
+
+    #[test]
+    fn test_local_server_code_receiver_uses_redirect_port() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let redirect_uri = Url::parse(&format!("http://localhost:{}/callback", port)).unwrap();
+
+        let browser = std::thread::spawn(move || {
+            for _ in 0..100 {
+                if let Ok(mut stream) = std::net::TcpStream::connect(("127.0.0.1", port)) {
+                    stream
+                        .write_all(b"GET /callback?code=test-code HTTP/1.1\r\n\r\n")
+                        .unwrap();
+                    return;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+            panic!("The receiver never listened on port {}", port);
+        });
+
+        let code = LocalServerCodeReceiver.receive_code(&redirect_uri).unwrap();
+        browser.join().unwrap();
+        assert_eq!(code, "test-code");
+    }
+
+    #[test]
+    fn test_oauth_config_from_file() {
+        let path = std::env::temp_dir().join("test_oauth_config.json");
+        fs::write(
+            &path,
+            r#"{"client_id": "file-client-id", "client_secret": "file-secret"}"#,
+        )
+        .unwrap();
+
+        let result = OAuthConfig::from_file(&path);
+        fs::remove_file(&path).unwrap();
+
+        let oauth_config = result.unwrap();
+        assert_eq!(oauth_config.client_id, "file-client-id");
+        assert_eq!(oauth_config.client_secret.as_deref(), Some("file-secret"));
+        assert_eq!(oauth_config.redirect_uri, None);
+    }
+
+    #[test]
+    fn test_oauth_config_from_env() {
+        let vars = HashMap::from([
+            (CLIENT_ID_ENV_VAR, "env-client-id"),
+            (CLIENT_SECRET_ENV_VAR, "env-secret"),
+            (REDIRECT_URI_ENV_VAR, ""),
+        ]);
+        let oauth_config =
+            OAuthConfig::from_lookup(|name| vars.get(name).map(|value| value.to_string())).unwrap();
+        assert_eq!(oauth_config.client_id, "env-client-id");
+        assert_eq!(oauth_config.client_secret.as_deref(), Some("env-secret"));
+        assert_eq!(oauth_config.redirect_uri, None);
+
+        assert_eq!(OAuthConfig::from_lookup(|_| None), None);
+    }
+
+    #[test]
+    fn test_oauth_config_debug_redacts_secret() {
+        let oauth_config = OAuthConfig {
+            client_id: "client-id".to_string(),
+            client_secret: Some("super-secret-value".to_string()),
+            redirect_uri: None,
+        };
+        let debug = format!("{:?}", oauth_config);
+        assert!(debug.contains("client-id"));
+        assert!(debug.contains("[redacted]"));
+        assert!(!debug.contains("super-secret-value"));
+    }
+
     #[test]
     fn test_memory_token_storage() {
         let mut storage = MemoryTokenStorage::default();
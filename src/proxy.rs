@@ -0,0 +1,320 @@
+use crate::EsimMailerError;
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// A SOCKS5 proxy to reach the SMTP server through, for networks which block
+/// outbound SMTP.
+#[derive(Default, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct ProxyConfig {
+    /// Host name or IP address of the proxy
+    pub host: String,
+
+    /// Port the proxy listens on
+    pub port: u16,
+
+    /// Username to authenticate with the proxy (optional)
+    pub username: Option<String>,
+
+    /// Password to authenticate with the proxy (optional, never saved)
+    #[serde(skip)]
+    pub password: Option<String>,
+}
+
+// Keep the password out of logs
+impl fmt::Debug for ProxyConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProxyConfig")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "[redacted]"))
+            .finish()
+    }
+}
+
+const SOCKS_VERSION: u8 = 0x05;
+const NO_AUTHENTICATION: u8 = 0x00;
+const USERNAME_PASSWORD: u8 = 0x02;
+const USERNAME_PASSWORD_VERSION: u8 = 0x01;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const CONNECT: u8 = 0x01;
+const IPV4: u8 = 0x01;
+const DOMAIN_NAME: u8 = 0x03;
+const IPV6: u8 = 0x04;
+
+/// Connects to `host` and `port` through the proxy, returning the stream to
+/// the server once the proxy has opened the tunnel (RFC 1928).
+pub fn connect(
+    proxy: &ProxyConfig,
+    host: &str,
+    port: u16,
+    timeout: Duration,
+) -> Result<TcpStream, EsimMailerError> {
+    let mut stream = (proxy.host.as_str(), proxy.port)
+        .to_socket_addrs()
+        .and_then(|mut addresses| {
+            addresses.next().ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::NotFound, "No addresses found")
+            })
+        })
+        .and_then(|address| TcpStream::connect_timeout(&address, timeout))
+        .map_err(|e| {
+            EsimMailerError::NetworkError(format!(
+                "Could not connect to the proxy {}:{}: {}",
+                proxy.host, proxy.port, e
+            ))
+        })?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    handshake(&mut stream, proxy, host, port)?;
+
+    // Leave any timeouts on the tunnel to the SMTP client
+    stream.set_read_timeout(None)?;
+    stream.set_write_timeout(None)?;
+    Ok(stream)
+}
+
+/// Negotiates authentication with the proxy over `stream`, then asks it to
+/// connect to `host` and `port`.
+fn handshake<S: Read + Write>(
+    stream: &mut S,
+    proxy: &ProxyConfig,
+    host: &str,
+    port: u16,
+) -> Result<(), EsimMailerError> {
+    let host_length = u8::try_from(host.len()).map_err(|_| {
+        EsimMailerError::ConfigError(format!("'{}' is too long to send to the proxy", host))
+    })?;
+
+    let credentials = proxy
+        .username
+        .as_deref()
+        .filter(|username| !username.is_empty())
+        .map(|username| (username, proxy.password.as_deref().unwrap_or_default()));
+    let methods: &[u8] = match credentials {
+        Some(_) => &[NO_AUTHENTICATION, USERNAME_PASSWORD],
+        None => &[NO_AUTHENTICATION],
+    };
+    let mut greeting = vec![SOCKS_VERSION, methods.len() as u8];
+    greeting.extend_from_slice(methods);
+    send(stream, &greeting)?;
+
+    let [version, method] = receive::<2>(stream)?;
+    check_version(version)?;
+    match (method, credentials) {
+        (NO_AUTHENTICATION, _) => {}
+        (USERNAME_PASSWORD, Some((username, password))) => {
+            authenticate(stream, username, password)?
+        }
+        (NO_ACCEPTABLE_METHODS, None) => {
+            return Err(proxy_error("requires a username and password"));
+        }
+        _ => return Err(proxy_error("doesn't accept the authentication offered")),
+    }
+
+    let mut request = vec![SOCKS_VERSION, CONNECT, 0x00, DOMAIN_NAME, host_length];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    send(stream, &request)?;
+
+    let [version, reply, _, address_type] = receive::<4>(stream)?;
+    check_version(version)?;
+    if reply != 0x00 {
+        return Err(proxy_error(&format!(
+            "could not connect to {}:{}: {}",
+            host,
+            port,
+            reply_message(reply)
+        )));
+    }
+
+    // The proxy also sends the address it connected from, which isn't needed
+    let address_length = match address_type {
+        IPV4 => 4,
+        IPV6 => 16,
+        DOMAIN_NAME => receive::<1>(stream)?[0] as usize,
+        _ => return Err(proxy_error("sent an unknown address type")),
+    };
+    let mut bound_address = vec![0; address_length + 2];
+    stream
+        .read_exact(&mut bound_address)
+        .map_err(network_error)?;
+    Ok(())
+}
+
+/// Authenticates with a username and password (RFC 1929).
+fn authenticate<S: Read + Write>(
+    stream: &mut S,
+    username: &str,
+    password: &str,
+) -> Result<(), EsimMailerError> {
+    let too_long =
+        || EsimMailerError::ConfigError("The proxy username or password is too long".to_string());
+    let mut request = vec![
+        USERNAME_PASSWORD_VERSION,
+        u8::try_from(username.len()).map_err(|_| too_long())?,
+    ];
+    request.extend_from_slice(username.as_bytes());
+    request.push(u8::try_from(password.len()).map_err(|_| too_long())?);
+    request.extend_from_slice(password.as_bytes());
+    send(stream, &request)?;
+
+    match receive::<2>(stream)? {
+        [_, 0x00] => Ok(()),
+        _ => Err(proxy_error("rejected the username and password")),
+    }
+}
+
+fn send<S: Write>(stream: &mut S, bytes: &[u8]) -> Result<(), EsimMailerError> {
+    stream.write_all(bytes).map_err(network_error)
+}
+
+fn receive<const N: usize>(stream: &mut impl Read) -> Result<[u8; N], EsimMailerError> {
+    let mut bytes = [0; N];
+    stream.read_exact(&mut bytes).map_err(network_error)?;
+    Ok(bytes)
+}
+
+fn check_version(version: u8) -> Result<(), EsimMailerError> {
+    if version != SOCKS_VERSION {
+        return Err(proxy_error("isn't a SOCKS5 proxy"));
+    }
+    Ok(())
+}
+
+fn proxy_error(message: &str) -> EsimMailerError {
+    EsimMailerError::NetworkError(format!("The proxy {}", message))
+}
+
+fn network_error(e: std::io::Error) -> EsimMailerError {
+    EsimMailerError::NetworkError(format!("Lost the connection to the proxy: {}", e))
+}
+
+/// Describes a failed reply to a connect request.
+fn reply_message(reply: u8) -> &'static str {
+    match reply {
+        0x01 => "general failure",
+        0x02 => "not allowed by the proxy's rules",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorCategory;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Runs a proxy which accepts a single connection, answering with
+    /// `replies` in turn and returning the bytes it was sent.
+    fn fake_proxy(replies: Vec<Vec<u8>>) -> (ProxyConfig, thread::JoinHandle<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = ProxyConfig {
+            host: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+            ..Default::default()
+        };
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = Vec::new();
+            for reply in replies {
+                let mut buffer = [0; 512];
+                let read = stream.read(&mut buffer).unwrap();
+                received.extend_from_slice(&buffer[..read]);
+                stream.write_all(&reply).unwrap();
+            }
+            received
+        });
+        (proxy, server)
+    }
+
+    #[test]
+    fn test_password_is_not_saved_or_logged() {
+        let proxy = ProxyConfig {
+            host: "proxy.example.com".to_string(),
+            port: 1080,
+            username: Some("user".to_string()),
+            password: Some("secret".to_string()),
+        };
+
+        let saved = serde_json::to_string(&proxy).unwrap();
+        assert!(!saved.contains("secret"));
+        let loaded: ProxyConfig = serde_json::from_str(&saved).unwrap();
+        assert_eq!(loaded.username.as_deref(), Some("user"));
+        assert_eq!(loaded.password, None);
+
+        let logged = format!("{:?}", proxy);
+        assert!(!logged.contains("secret"));
+        assert!(logged.contains("[redacted]"));
+    }
+
+    #[test]
+    fn test_connect_through_proxy() {
+        let (proxy, server) = fake_proxy(vec![
+            vec![0x05, 0x00],
+            vec![0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0x1f, 0x90],
+        ]);
+        connect(&proxy, "smtp.gmail.com", 587, Duration::from_secs(5)).unwrap();
+
+        let mut expected = vec![0x05, 0x01, 0x00, 0x05, 0x01, 0x00, 0x03, 14];
+        expected.extend_from_slice(b"smtp.gmail.com");
+        expected.extend_from_slice(&[0x02, 0x4b]);
+        assert_eq!(server.join().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_connect_through_proxy_with_password() {
+        let (mut proxy, server) = fake_proxy(vec![
+            vec![0x05, 0x02],
+            vec![0x01, 0x00],
+            vec![0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0x1f, 0x90],
+        ]);
+        proxy.username = Some("user".to_string());
+        proxy.password = Some("secret".to_string());
+        connect(&proxy, "smtp.gmail.com", 587, Duration::from_secs(5)).unwrap();
+
+        let received = server.join().unwrap();
+        assert!(received.starts_with(&[0x05, 0x02, 0x00, 0x02, 0x01, 4]));
+        assert!(received[6..].starts_with(b"user\x06secret"));
+    }
+
+    #[test]
+    fn test_proxy_refuses_connection() {
+        let (proxy, server) = fake_proxy(vec![
+            vec![0x05, 0x00],
+            vec![0x05, 0x05, 0x00, 0x01, 0, 0, 0, 0, 0, 0],
+        ]);
+        let error = connect(&proxy, "smtp.gmail.com", 587, Duration::from_secs(5)).unwrap_err();
+        server.join().unwrap();
+        assert!(matches!(error, EsimMailerError::NetworkError(_)));
+        assert!(error.to_string().contains("connection refused"));
+    }
+
+    #[test]
+    fn test_unreachable_proxy() {
+        // Nothing listens on the port once the listener is dropped
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let proxy = ProxyConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            ..Default::default()
+        };
+        let error = connect(&proxy, "smtp.gmail.com", 587, Duration::from_secs(5)).unwrap_err();
+        assert!(matches!(error, EsimMailerError::NetworkError(_)));
+        assert_eq!(error.category(), ErrorCategory::Network);
+    }
+}
@@ -0,0 +1,205 @@
+use crate::EsimMailerError;
+pub use crate::lpa::LPA_PREFIX;
+use qrcode::{Color, EcLevel, QrCode};
+
+/// How many pixels wide each QR module is drawn.
+const MODULE_PIXELS: usize = 8;
+
+/// Width of the blank border around the code in modules, per ISO/IEC 18004.
+const QUIET_ZONE: usize = 4;
+
+/// The fewest pixels wide a module may be shrunk to and still scan reliably.
+const MIN_MODULE_PIXELS: usize = 2;
+
+/// Generates a PNG of the QR code for an eSIM activation code such as
+/// `LPA:1$smdp.example.com$MATCHING-ID`.
+pub fn activation_code_png(activation_code: &str) -> Result<Vec<u8>, EsimMailerError> {
+    if !activation_code.starts_with(LPA_PREFIX) {
+        return Err(EsimMailerError::MessageError(format!(
+            "Activation code must start with '{}'",
+            LPA_PREFIX
+        )));
+    }
+    let qr = QrCode::with_error_correction_level(activation_code, EcLevel::M).map_err(|e| {
+        EsimMailerError::MessageError(format!("Could not encode activation code: {}", e))
+    })?;
+    encode_png(&qr)
+}
+
+fn encode_png(qr: &QrCode) -> Result<Vec<u8>, EsimMailerError> {
+    let size = qr.width();
+    let width = (size + QUIET_ZONE * 2) * MODULE_PIXELS;
+    let mut pixels = vec![0xff; width * width];
+    for (index, _) in qr
+        .to_colors()
+        .iter()
+        .enumerate()
+        .filter(|(_, color)| **color == Color::Dark)
+    {
+        let (x, y) = (index % size, index / size);
+        for dy in 0..MODULE_PIXELS {
+            let start =
+                ((y + QUIET_ZONE) * MODULE_PIXELS + dy) * width + (x + QUIET_ZONE) * MODULE_PIXELS;
+            pixels[start..start + MODULE_PIXELS].fill(0);
+        }
+    }
+
+    encode_pixels(&pixels, width, width, png::ColorType::Grayscale)
+}
+
+/// Shrinks a PNG image by halving its width and height until it takes at
+/// most `max_bytes`. Images which already fit are returned as they are.
+///
+/// Fails rather than shrinking the modules of the code below
+/// [`MIN_MODULE_PIXELS`], since a phone couldn't scan it any more.
+pub fn shrink_png(data: &[u8], max_bytes: usize) -> Result<Vec<u8>, EsimMailerError> {
+    if data.len() <= max_bytes {
+        return Ok(data.to_vec());
+    }
+
+    let decoding_error = |e: png::DecodingError| EsimMailerError::MessageError(e.to_string());
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info().map_err(decoding_error)?;
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels).map_err(decoding_error)?;
+    pixels.truncate(info.buffer_size());
+    let (color, _) = reader.output_color_type();
+    let channels = color.samples();
+
+    let (mut width, mut height) = (info.width as usize, info.height as usize);
+    let mut shrunk = data.to_vec();
+    while shrunk.len() > max_bytes {
+        if narrowest_run(&pixels, width, channels) < MIN_MODULE_PIXELS * 2 {
+            return Err(EsimMailerError::MessageError(format!(
+                "Could not shrink the image to {} bytes and keep the QR code scannable",
+                max_bytes
+            )));
+        }
+        (pixels, width, height) = halve(&pixels, width, height, channels);
+        shrunk = encode_pixels(&pixels, width, height, color)?;
+    }
+    Ok(shrunk)
+}
+
+/// Returns the width in pixels of the narrowest run of light or dark pixels
+/// along any row, which for a QR code is the width of a module.
+fn narrowest_run(pixels: &[u8], width: usize, channels: usize) -> usize {
+    pixels
+        .chunks(width * channels)
+        .flat_map(|row| {
+            let dark: Vec<bool> = row.chunks(channels).map(|pixel| pixel[0] < 0x80).collect();
+            dark.chunk_by(|a, b| a == b)
+                .map(<[bool]>::len)
+                .collect::<Vec<_>>()
+        })
+        .min()
+        .unwrap_or(0)
+}
+
+/// Halves the width and height of 8-bit pixels, averaging each 2x2 block.
+fn halve(pixels: &[u8], width: usize, height: usize, channels: usize) -> (Vec<u8>, usize, usize) {
+    let (new_width, new_height) = (width / 2, height / 2);
+    let mut halved = Vec::with_capacity(new_width * new_height * channels);
+    for y in 0..new_height {
+        for x in 0..new_width {
+            for channel in 0..channels {
+                let sample = |dx: usize, dy: usize| {
+                    u32::from(pixels[((y * 2 + dy) * width + x * 2 + dx) * channels + channel])
+                };
+                let sum = sample(0, 0) + sample(1, 0) + sample(0, 1) + sample(1, 1);
+                halved.push((sum / 4) as u8);
+            }
+        }
+    }
+    (halved, new_width, new_height)
+}
+
+fn encode_pixels(
+    pixels: &[u8],
+    width: usize,
+    height: usize,
+    color: png::ColorType,
+) -> Result<Vec<u8>, EsimMailerError> {
+    let png_error = |e: png::EncodingError| EsimMailerError::MessageError(e.to_string());
+    let mut png_data = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png_data, width as u32, height as u32);
+    encoder.set_color(color);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(png_error)?;
+    writer.write_image_data(pixels).map_err(png_error)?;
+    writer.finish().map_err(png_error)?;
+    Ok(png_data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads the text back out of a QR code PNG, as a phone scanning it would.
+    fn decode(png_data: &[u8]) -> String {
+        let mut decoder = png::Decoder::new(png_data);
+        decoder.set_transformations(png::Transformations::EXPAND);
+        let mut reader = decoder.read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        let (width, height) = (info.width as usize, info.height as usize);
+
+        let mut image = rqrr::PreparedImage::prepare_from_greyscale(width, height, |x, y| {
+            pixels[y * width + x]
+        });
+        let grids = image.detect_grids();
+        assert_eq!(grids.len(), 1);
+        grids[0].decode().unwrap().1
+    }
+
+    #[test]
+    fn test_activation_code_png() {
+        let activation_code = "LPA:1$smdp.example.com$ABCD-1234-EFGH-5678";
+        let png_data = activation_code_png(activation_code).unwrap();
+        assert!(png_data.starts_with(b"\x89PNG\r\n\x1a\n"));
+        assert_eq!(decode(&png_data), activation_code);
+    }
+
+    #[test]
+    fn test_activation_code_requires_lpa_prefix() {
+        assert!(matches!(
+            activation_code_png("1$smdp.example.com$ABCD"),
+            Err(EsimMailerError::MessageError(_))
+        ));
+    }
+
+    #[test]
+    fn test_activation_code_too_long() {
+        let activation_code = format!("LPA:1$smdp.example.com${}", "a".repeat(3000));
+        assert!(activation_code_png(&activation_code).is_err());
+    }
+
+    #[test]
+    fn test_shrink_png() {
+        let png_data = activation_code_png("LPA:1$smdp.example.com$ABCD-1234-EFGH-5678").unwrap();
+        assert_eq!(shrink_png(&png_data, png_data.len()).unwrap(), png_data);
+
+        let shrunk = shrink_png(&png_data, png_data.len() - 1).unwrap();
+        assert!(shrunk.len() < png_data.len());
+        let decoder = png::Decoder::new(&shrunk[..]);
+        let original = png::Decoder::new(&png_data[..]).read_info().unwrap();
+        let info = decoder.read_info().unwrap();
+        assert_eq!(info.info().width, original.info().width / 2);
+        assert_eq!(
+            decode(&shrunk),
+            "LPA:1$smdp.example.com$ABCD-1234-EFGH-5678"
+        );
+    }
+
+    #[test]
+    fn test_shrink_png_keeps_code_scannable() {
+        let png_data = activation_code_png("LPA:1$smdp.example.com$ABCD-1234-EFGH-5678").unwrap();
+
+        // Modules are drawn 8 pixels wide, so two halvings is as far as it goes
+        assert!(matches!(
+            shrink_png(&png_data, 10),
+            Err(EsimMailerError::MessageError(_))
+        ));
+    }
+}
@@ -0,0 +1,190 @@
+use crate::clock::{Clock, SystemClock};
+use crate::{Args, EsimMailerError};
+use std::env;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// Environment variable which can hold the most emails to send per second.
+pub const MAX_RATE_ENV_VAR: &str = "ESIM_MAILER_MAX_RATE";
+
+/// Spaces sends out so they never go faster than a given rate.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    next_slot: Mutex<Option<SystemTime>>,
+}
+
+/// The limiter shared by every send in the process, whichever provider it
+/// goes through.
+static GLOBAL_RATE_LIMITER: RateLimiter = RateLimiter::new();
+
+impl RateLimiter {
+    pub const fn new() -> Self {
+        Self {
+            next_slot: Mutex::new(None),
+        }
+    }
+
+    /// Reserves the next slot for a send at no more than `max_per_second`,
+    /// returning how long to wait before sending. Fails if the rate is so
+    /// low that the wait can't be represented.
+    pub fn reserve(
+        &self,
+        max_per_second: f64,
+        clock: &dyn Clock,
+    ) -> Result<Duration, EsimMailerError> {
+        let too_low = || {
+            EsimMailerError::ConfigError(format!(
+                "The rate of {} emails per second is too low",
+                max_per_second
+            ))
+        };
+        let interval = Duration::try_from_secs_f64(1.0 / max_per_second).map_err(|_| too_low())?;
+        let now = clock.now();
+        let mut next_slot = self
+            .next_slot
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let slot = next_slot.filter(|slot| *slot > now).unwrap_or(now);
+        *next_slot = Some(slot.checked_add(interval).ok_or_else(too_low)?);
+        Ok(slot.duration_since(now).unwrap_or_default())
+    }
+}
+
+/// Returns the most emails to send per second, from `args` or else the
+/// environment, or `None` if sends aren't limited.
+pub fn max_rate(args: &Args) -> Option<f64> {
+    max_rate_with_env(args, env::var(MAX_RATE_ENV_VAR).ok())
+}
+
+fn max_rate_with_env(args: &Args, env_rate: Option<String>) -> Option<f64> {
+    args.max_sends_per_second
+        .or_else(|| env_rate.and_then(|rate| rate.trim().parse().ok()))
+        .filter(|rate: &f64| rate.is_finite() && *rate > 0.0)
+}
+
+/// Reserves a slot from `limiter` for a send with `args`, returning how long
+/// to wait before sending. Sends which aren't limited go straight away.
+fn reserve_for(
+    limiter: &RateLimiter,
+    args: &Args,
+    clock: &dyn Clock,
+) -> Result<Duration, EsimMailerError> {
+    match max_rate(args) {
+        Some(max_per_second) => limiter.reserve(max_per_second, clock),
+        None => Ok(Duration::ZERO),
+    }
+}
+
+/// Waits until the process may send another email, if sends are limited.
+pub fn wait_for_slot(args: &Args) -> Result<(), EsimMailerError> {
+    let wait = reserve_for(&GLOBAL_RATE_LIMITER, args, &SystemClock)?;
+    if !wait.is_zero() {
+        thread::sleep(wait);
+    }
+    Ok(())
+}
+
+/// Waits as [`wait_for_slot`] does, without blocking the async runtime.
+#[cfg(feature = "async")]
+pub async fn wait_for_slot_async(args: &Args) -> Result<(), EsimMailerError> {
+    let wait = reserve_for(&GLOBAL_RATE_LIMITER, args, &SystemClock)?;
+    if !wait.is_zero() {
+        tokio::time::sleep(wait).await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+    use crate::email::Provider;
+
+    #[test]
+    fn test_rate_limiter_spaces_sends() {
+        let limiter = RateLimiter::new();
+        let clock = FixedClock(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+
+        let waits: Vec<Duration> = (0..3)
+            .map(|_| limiter.reserve(10.0, &clock).unwrap())
+            .collect();
+
+        assert_eq!(
+            waits,
+            vec![
+                Duration::ZERO,
+                Duration::from_millis(100),
+                Duration::from_millis(200)
+            ]
+        );
+
+        // Once the slots have passed, sends go straight away again
+        let later = FixedClock(clock.0 + Duration::from_secs(1));
+        assert_eq!(limiter.reserve(10.0, &later).unwrap(), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_rate_limit_spaces_sends_across_providers() {
+        let limiter = RateLimiter::new();
+        let clock = FixedClock(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+
+        let senders = ["a@gmail.com", "b@outlook.com", "c@fastmail.com"];
+        let providers: Vec<String> = senders
+            .iter()
+            .map(|sender| sender.parse::<Provider>().unwrap().to_string())
+            .collect();
+        assert_eq!(providers, vec!["Gmail", "Outlook", "Fastmail"]);
+        let waits: Vec<Duration> = senders
+            .iter()
+            .map(|sender| {
+                let args = Args {
+                    email_from: sender.to_string(),
+                    max_sends_per_second: Some(10.0),
+                    ..Default::default()
+                };
+                reserve_for(&limiter, &args, &clock).unwrap()
+            })
+            .collect();
+
+        assert_eq!(
+            waits,
+            vec![
+                Duration::ZERO,
+                Duration::from_millis(100),
+                Duration::from_millis(200)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rate_limiter_rejects_tiny_rates() {
+        let limiter = RateLimiter::new();
+        let clock = FixedClock(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+
+        for rate in [1e-20, 1e-19] {
+            assert!(matches!(
+                limiter.reserve(rate, &clock),
+                Err(EsimMailerError::ConfigError(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_max_rate_precedence() {
+        let args = Args {
+            max_sends_per_second: Some(5.0),
+            ..Default::default()
+        };
+        assert_eq!(max_rate_with_env(&args, Some("10".to_string())), Some(5.0));
+        assert_eq!(
+            max_rate_with_env(&Args::default(), Some("10".to_string())),
+            Some(10.0)
+        );
+        assert_eq!(
+            max_rate_with_env(&Args::default(), Some("0".to_string())),
+            None
+        );
+        assert_eq!(max_rate_with_env(&Args::default(), None), None);
+    }
+}
@@ -0,0 +1,144 @@
+use crate::email::{Provider, TlsMode};
+use lettre::transport::smtp::authentication::Mechanism;
+use std::sync::{Arc, RwLock};
+
+/// The settings for a provider added at runtime.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CustomProvider {
+    /// Name shown for the provider and used to look up per-provider settings
+    pub name: String,
+
+    /// Email domains sent through this provider, e.g. "example.com"
+    pub domains: Vec<String>,
+
+    pub host: String,
+    pub port: u16,
+    pub tls: TlsMode,
+    pub mechanism: Mechanism,
+}
+
+/// The providers email addresses can be sent through: the built-in ones
+/// along with any registered at runtime.
+#[derive(Debug, Default)]
+pub struct ProviderRegistry {
+    custom: RwLock<Vec<Arc<CustomProvider>>>,
+}
+
+/// The registry consulted when parsing a [`Provider`] from an email address.
+static PROVIDER_REGISTRY: ProviderRegistry = ProviderRegistry::new();
+
+impl ProviderRegistry {
+    pub const fn new() -> Self {
+        Self {
+            custom: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// Returns the registry used by the rest of the crate.
+    pub fn global() -> &'static Self {
+        &PROVIDER_REGISTRY
+    }
+
+    /// Adds a provider for its domains, returning it.
+    ///
+    /// Built-in providers take precedence for their domains, and earlier
+    /// registrations take precedence over later ones.
+    pub fn register(&self, provider: CustomProvider) -> Provider {
+        let provider = Arc::new(provider);
+        self.custom
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(Arc::clone(&provider));
+        Provider::Custom(provider)
+    }
+
+    /// Returns the provider for a domain, matched case-insensitively.
+    pub fn lookup(&self, domain: &str) -> Option<Provider> {
+        let domain = domain.to_ascii_lowercase();
+        Provider::builtin(&domain).or_else(|| {
+            self.custom
+                .read()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .iter()
+                .find(|custom| {
+                    custom
+                        .domains
+                        .iter()
+                        .any(|custom_domain| custom_domain.eq_ignore_ascii_case(&domain))
+                })
+                .map(|custom| Provider::Custom(Arc::clone(custom)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Args;
+    use crate::email::describe_transport;
+
+    fn example_provider(name: &str, domain: &str) -> CustomProvider {
+        CustomProvider {
+            name: name.to_string(),
+            domains: vec![domain.to_string()],
+            host: format!("smtp.{}", domain),
+            port: 465,
+            tls: TlsMode::Implicit,
+            mechanism: Mechanism::Plain,
+        }
+    }
+
+    #[test]
+    fn test_registered_provider_routes_its_domain() {
+        let registry = ProviderRegistry::new();
+        let provider = registry.register(example_provider("Example", "example-mail.test"));
+
+        assert_eq!(registry.lookup("Example-Mail.test"), Some(provider));
+        assert_eq!(registry.lookup("gmail.com"), Some(Provider::Gmail));
+        assert_eq!(registry.lookup("unknown.test"), None);
+
+        let config = registry.lookup("example-mail.test").unwrap().smtp_config();
+        assert_eq!(config.host, "smtp.example-mail.test");
+        assert_eq!(config.port, 465);
+        assert_eq!(config.tls, TlsMode::Implicit);
+        assert_eq!(config.mechanism, Mechanism::Plain);
+    }
+
+    #[test]
+    fn test_global_registry_is_used_when_parsing() {
+        assert!("user@registry-global.test".parse::<Provider>().is_err());
+
+        ProviderRegistry::global().register(example_provider("Registered", "registry-global.test"));
+        let args = Args {
+            email_from: "user@registry-global.test".to_string(),
+            ..Default::default()
+        };
+        let description = describe_transport(&args).unwrap();
+
+        assert_eq!(description.provider.to_string(), "Registered");
+        assert_eq!(description.host, "smtp.registry-global.test");
+        assert!(!description.provider.requires_oauth());
+    }
+
+    #[test]
+    fn test_dropping_registry_frees_its_providers() {
+        let registry = ProviderRegistry::new();
+        let Provider::Custom(provider) =
+            registry.register(example_provider("Example", "example-mail.test"))
+        else {
+            panic!("Registering should return a custom provider");
+        };
+        assert_eq!(Arc::strong_count(&provider), 2);
+
+        drop(registry);
+        assert_eq!(Arc::strong_count(&provider), 1);
+    }
+
+    #[test]
+    fn test_builtin_providers_take_precedence() {
+        let registry = ProviderRegistry::new();
+        registry.register(example_provider("Impostor", "gmail.com"));
+
+        assert_eq!(registry.lookup("gmail.com"), Some(Provider::Gmail));
+    }
+}
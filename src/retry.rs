@@ -0,0 +1,164 @@
+use crate::EsimMailerError;
+use crate::error::ErrorCategory;
+use std::thread;
+use std::time::Duration;
+
+/// How failed sends are retried.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first
+    pub max_attempts: usize,
+
+    /// Delay between attempts
+    pub delay: Duration,
+
+    /// Categories of errors which are retried
+    pub retry_on: Vec<ErrorCategory>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            delay: Duration::from_secs(1),
+            retry_on: vec![ErrorCategory::Transient, ErrorCategory::Network],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns whether the policy allows retrying after this error.
+    pub fn should_retry(&self, error: &EsimMailerError) -> bool {
+        self.retry_on.contains(&error.category())
+    }
+
+    /// Calls `f` until it succeeds, fails with an error which shouldn't be
+    /// retried, or runs out of attempts.
+    pub fn run<T, F>(&self, mut f: F) -> Result<T, EsimMailerError>
+    where
+        F: FnMut() -> Result<T, EsimMailerError>,
+    {
+        let mut attempt = 1;
+        loop {
+            match f() {
+                Err(e) if attempt < self.max_attempts && self.should_retry(&e) => {
+                    eprintln!("Attempt {} failed, retrying: {}", attempt, e);
+                    attempt += 1;
+                    thread::sleep(self.delay);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Runs `f` as [`RetryPolicy::run`] does, waiting between attempts
+    /// without blocking the async runtime.
+    #[cfg(feature = "async")]
+    pub async fn run_async<T, F, Fut>(&self, mut f: F) -> Result<T, EsimMailerError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, EsimMailerError>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match f().await {
+                Err(e) if attempt < self.max_attempts && self.should_retry(&e) => {
+                    eprintln!("Attempt {} failed, retrying: {}", attempt, e);
+                    attempt += 1;
+                    tokio::time::sleep(self.delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network_only() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            delay: Duration::ZERO,
+            retry_on: vec![ErrorCategory::Network],
+        }
+    }
+
+    #[test]
+    fn test_default_retries_transient_and_network() {
+        let policy = RetryPolicy::default();
+        assert!(policy.should_retry(&EsimMailerError::TransientError("451".to_string())));
+        assert!(policy.should_retry(&EsimMailerError::NetworkError("refused".to_string())));
+        assert!(!policy.should_retry(&EsimMailerError::AuthError("535".to_string())));
+        assert!(!policy.should_retry(&EsimMailerError::MessageError("bad".to_string())));
+    }
+
+    #[test]
+    fn test_auth_failure_is_not_retried() {
+        let mut attempts = 0;
+        let result: Result<(), _> = network_only().run(|| {
+            attempts += 1;
+            Err(EsimMailerError::AuthError(
+                "535 bad credentials".to_string(),
+            ))
+        });
+
+        assert!(matches!(result, Err(EsimMailerError::AuthError(_))));
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn test_network_failure_is_retried() {
+        let mut attempts = 0;
+        let result = network_only().run(|| {
+            attempts += 1;
+            if attempts < 3 {
+                Err(EsimMailerError::NetworkError(
+                    "connection reset".to_string(),
+                ))
+            } else {
+                Ok(attempts)
+            }
+        });
+
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[test]
+    fn test_retries_stop_after_max_attempts() {
+        let mut attempts = 0;
+        let result: Result<(), _> = network_only().run(|| {
+            attempts += 1;
+            Err(EsimMailerError::NetworkError(
+                "connection reset".to_string(),
+            ))
+        });
+
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+    }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn test_run_async_retries() {
+        let mut attempts = 0;
+        let result = network_only()
+            .run_async(|| {
+                attempts += 1;
+                let attempt = attempts;
+                async move {
+                    if attempt < 3 {
+                        Err(EsimMailerError::NetworkError(
+                            "connection reset".to_string(),
+                        ))
+                    } else {
+                        Ok(attempt)
+                    }
+                }
+            })
+            .await;
+
+        assert_eq!(result.unwrap(), 3);
+    }
+}